@@ -0,0 +1,248 @@
+use std::result::Result;
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+use super::battle_manager::{BattleId, BattleManagerError};
+use super::engine_interface::EngineInterfaceError;
+use super::lobby::{Lobby, LobbyError};
+
+/// How many admin requests may wait for the daemon before senders have to wait too.
+const ADMIN_QUEUE_SIZE: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error(transparent)]
+    BattleManager(#[from] BattleManagerError),
+    #[error("No game is running in battle {0}")]
+    NoGameRunning(BattleId),
+    #[error("Failed to control the battle")]
+    Lobby(#[from] LobbyError),
+    #[error("Failed to send the command to the game")]
+    Game(#[from] EngineInterfaceError),
+    #[error("The autohost isn't taking admin requests")]
+    Unavailable,
+}
+
+/// An operation on the running autohost, requested through the admin API.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    ListBattles,
+    GetBattle(BattleId),
+    /// Starts the battle's game without waiting for every player to be ready.
+    ForceStart(BattleId),
+    /// Asks the battle's game to end, killing it if it doesn't exit in time.
+    StopGame(BattleId),
+    /// Kills the battle's game right away.
+    KillGame(BattleId),
+    Say(BattleId, String),
+    /// Stops or resumes accepting new battles.
+    SetDraining(bool),
+    /// Stops every game and ends the server session, like a SIGTERM would.
+    Shutdown,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminResponse {
+    Battles(Vec<BattleStatus>),
+    Battle(BattleStatus),
+    Done,
+    /// The command was accepted, and is carried out after the response.
+    Accepted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameState {
+    /// No game was started yet.
+    Waiting,
+    Running,
+    Ended,
+}
+
+/// A player of a battle, without an ally team if spectating.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PlayerStatus {
+    pub name: String,
+    pub ally_team: Option<usize>,
+}
+
+/// A snapshot of a battle and its game, as shown by the admin API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BattleStatus {
+    pub id: BattleId,
+    pub game_version: String,
+    pub map_name: String,
+    pub host_port: u16,
+    pub players: Vec<PlayerStatus>,
+    pub game_state: GameState,
+    /// The players connected to the game, as reported by the engine.
+    pub players_in_game: usize,
+    pub running_seconds: Option<u64>,
+    /// The exit code of the engine once it has exited, `None` if killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+impl BattleStatus {
+    /// Takes a snapshot of the lobby, checking the engine process itself so a game that
+    /// exited before its updates were read isn't shown as running.
+    pub fn new(battle_id: BattleId, lobby: &mut Lobby) -> Self {
+        let running = lobby.is_game_running();
+        let battle = lobby.get_battle();
+        let game = lobby.get_game();
+        let exit = game.and_then(|game| game.get_exit());
+
+        BattleStatus {
+            id: battle_id,
+            game_version: battle.game_version.clone(),
+            map_name: battle.map_name.clone(),
+            host_port: battle.host_port,
+            players: battle
+                .players
+                .iter()
+                .map(|player| PlayerStatus {
+                    name: player.name.clone(),
+                    ally_team: player.ally_team,
+                })
+                .collect(),
+            game_state: match game {
+                None => GameState::Waiting,
+                Some(_) if running => GameState::Running,
+                Some(_) => GameState::Ended,
+            },
+            players_in_game: game.map_or(0, |game| game.get_player_count()),
+            running_seconds: game.map(|game| game.get_running_time().as_secs()),
+            exit_code: exit.and_then(|exit| exit.code()),
+        }
+    }
+}
+
+/// A command waiting for the daemon, which answers it with `respond`.
+#[derive(Debug)]
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    responder: oneshot::Sender<Result<AdminResponse, AdminError>>,
+}
+
+impl AdminRequest {
+    pub fn respond(self, result: Result<AdminResponse, AdminError>) {
+        // The requester may have given up waiting, there's no one left to tell then.
+        let _ = self.responder.send(result);
+    }
+}
+
+/// Sends admin commands to the daemon, which serves them between server requests.
+#[derive(Clone, Debug)]
+pub struct AdminClient {
+    sender: mpsc::Sender<AdminRequest>,
+}
+
+impl AdminClient {
+    /// Sends a command and waits for the daemon to carry it out.
+    ///
+    /// # Errors
+    ///
+    /// An `AdminError::Unavailable` is returned if the daemon isn't running, and the
+    /// error the command failed with otherwise.
+    ///
+    pub async fn send(&self, command: AdminCommand) -> Result<AdminResponse, AdminError> {
+        let (responder, response) = oneshot::channel();
+        self.sender
+            .send(AdminRequest { command, responder })
+            .await
+            .map_err(|_| AdminError::Unavailable)?;
+
+        response.await.map_err(|_| AdminError::Unavailable)?
+    }
+}
+
+/// Creates a client for admin commands, and the receiver the daemon serves them from.
+pub fn channel() -> (AdminClient, mpsc::Receiver<AdminRequest>) {
+    let (sender, receiver) = mpsc::channel(ADMIN_QUEUE_SIZE);
+
+    (AdminClient { sender }, receiver)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::process::Stdio;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::process::Command;
+
+    use super::*;
+    use crate::autohost::battle::Battle;
+    use crate::autohost::engine_interface::EngineInterface;
+    use crate::autohost::engine_log::EngineLog;
+    use crate::autohost::spring::{LaunchError, Spring, SpringGame};
+    use crate::utils::config::fake::FakeConfig;
+    use crate::utils::config::Config;
+    use crate::utils::environment::{Environment, EnvironmentError};
+
+    /// Launches a process that exits right away in place of the engine.
+    struct ExitingSpring {}
+
+    impl Spring for ExitingSpring {
+        fn launch(
+            &self,
+            _config: &dyn Config,
+            _root_dir: &Path,
+            game_dir: &Path,
+            _start_script_path: &Path,
+            engine_interface: EngineInterface,
+        ) -> Result<SpringGame, LaunchError> {
+            let mut process = Command::new("true").stdout(Stdio::piped()).spawn().unwrap();
+            let engine_log = EngineLog::capture(
+                &mut process,
+                &game_dir.join("game.log"),
+                &game_dir.join("infolog.txt"),
+            )
+            .unwrap();
+
+            Ok(SpringGame::new(
+                process,
+                engine_interface,
+                engine_log,
+                Arc::default(),
+            ))
+        }
+    }
+
+    struct TempEnvironment {
+        root_dir: PathBuf,
+    }
+
+    impl Environment for TempEnvironment {
+        fn get_current_dir(&self) -> Result<PathBuf, EnvironmentError> {
+            Ok(self.root_dir.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exited_game_is_ended_before_its_exit_is_read() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let config = FakeConfig::default();
+        let environment = TempEnvironment {
+            root_dir: root_dir.path().to_path_buf(),
+        };
+        let mut lobby = Lobby::new(
+            &config,
+            &ExitingSpring {},
+            &environment,
+            Battle::default(),
+            Arc::default(),
+        );
+
+        lobby.start_game().unwrap();
+        while lobby.is_game_running() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let status = BattleStatus::new(1, &mut lobby);
+
+        assert_eq!(status.game_state, GameState::Ended);
+        assert_eq!(lobby.get_game().unwrap().get_exit(), None);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::start_script::script::{AllyTeam, Player, StartPosType, StartRect, StartScript, Team};
+
+const DEFAULT_HOST_PORT: u16 = 8452;
+const HOST_IP: &str = "0.0.0.0";
+const HOST_PLAYER_NAME: &str = "bar-autohost";
+
+/// A player in a battle. Players without an ally team are spectators.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BattlePlayer {
+    pub name: String,
+    pub ally_team: Option<usize>,
+    pub side: Option<String>,
+    pub color: Option<[f32; 3]>,
+    pub country_code: Option<String>,
+    pub skill: Option<String>,
+}
+
+impl BattlePlayer {
+    pub fn new(name: &str, ally_team: Option<usize>) -> Self {
+        BattlePlayer {
+            name: name.to_string(),
+            ally_team,
+            ..Default::default()
+        }
+    }
+}
+
+/// The state of a battle hosted by a lobby, from which a start script is generated
+/// whenever a game is launched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Battle {
+    pub game_version: String,
+    pub map_name: String,
+    pub host_port: u16,
+    pub start_pos_type: StartPosType,
+    pub players: Vec<BattlePlayer>,
+    pub start_boxes: BTreeMap<usize, StartRect>,
+    pub mod_options: BTreeMap<String, String>,
+}
+
+impl Default for Battle {
+    fn default() -> Self {
+        Battle {
+            game_version: String::new(),
+            map_name: String::new(),
+            host_port: DEFAULT_HOST_PORT,
+            start_pos_type: StartPosType::ChooseInGame,
+            players: Vec::new(),
+            start_boxes: BTreeMap::new(),
+            mod_options: BTreeMap::new(),
+        }
+    }
+}
+
+impl Battle {
+    pub fn new(game_version: &str, map_name: &str) -> Self {
+        Battle {
+            game_version: game_version.to_string(),
+            map_name: map_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds the player, replacing any player with the same name.
+    pub fn add_player(&mut self, player: BattlePlayer) {
+        self.remove_player(&player.name);
+        self.players.push(player);
+    }
+
+    pub fn remove_player(&mut self, name: &str) -> Option<BattlePlayer> {
+        let position = self.players.iter().position(|player| player.name == name)?;
+        Some(self.players.remove(position))
+    }
+
+    pub fn set_start_box(&mut self, ally_team: usize, start_box: StartRect) {
+        self.start_boxes.insert(ally_team, start_box);
+    }
+
+    pub fn set_mod_option(&mut self, key: &str, value: &str) {
+        self.mod_options.insert(key.to_string(), value.to_string());
+    }
+
+    /// Builds the start script for the current battle state.
+    ///
+    /// Every playing player gets a team of their own. The engine requires ally teams to
+    /// be numbered from 0 without gaps, so the battle's ally team numbers are mapped onto
+    /// the sequence in ascending order, together with their start boxes.
+    pub fn to_start_script(&self) -> StartScript {
+        let ally_team_ids: BTreeSet<usize> = self
+            .players
+            .iter()
+            .filter_map(|player| player.ally_team)
+            .collect();
+        let ally_team_index: BTreeMap<usize, usize> = ally_team_ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (*id, index))
+            .collect();
+
+        let mut script = StartScript {
+            game_type: self.game_version.clone(),
+            map_name: self.map_name.clone(),
+            host_ip: Some(HOST_IP.to_string()),
+            host_port: Some(self.host_port),
+            is_host: true,
+            my_player_name: Some(HOST_PLAYER_NAME.to_string()),
+            start_pos_type: Some(self.start_pos_type),
+            mod_options: self.mod_options.clone(),
+            ..Default::default()
+        };
+
+        script.ally_teams = ally_team_ids
+            .iter()
+            .map(|id| AllyTeam {
+                start_rect: self.start_boxes.get(id).copied(),
+                ..Default::default()
+            })
+            .collect();
+
+        for player in &self.players {
+            let player_id = script.players.len();
+            let team = player.ally_team.map(|ally_team| {
+                script.teams.push(Team {
+                    team_leader: player_id,
+                    ally_team: ally_team_index[&ally_team],
+                    rgb_color: player.color,
+                    side: player.side.clone(),
+                    ..Default::default()
+                });
+                script.teams.len() - 1
+            });
+
+            script.players.push(Player {
+                name: player.name.clone(),
+                team,
+                spectator: team.is_none(),
+                country_code: player.country_code.clone(),
+                skill: player.skill.clone(),
+                ..Default::default()
+            });
+        }
+
+        script
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_script_numbers_teams_and_ally_teams_sequentially() {
+        let mut battle = Battle::new("BAR test-1", "DSDR 4.1");
+        battle.add_player(BattlePlayer::new("first", Some(3)));
+        battle.add_player(BattlePlayer::new("spectator", None));
+        battle.add_player(BattlePlayer::new("second", Some(1)));
+        battle.set_start_box(
+            3,
+            StartRect {
+                top: 0.0,
+                left: 0.0,
+                bottom: 1.0,
+                right: 0.25,
+            },
+        );
+
+        let script = battle.to_start_script();
+
+        assert_eq!(script.players.len(), 3);
+        assert_eq!(script.teams.len(), 2);
+        assert_eq!(script.ally_teams.len(), 2);
+        assert_eq!(script.players[0].team, Some(0));
+        assert!(script.players[1].spectator);
+        assert_eq!(script.players[2].team, Some(1));
+        assert_eq!(script.teams[0].ally_team, 1);
+        assert_eq!(script.teams[1].ally_team, 0);
+        assert_eq!(script.teams[1].team_leader, 2);
+        assert!(script.ally_teams[0].start_rect.is_none());
+        assert_eq!(
+            script.ally_teams[1].start_rect.map(|rect| rect.right),
+            Some(0.25)
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::result::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::future::{join_all, select_all};
+use thiserror::Error;
+use tracing::{info, info_span};
+
+use super::battle::Battle;
+use super::lobby::{Lobby, LobbyError};
+use super::spring::{GameUpdate, Spring};
+use crate::utils::config::Config;
+use crate::utils::environment::Environment;
+use crate::utils::metrics::Metrics;
+
+const BATTLES_DIR: &str = "battles";
+
+pub type BattleId = u32;
+
+#[derive(Error, Debug)]
+pub enum BattleManagerError {
+    #[error("Battle {0} not found")]
+    BattleNotFound(BattleId),
+    #[error("Maximum number of concurrent games reached")]
+    AtCapacity,
+    #[error("No free host port left")]
+    NoFreePort,
+    #[error("Battle manager is shutting down")]
+    ShuttingDown,
+    #[error("Not accepting new battles while draining")]
+    Draining,
+    #[error(transparent)]
+    Lobby(#[from] LobbyError),
+}
+
+/// The limits on the battles and games hosted at once, which may change while running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostLimits {
+    pub min_host_port: u16,
+    pub max_host_port: u16,
+    pub max_concurrent_games: usize,
+}
+
+impl HostLimits {
+    pub fn from_config(config: &dyn Config) -> Self {
+        HostLimits {
+            min_host_port: config.get_min_host_port(),
+            max_host_port: config.get_max_host_port(),
+            max_concurrent_games: config.get_max_concurrent_games(),
+        }
+    }
+}
+
+/// Owns the lobbies of every battle hosted by the autohost.
+///
+/// Each battle gets a host port of its own from the configured range, and its games
+/// run in a subfolder of the write dir named after the battle id. A battle closed while
+/// its game is running keeps its lobby, and port, until the exit of the game is read.
+pub struct BattleManager<'a> {
+    config: &'a dyn Config,
+    spring: &'a dyn Spring,
+    environment: &'a dyn Environment,
+    limits: HostLimits,
+    lobbies: BTreeMap<BattleId, Lobby<'a>>,
+    closing: BTreeSet<BattleId>,
+    next_battle_id: BattleId,
+    shutting_down: bool,
+    draining: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl<'a> BattleManager<'a> {
+    pub fn new(
+        config: &'a dyn Config,
+        spring: &'a dyn Spring,
+        environment: &'a dyn Environment,
+        metrics: Arc<Metrics>,
+    ) -> BattleManager<'a> {
+        BattleManager {
+            config,
+            spring,
+            environment,
+            limits: HostLimits::from_config(config),
+            lobbies: BTreeMap::new(),
+            closing: BTreeSet::new(),
+            next_battle_id: 1,
+            shutting_down: false,
+            draining: false,
+            metrics,
+        }
+    }
+
+    /// Opens a lobby for the battle, overriding its host port with a free one.
+    ///
+    /// # Errors
+    ///
+    /// A `BattleManagerError::NoFreePort` is returned if every port of the configured
+    /// range is taken, a `BattleManagerError::ShuttingDown` once `shutdown` was called and
+    /// a `BattleManagerError::Draining` while draining.
+    ///
+    pub fn open_battle(&mut self, mut battle: Battle) -> Result<BattleId, BattleManagerError> {
+        if self.shutting_down {
+            return Err(BattleManagerError::ShuttingDown);
+        }
+        if self.draining {
+            return Err(BattleManagerError::Draining);
+        }
+
+        battle.host_port = self.find_free_port()?;
+
+        let battle_id = self.next_battle_id;
+        self.next_battle_id += 1;
+
+        let span = info_span!(
+            "battle",
+            id = battle_id,
+            port = battle.host_port,
+            map = %battle.map_name,
+            players = battle.players.len()
+        );
+        info!(parent: &span, game = %battle.game_version, "Opened battle");
+
+        let mut lobby = Lobby::new(
+            self.config,
+            self.spring,
+            self.environment,
+            battle,
+            self.metrics.clone(),
+        );
+        lobby.set_game_dir(&PathBuf::from(BATTLES_DIR).join(battle_id.to_string()));
+        lobby.set_span(span);
+        self.lobbies.insert(battle_id, lobby);
+
+        Ok(battle_id)
+    }
+
+    /// Closes the battle's lobby. A running game is asked to stop, and killed if it
+    /// hasn't exited within `timeout`, without waiting for it: the lobby is only removed
+    /// once `next_update` has returned the exit of the game.
+    pub async fn close_battle(
+        &mut self,
+        battle_id: BattleId,
+        timeout: Duration,
+    ) -> Result<Battle, BattleManagerError> {
+        let lobby = self.lobby_mut(battle_id)?;
+
+        if lobby.is_game_running() {
+            lobby.request_stop_game(timeout).await?;
+            info!(parent: lobby.get_span(), "Closing battle once its game has exited");
+            let battle = lobby.get_battle().clone();
+            self.closing.insert(battle_id);
+
+            return Ok(battle);
+        }
+
+        let lobby = self
+            .lobbies
+            .remove(&battle_id)
+            .ok_or(BattleManagerError::BattleNotFound(battle_id))?;
+        info!(parent: lobby.get_span(), "Closed battle");
+
+        Ok(lobby.get_battle().clone())
+    }
+
+    /// Starts a game for the battle.
+    ///
+    /// # Errors
+    ///
+    /// A `BattleManagerError::AtCapacity` is returned if the configured maximum of
+    /// concurrent games are already running.
+    ///
+    pub fn start_game(&mut self, battle_id: BattleId) -> Result<(), BattleManagerError> {
+        if self.shutting_down {
+            return Err(BattleManagerError::ShuttingDown);
+        }
+        if self.is_closing(battle_id) {
+            return Err(BattleManagerError::BattleNotFound(battle_id));
+        }
+        if !self.has_capacity() {
+            return Err(BattleManagerError::AtCapacity);
+        }
+
+        Ok(self.lobby_mut(battle_id)?.start_game()?)
+    }
+
+    pub fn get_lobby(&self, battle_id: BattleId) -> Option<&Lobby<'a>> {
+        self.lobbies.get(&battle_id)
+    }
+
+    pub fn get_lobby_mut(&mut self, battle_id: BattleId) -> Option<&mut Lobby<'a>> {
+        self.lobbies.get_mut(&battle_id)
+    }
+
+    pub fn get_battle_ids(&self) -> Vec<BattleId> {
+        self.lobbies.keys().copied().collect()
+    }
+
+    pub fn get_running_game_count(&mut self) -> usize {
+        self.lobbies
+            .values_mut()
+            .map(|lobby| lobby.is_game_running())
+            .filter(|running| *running)
+            .count()
+    }
+
+    pub fn has_capacity(&mut self) -> bool {
+        self.get_running_game_count() < self.limits.max_concurrent_games
+    }
+
+    pub fn get_limits(&self) -> HostLimits {
+        self.limits
+    }
+
+    /// Changes the limits for the battles opened and games started from now on. Running
+    /// games and open battles are left alone, even if they exceed the new limits.
+    pub fn set_limits(&mut self, limits: HostLimits) {
+        self.limits = limits;
+    }
+
+    /// Whether the battle was closed, and only waits for its game to exit.
+    pub fn is_closing(&self, battle_id: BattleId) -> bool {
+        self.closing.contains(&battle_id)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
+    /// Stops or resumes accepting new battles. Open battles are left alone while
+    /// draining, and may still start games.
+    pub fn set_draining(&mut self, draining: bool) {
+        self.draining = draining;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Waits for the next update of any battle's game. Never resolves while no game has
+    /// updates left to read, so it can be raced against other work.
+    pub async fn next_update(&mut self) -> (BattleId, Result<GameUpdate, LobbyError>) {
+        self.remove_closed_lobbies();
+
+        let updates: Vec<_> = self
+            .lobbies
+            .iter_mut()
+            .filter(|(_, lobby)| lobby.has_game_updates())
+            .map(|(battle_id, lobby)| {
+                Box::pin(async move { (*battle_id, lobby.next_game_update().await) })
+            })
+            .collect();
+
+        if updates.is_empty() {
+            return std::future::pending().await;
+        }
+
+        select_all(updates).await.0
+    }
+
+    /// Stops accepting new battles and games, and stops every running game, killing the
+    /// ones that haven't exited within `timeout`. Lobbies stay open so the final updates
+    /// of their games can still be read.
+    ///
+    /// # Errors
+    ///
+    /// The first error of any game that couldn't be stopped is returned, after every
+    /// other game has been stopped.
+    ///
+    pub async fn shutdown(&mut self, timeout: Duration) -> Result<(), BattleManagerError> {
+        self.shutting_down = true;
+
+        let results = join_all(
+            self.lobbies
+                .values_mut()
+                .filter_map(|lobby| lobby.is_game_running().then_some(lobby))
+                .map(|lobby| lobby.stop_game(timeout)),
+        )
+        .await;
+
+        for result in results {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the lobbies of closed battles whose game has exited, and whose last
+    /// update was read.
+    fn remove_closed_lobbies(&mut self) {
+        let lobbies = &mut self.lobbies;
+        self.closing.retain(|battle_id| {
+            if lobbies
+                .get(battle_id)
+                .is_some_and(|lobby| lobby.has_game_updates())
+            {
+                return true;
+            }
+
+            if let Some(lobby) = lobbies.remove(battle_id) {
+                info!(parent: lobby.get_span(), "Closed battle");
+            }
+            false
+        });
+    }
+
+    fn lobby_mut(&mut self, battle_id: BattleId) -> Result<&mut Lobby<'a>, BattleManagerError> {
+        self.lobbies
+            .get_mut(&battle_id)
+            .ok_or(BattleManagerError::BattleNotFound(battle_id))
+    }
+
+    fn find_free_port(&self) -> Result<u16, BattleManagerError> {
+        (self.limits.min_host_port..=self.limits.max_host_port)
+            .find(|port| {
+                !self
+                    .lobbies
+                    .values()
+                    .any(|lobby| lobby.get_battle().host_port == *port)
+            })
+            .ok_or(BattleManagerError::NoFreePort)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::autohost::engine_interface::EngineInterface;
+    use crate::autohost::spring::{LaunchError, SpringGame};
+    use crate::utils::environment::EnvironmentError;
+
+    pub struct FakeSpring {}
+
+    impl Spring for FakeSpring {
+        fn launch(
+            &self,
+            config: &dyn Config,
+            root_dir: &Path,
+            _game_dir: &Path,
+            _start_script_path: &Path,
+            _engine_interface: EngineInterface,
+        ) -> Result<SpringGame, LaunchError> {
+            Err(LaunchError::LaunchFail {
+                path: root_dir.join(config.get_spring_relative_path()),
+                source: io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "games aren't launched in these tests",
+                ),
+            })
+        }
+    }
+
+    pub struct FakeEnvironment {}
+
+    impl Environment for FakeEnvironment {
+        fn get_current_dir(&self) -> Result<PathBuf, EnvironmentError> {
+            Ok(PathBuf::from("fake_root_dir"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::{FakeEnvironment, FakeSpring};
+    use super::*;
+    use crate::utils::config::fake::FakeConfig;
+
+    fn build_config(max_concurrent_games: usize) -> FakeConfig {
+        FakeConfig {
+            min_host_port: 9000,
+            max_host_port: 9001,
+            max_concurrent_games,
+            ..FakeConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_battles_get_unique_host_ports_and_game_dirs() {
+        let config = build_config(1);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+
+        let first = manager.open_battle(Battle::default()).unwrap();
+        let second = manager.open_battle(Battle::default()).unwrap();
+
+        let first = manager.get_lobby(first).unwrap();
+        let second = manager.get_lobby(second).unwrap();
+        assert_eq!(first.get_battle().host_port, 9000);
+        assert_eq!(second.get_battle().host_port, 9001);
+        assert_ne!(first.get_game_dir(), second.get_game_dir());
+    }
+
+    #[test]
+    fn test_open_battle_fails_when_ports_run_out() {
+        let config = build_config(1);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+
+        manager.open_battle(Battle::default()).unwrap();
+        manager.open_battle(Battle::default()).unwrap();
+        let result = manager.open_battle(Battle::default());
+
+        assert!(matches!(result, Err(BattleManagerError::NoFreePort)));
+    }
+
+    #[tokio::test]
+    async fn test_closed_battle_frees_its_port() {
+        let config = build_config(1);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+
+        let first = manager.open_battle(Battle::default()).unwrap();
+        manager.open_battle(Battle::default()).unwrap();
+        manager
+            .close_battle(first, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let third = manager.open_battle(Battle::default()).unwrap();
+
+        assert_eq!(
+            manager.get_lobby(third).unwrap().get_battle().host_port,
+            9000
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_game_fails_without_capacity() {
+        let config = build_config(0);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+
+        let battle_id = manager.open_battle(Battle::default()).unwrap();
+        let result = manager.start_game(battle_id);
+
+        assert!(matches!(result, Err(BattleManagerError::AtCapacity)));
+    }
+
+    #[test]
+    fn test_new_limits_apply_to_new_battles() {
+        let config = build_config(1);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+        let first = manager.open_battle(Battle::default()).unwrap();
+
+        manager.set_limits(HostLimits {
+            min_host_port: 9100,
+            max_host_port: 9100,
+            max_concurrent_games: 0,
+        });
+        let second = manager.open_battle(Battle::default()).unwrap();
+
+        assert_eq!(
+            manager.get_lobby(first).unwrap().get_battle().host_port,
+            9000
+        );
+        assert_eq!(
+            manager.get_lobby(second).unwrap().get_battle().host_port,
+            9100
+        );
+        assert!(!manager.has_capacity());
+    }
+
+    #[tokio::test]
+    async fn test_no_battles_are_opened_after_shutdown() {
+        let config = build_config(1);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+
+        manager.shutdown(Duration::from_secs(1)).await.unwrap();
+        let result = manager.open_battle(Battle::default());
+
+        assert!(matches!(result, Err(BattleManagerError::ShuttingDown)));
+    }
+
+    #[test]
+    fn test_no_battles_are_opened_while_draining() {
+        let config = build_config(1);
+        let mut manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+
+        manager.set_draining(true);
+        let result = manager.open_battle(Battle::default());
+        assert!(matches!(result, Err(BattleManagerError::Draining)));
+
+        manager.set_draining(false);
+        assert!(manager.open_battle(Battle::default()).is_ok());
+    }
+}
@@ -0,0 +1,576 @@
+use std::future::Future;
+use std::result::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn, Instrument, Span};
+
+use super::admin::{AdminCommand, AdminError, AdminRequest, AdminResponse, BattleStatus};
+use super::battle_manager::{BattleId, BattleManager, BattleManagerError, HostLimits};
+use super::engine_log::LogKind;
+use super::lobby::{Lobby, LobbyError};
+use super::spring::{GameUpdate, SpringGame};
+use crate::server_coms::server::Server;
+use crate::server_coms::server_error::ServerError;
+use crate::server_coms::server_request::ServerRequest;
+use crate::utils::config::ConfigError;
+use crate::utils::config_watcher::{ConfigReload, ConfigWatcher};
+use crate::utils::error_report::ErrorChain;
+use crate::utils::health::Health;
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error(transparent)]
+    Server(#[from] ServerError),
+    #[error(transparent)]
+    BattleManager(#[from] BattleManagerError),
+}
+
+/// Keeps the autohost connected to the server, hosting the battles it asks for until
+/// told to shut down.
+pub struct Daemon<'a> {
+    server: &'a mut (dyn Server + Send),
+    battle_manager: BattleManager<'a>,
+    config_watcher: Option<&'a mut (dyn ConfigWatcher + Send)>,
+    health: Option<Arc<Health>>,
+    admin_requests: Option<mpsc::Receiver<AdminRequest>>,
+    game_stop_timeout: Duration,
+}
+
+impl<'a> Daemon<'a> {
+    pub fn new(
+        server: &'a mut (dyn Server + Send),
+        battle_manager: BattleManager<'a>,
+        game_stop_timeout: Duration,
+    ) -> Daemon<'a> {
+        Daemon {
+            server,
+            battle_manager,
+            config_watcher: None,
+            health: None,
+            admin_requests: None,
+            game_stop_timeout,
+        }
+    }
+
+    /// Applies the config changes found by `config_watcher` that are safe while
+    /// running, and reports the ones that need a restart.
+    pub fn set_config_watcher(&mut self, config_watcher: &'a mut (dyn ConfigWatcher + Send)) {
+        self.config_watcher = Some(config_watcher);
+    }
+
+    /// Sets the health whether games can be started and the config is valid are
+    /// reported in.
+    pub fn set_health(&mut self, health: Arc<Health>) {
+        self.health = Some(health);
+    }
+
+    /// Serves the admin commands sent by the clients of `admin_requests`, see
+    /// `admin::channel`. A `Shutdown` command ends `run` like its `shutdown` future does.
+    pub fn set_admin_requests(&mut self, admin_requests: mpsc::Receiver<AdminRequest>) {
+        self.admin_requests = Some(admin_requests);
+    }
+
+    pub fn get_battle_manager(&self) -> &BattleManager<'a> {
+        &self.battle_manager
+    }
+
+    /// Starts a server session and serves its requests until `shutdown` resolves.
+    /// Running games are then stopped, and the session is ended.
+    ///
+    /// # Errors
+    ///
+    /// A `DaemonError::Server` is returned if the session can't be started or the
+    /// connection to the server is lost. Games are stopped and the session ended either
+    /// way, and the first error encountered is returned.
+    ///
+    pub async fn run(&mut self, shutdown: impl Future<Output = ()>) -> Result<(), DaemonError> {
+        self.server.start_session().await?;
+
+        let served = self.serve(shutdown).await;
+        if let Some(health) = &self.health {
+            health.set_capacity_available(false);
+        }
+        let stopped = self.battle_manager.shutdown(self.game_stop_timeout).await;
+        let ended = self.server.end_session().await;
+
+        served?;
+        stopped?;
+        ended?;
+
+        Ok(())
+    }
+
+    async fn serve(&mut self, shutdown: impl Future<Output = ()>) -> Result<(), DaemonError> {
+        tokio::pin!(shutdown);
+
+        loop {
+            if let Some(health) = &self.health {
+                health.set_capacity_available(self.battle_manager.has_capacity());
+            }
+
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                request = self.server.next_request() => {
+                    let request = request?;
+                    debug!(?request, "Received server request");
+                    // A request that can't be honoured mustn't take the other battles down.
+                    if let Err(e) = self.handle_request(request).await {
+                        warn!(error = %ErrorChain(&e), "Failed to handle server request");
+                    }
+                }
+                (battle_id, update) = self.battle_manager.next_update() => {
+                    let span = self.get_battle_span(battle_id);
+                    let handled = self
+                        .handle_game_update(battle_id, update)
+                        .instrument(span.clone())
+                        .await;
+                    if let Err(e) = handled {
+                        warn!(parent: &span, error = %ErrorChain(&e), "Failed to report game");
+                    }
+                }
+                reload = next_reload(&mut self.config_watcher) => self.handle_reload(reload),
+                request = next_admin_request(&mut self.admin_requests) => {
+                    info!(command = ?request.command, "Received admin request");
+                    let shutdown = request.command == AdminCommand::Shutdown;
+
+                    let handled = self.handle_admin_command(request.command.clone()).await;
+                    if let Err(e) = &handled {
+                        warn!(error = %ErrorChain(e), "Failed to handle admin request");
+                    }
+                    request.respond(handled);
+
+                    if shutdown {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_request(&mut self, request: ServerRequest) -> Result<(), DaemonError> {
+        match request {
+            ServerRequest::OpenBattle(battle, lobby_id) => {
+                let battle_id = self.battle_manager.open_battle(battle)?;
+                let lobby = self.battle_manager.get_lobby_mut(battle_id).unwrap();
+                lobby.set_server_lobby_id(lobby_id);
+                self.server
+                    .battle_opened(battle_id, lobby.get_battle(), lobby.get_server_lobby_id())
+                    .await?;
+            }
+            ServerRequest::StartGame(battle_id) => {
+                self.battle_manager.start_game(battle_id)?;
+                self.server.battle_status_changed(battle_id, true).await?;
+            }
+            ServerRequest::CloseBattle(battle_id) => {
+                self.battle_manager
+                    .close_battle(battle_id, self.game_stop_timeout)
+                    .await?;
+                self.server.battle_closed(battle_id).await?;
+            }
+            ServerRequest::AddPlayer(battle_id, player) => {
+                self.lobby_mut(battle_id)?.add_player(player);
+            }
+            ServerRequest::RemovePlayer(battle_id, name) => {
+                self.lobby_mut(battle_id)?.remove_player(&name);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_admin_command(
+        &mut self,
+        command: AdminCommand,
+    ) -> Result<AdminResponse, AdminError> {
+        match command {
+            AdminCommand::ListBattles => {
+                let battles = self
+                    .battle_manager
+                    .get_battle_ids()
+                    .into_iter()
+                    .filter_map(|battle_id| {
+                        let lobby = self.battle_manager.get_lobby_mut(battle_id)?;
+                        Some(BattleStatus::new(battle_id, lobby))
+                    })
+                    .collect();
+                return Ok(AdminResponse::Battles(battles));
+            }
+            AdminCommand::GetBattle(battle_id) => {
+                let lobby = self.lobby_mut(battle_id)?;
+                return Ok(AdminResponse::Battle(BattleStatus::new(battle_id, lobby)));
+            }
+            AdminCommand::ForceStart(battle_id) => {
+                self.running_game(battle_id)?.force_start().await?;
+            }
+            // The exit of the game is reported by `next_update` like any other.
+            AdminCommand::StopGame(battle_id) => {
+                let timeout = self.game_stop_timeout;
+                self.running_lobby_mut(battle_id)?
+                    .request_stop_game(timeout)
+                    .await?;
+                return Ok(AdminResponse::Accepted);
+            }
+            AdminCommand::KillGame(battle_id) => {
+                self.running_lobby_mut(battle_id)?
+                    .request_stop_game(Duration::ZERO)
+                    .await?;
+                return Ok(AdminResponse::Accepted);
+            }
+            AdminCommand::Say(battle_id, message) => {
+                self.running_game(battle_id)?.say(&message).await?;
+            }
+            AdminCommand::SetDraining(draining) => {
+                self.battle_manager.set_draining(draining);
+                if let Some(health) = &self.health {
+                    health.set_draining(draining);
+                }
+            }
+            // Carried out by `serve` once the response is sent.
+            AdminCommand::Shutdown => return Ok(AdminResponse::Accepted),
+        }
+
+        Ok(AdminResponse::Done)
+    }
+
+    async fn handle_game_update(
+        &mut self,
+        battle_id: BattleId,
+        update: Result<GameUpdate, LobbyError>,
+    ) -> Result<(), DaemonError> {
+        match update {
+            Ok(GameUpdate::Exited(exit)) => {
+                info!(duration = ?exit.duration, status = %exit.status, "Game ended");
+                // The server was already told about the battle closing.
+                if !self.battle_manager.is_closing(battle_id) {
+                    self.server.battle_status_changed(battle_id, false).await?;
+                }
+            }
+            Ok(GameUpdate::Event(event)) => debug!(?event, "Engine event"),
+            Ok(GameUpdate::Log(line)) => match line.kind {
+                LogKind::Info => debug!(source = ?line.source, "{}", line.text),
+                kind => warn!(source = ?line.source, ?kind, "{}", line.text),
+            },
+            Err(e) => error!(error = %ErrorChain(&e), "Failed to follow game"),
+        }
+
+        Ok(())
+    }
+
+    fn handle_reload(&mut self, reload: Result<ConfigReload, ConfigError>) {
+        if let Some(health) = &self.health {
+            health.set_config_valid(reload.is_ok());
+        }
+        let reload = match reload {
+            Ok(reload) => reload,
+            Err(e) => {
+                error!(
+                    error = %ErrorChain(&e),
+                    "Failed to reload the config, keeping the current one"
+                );
+                return;
+            }
+        };
+
+        self.battle_manager
+            .set_limits(HostLimits::from_config(reload.config.as_ref()));
+
+        if !reload.changes.applied.is_empty() {
+            info!(
+                applied = ?reload.changes.applied,
+                "Config reloaded, changes were applied"
+            );
+        }
+        if !reload.changes.needs_restart.is_empty() {
+            warn!(
+                needs_restart = ?reload.changes.needs_restart,
+                "Config reloaded, some changes need a restart to take effect"
+            );
+        }
+    }
+
+    fn get_battle_span(&self, battle_id: BattleId) -> Span {
+        self.battle_manager
+            .get_lobby(battle_id)
+            .map_or_else(Span::none, |lobby| lobby.get_span().clone())
+    }
+
+    fn running_lobby_mut(&mut self, battle_id: BattleId) -> Result<&mut Lobby<'a>, AdminError> {
+        let lobby = self
+            .battle_manager
+            .get_lobby_mut(battle_id)
+            .ok_or(BattleManagerError::BattleNotFound(battle_id))?;
+        if !lobby.is_game_running() {
+            return Err(AdminError::NoGameRunning(battle_id));
+        }
+
+        Ok(lobby)
+    }
+
+    fn running_game(&mut self, battle_id: BattleId) -> Result<&SpringGame, AdminError> {
+        self.running_lobby_mut(battle_id)?
+            .get_game()
+            .ok_or(AdminError::NoGameRunning(battle_id))
+    }
+
+    fn lobby_mut(&mut self, battle_id: BattleId) -> Result<&mut Lobby<'a>, BattleManagerError> {
+        self.battle_manager
+            .get_lobby_mut(battle_id)
+            .ok_or(BattleManagerError::BattleNotFound(battle_id))
+    }
+}
+
+/// Waits for the next reload of the config, forever if it isn't watched.
+async fn next_reload(
+    config_watcher: &mut Option<&mut (dyn ConfigWatcher + Send)>,
+) -> Result<ConfigReload, ConfigError> {
+    match config_watcher {
+        Some(config_watcher) => config_watcher.next_reload().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next admin request, forever if there are no admin clients.
+async fn next_admin_request(
+    admin_requests: &mut Option<mpsc::Receiver<AdminRequest>>,
+) -> AdminRequest {
+    match admin_requests {
+        Some(receiver) => match receiver.recv().await {
+            Some(request) => request,
+            None => {
+                *admin_requests = None;
+                std::future::pending().await
+            }
+        },
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::autohost::admin;
+    use crate::autohost::battle::{Battle, BattlePlayer};
+    use crate::autohost::battle_manager::fake::{FakeEnvironment, FakeSpring};
+    use crate::server_coms::protocol::LobbyId;
+    use crate::utils::config::fake::FakeConfig;
+    use crate::utils::config::ConfigChanges;
+
+    #[derive(Default)]
+    struct FakeServer {
+        requests: VecDeque<ServerRequest>,
+        open_battles: Vec<BattleId>,
+        session_started: bool,
+        session_ended: bool,
+    }
+
+    #[async_trait]
+    impl Server for FakeServer {
+        async fn start_session(&mut self) -> Result<(), ServerError> {
+            self.session_started = true;
+            Ok(())
+        }
+
+        async fn end_session(&mut self) -> Result<(), ServerError> {
+            self.session_ended = true;
+            Ok(())
+        }
+
+        async fn next_request(&mut self) -> Result<ServerRequest, ServerError> {
+            match self.requests.pop_front() {
+                Some(request) => Ok(request),
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn battle_opened(
+            &mut self,
+            battle_id: BattleId,
+            _battle: &Battle,
+            _lobby_id: Option<LobbyId>,
+        ) -> Result<(), ServerError> {
+            self.open_battles.push(battle_id);
+            Ok(())
+        }
+
+        async fn battle_closed(&mut self, battle_id: BattleId) -> Result<(), ServerError> {
+            self.open_battles.retain(|id| *id != battle_id);
+            Ok(())
+        }
+
+        async fn battle_status_changed(
+            &mut self,
+            _battle_id: BattleId,
+            _game_running: bool,
+        ) -> Result<(), ServerError> {
+            Ok(())
+        }
+    }
+
+    struct FakeConfigWatcher {
+        reloads: VecDeque<Result<ConfigReload, ConfigError>>,
+    }
+
+    #[async_trait]
+    impl ConfigWatcher for FakeConfigWatcher {
+        async fn next_reload(&mut self) -> Result<ConfigReload, ConfigError> {
+            match self.reloads.pop_front() {
+                Some(reload) => reload,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_is_ended_on_shutdown() {
+        let config = FakeConfig {
+            max_concurrent_games: 1,
+            ..FakeConfig::default()
+        };
+        let battle_manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+        let mut server = FakeServer::default();
+
+        Daemon::new(&mut server, battle_manager, Duration::from_secs(1))
+            .run(async {})
+            .await
+            .unwrap();
+
+        assert!(server.session_started);
+        assert!(server.session_ended);
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_handled_until_shutdown() {
+        let config = FakeConfig {
+            max_concurrent_games: 1,
+            ..FakeConfig::default()
+        };
+        let battle_manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+        let mut server = FakeServer {
+            requests: VecDeque::from([
+                ServerRequest::OpenBattle(Battle::default(), None),
+                ServerRequest::OpenBattle(Battle::default(), None),
+                ServerRequest::CloseBattle(1),
+                ServerRequest::StartGame(42),
+                ServerRequest::AddPlayer(2, BattlePlayer::new("player", Some(0))),
+            ]),
+            ..FakeServer::default()
+        };
+
+        let mut daemon = Daemon::new(&mut server, battle_manager, Duration::from_secs(1));
+        daemon
+            .run(tokio::time::sleep(Duration::from_millis(100)))
+            .await
+            .unwrap();
+
+        let battle_manager = daemon.get_battle_manager();
+        assert_eq!(battle_manager.get_battle_ids(), vec![2]);
+        assert_eq!(
+            battle_manager.get_lobby(2).unwrap().get_battle().players,
+            vec![BattlePlayer::new("player", Some(0))]
+        );
+        drop(daemon);
+        assert_eq!(server.open_battles, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_limits_are_applied() {
+        let config = FakeConfig {
+            max_concurrent_games: 1,
+            ..FakeConfig::default()
+        };
+        let battle_manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+        let mut server = FakeServer::default();
+        let mut config_watcher = FakeConfigWatcher {
+            reloads: VecDeque::from([
+                Err(ConfigError::Invalid(Vec::new())),
+                Ok(ConfigReload {
+                    config: Box::new(FakeConfig {
+                        max_concurrent_games: 4,
+                        ..FakeConfig::default()
+                    }),
+                    changes: ConfigChanges {
+                        applied: vec!["max_concurrent_games"],
+                        needs_restart: Vec::new(),
+                    },
+                }),
+            ]),
+        };
+
+        let mut daemon = Daemon::new(&mut server, battle_manager, Duration::from_secs(1));
+        daemon.set_config_watcher(&mut config_watcher);
+        daemon
+            .run(tokio::time::sleep(Duration::from_millis(100)))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            daemon
+                .get_battle_manager()
+                .get_limits()
+                .max_concurrent_games,
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_commands_are_served_until_shutdown() {
+        let config = FakeConfig {
+            max_concurrent_games: 1,
+            ..FakeConfig::default()
+        };
+        let battle_manager =
+            BattleManager::new(&config, &FakeSpring {}, &FakeEnvironment {}, Arc::default());
+        let mut server = FakeServer {
+            requests: VecDeque::from([ServerRequest::OpenBattle(
+                Battle::new("Beyond All Reason test-1", "Red Comet"),
+                None,
+            )]),
+            ..FakeServer::default()
+        };
+        let (client, admin_requests) = admin::channel();
+
+        let mut daemon = Daemon::new(&mut server, battle_manager, Duration::from_secs(1));
+        daemon.set_admin_requests(admin_requests);
+        let admin = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let battles = client.send(AdminCommand::ListBattles).await.unwrap();
+            let stopped = client.send(AdminCommand::StopGame(1)).await;
+            let missing = client.send(AdminCommand::GetBattle(42)).await;
+            client.send(AdminCommand::SetDraining(true)).await.unwrap();
+            let shutdown = client.send(AdminCommand::Shutdown).await.unwrap();
+
+            (battles, stopped, missing, shutdown)
+        };
+        let (ran, (battles, stopped, missing, shutdown)) =
+            tokio::join!(daemon.run(std::future::pending()), admin);
+
+        ran.unwrap();
+        let AdminResponse::Battles(battles) = battles else {
+            panic!("Unexpected response {:?}", battles);
+        };
+        assert_eq!(battles.len(), 1);
+        assert_eq!(battles[0].map_name, "Red Comet");
+        assert_eq!(battles[0].game_state, admin::GameState::Waiting);
+        assert!(matches!(stopped, Err(AdminError::NoGameRunning(1))));
+        assert!(matches!(
+            missing,
+            Err(AdminError::BattleManager(
+                BattleManagerError::BattleNotFound(42)
+            ))
+        ));
+        assert_eq!(shutdown, AdminResponse::Accepted);
+        assert!(daemon.get_battle_manager().is_draining());
+        drop(daemon);
+        assert!(server.session_ended);
+    }
+}
@@ -0,0 +1,857 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{warn, Instrument};
+
+/// The engine connects to the autohost interface from the same machine.
+pub const AUTOHOST_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+const MAX_PACKET_SIZE: usize = 65536;
+const EVENT_CHANNEL_SIZE: usize = 256;
+
+const SERVER_STARTED: u8 = 0;
+const SERVER_QUIT: u8 = 1;
+const SERVER_STARTPLAYING: u8 = 2;
+const SERVER_GAMEOVER: u8 = 3;
+const SERVER_MESSAGE: u8 = 4;
+const SERVER_WARNING: u8 = 5;
+const PLAYER_JOINED: u8 = 10;
+const PLAYER_LEFT: u8 = 11;
+const PLAYER_READY: u8 = 12;
+const PLAYER_CHAT: u8 = 13;
+const PLAYER_DEFEATED: u8 = 14;
+const GAME_LUAMSG: u8 = 20;
+const GAME_TEAMSTAT: u8 = 60;
+
+/// The id of the engine's network message a `GAME_LUAMSG` forwards whole.
+const NETMSG_LUAMSG: u8 = 50;
+
+const GAME_ID_SIZE: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum EngineInterfaceError {
+    #[error("Failed to bind the autohost interface")]
+    Bind(#[from] io::Error),
+    #[error("Failed to decode engine message: {0}")]
+    Decode(String),
+    #[error("The engine is not listening on the autohost interface")]
+    NotListening,
+    #[error("Failed to send to the engine")]
+    Send(#[source] io::Error),
+    #[error("Failed to receive from the engine")]
+    Receive(#[source] io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaveReason {
+    LostConnection,
+    Left,
+    Kicked,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadyState {
+    NotReady,
+    Ready,
+    Unchanged,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatDestination {
+    Allies,
+    Spectators,
+    Everyone,
+    Player(u8),
+}
+
+/// The statistics the engine reports for a team, in the order of its `TeamStatistics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TeamStatistics {
+    pub frame: i32,
+    pub metal_used: f32,
+    pub energy_used: f32,
+    pub metal_produced: f32,
+    pub energy_produced: f32,
+    pub metal_excess: f32,
+    pub energy_excess: f32,
+    pub metal_received: f32,
+    pub energy_received: f32,
+    pub metal_sent: f32,
+    pub energy_sent: f32,
+    pub damage_dealt: f32,
+    pub damage_received: f32,
+    pub units_produced: i32,
+    pub units_died: i32,
+    pub units_received: i32,
+    pub units_sent: i32,
+    pub units_captured: i32,
+    pub units_out_captured: i32,
+    pub units_killed: i32,
+}
+
+/// A message sent by a running engine over its autohost interface.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EngineEvent {
+    ServerStarted,
+    ServerQuit,
+    ServerStartPlaying {
+        game_id: String,
+        demo_name: String,
+    },
+    ServerGameOver {
+        player: u8,
+        winning_ally_teams: Vec<u8>,
+    },
+    ServerMessage(String),
+    ServerWarning(String),
+    PlayerJoined {
+        player: u8,
+        name: String,
+    },
+    PlayerLeft {
+        player: u8,
+        reason: LeaveReason,
+    },
+    PlayerReady {
+        player: u8,
+        state: ReadyState,
+    },
+    PlayerChat {
+        player: u8,
+        destination: ChatDestination,
+        message: String,
+    },
+    PlayerDefeated {
+        player: u8,
+    },
+    GameLuaMsg {
+        player: u8,
+        script: u16,
+        mode: u8,
+        data: Vec<u8>,
+    },
+    GameTeamStat {
+        team: u8,
+        statistics: TeamStatistics,
+    },
+    Unknown {
+        message_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl EngineEvent {
+    /// Decodes a single UDP packet. All values are little endian and strings take up
+    /// the rest of the packet, without a terminator.
+    ///
+    /// # Errors
+    ///
+    /// An `EngineInterfaceError::Decode` is returned if the packet is empty or shorter
+    /// than its message type requires.
+    ///
+    pub fn decode(packet: &[u8]) -> Result<Self, EngineInterfaceError> {
+        let mut reader = PacketReader { data: packet };
+
+        let event = match reader.u8()? {
+            SERVER_STARTED => EngineEvent::ServerStarted,
+            SERVER_QUIT => EngineEvent::ServerQuit,
+            SERVER_STARTPLAYING => {
+                let _message_size = reader.u32()?;
+                let game_id = reader
+                    .bytes(GAME_ID_SIZE)?
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+
+                EngineEvent::ServerStartPlaying {
+                    game_id,
+                    demo_name: reader.string(),
+                }
+            }
+            SERVER_GAMEOVER => {
+                let _message_size = reader.u8()?;
+
+                EngineEvent::ServerGameOver {
+                    player: reader.u8()?,
+                    winning_ally_teams: reader.rest().to_vec(),
+                }
+            }
+            SERVER_MESSAGE => EngineEvent::ServerMessage(reader.string()),
+            SERVER_WARNING => EngineEvent::ServerWarning(reader.string()),
+            PLAYER_JOINED => EngineEvent::PlayerJoined {
+                player: reader.u8()?,
+                name: reader.string(),
+            },
+            PLAYER_LEFT => EngineEvent::PlayerLeft {
+                player: reader.u8()?,
+                reason: match reader.u8()? {
+                    0 => LeaveReason::LostConnection,
+                    1 => LeaveReason::Left,
+                    2 => LeaveReason::Kicked,
+                    reason => LeaveReason::Unknown(reason),
+                },
+            },
+            PLAYER_READY => EngineEvent::PlayerReady {
+                player: reader.u8()?,
+                state: match reader.u8()? {
+                    0 => ReadyState::NotReady,
+                    1 => ReadyState::Ready,
+                    2 => ReadyState::Unchanged,
+                    state => ReadyState::Unknown(state),
+                },
+            },
+            PLAYER_CHAT => EngineEvent::PlayerChat {
+                player: reader.u8()?,
+                destination: match reader.u8()? {
+                    252 => ChatDestination::Allies,
+                    253 => ChatDestination::Spectators,
+                    254 => ChatDestination::Everyone,
+                    player => ChatDestination::Player(player),
+                },
+                message: reader.string(),
+            },
+            PLAYER_DEFEATED => EngineEvent::PlayerDefeated {
+                player: reader.u8()?,
+            },
+            GAME_LUAMSG => {
+                let _message_id = reader.u8()?;
+                let _message_size = reader.u16()?;
+
+                EngineEvent::GameLuaMsg {
+                    player: reader.u8()?,
+                    script: reader.u16()?,
+                    mode: reader.u8()?,
+                    data: reader.rest().to_vec(),
+                }
+            }
+            GAME_TEAMSTAT => EngineEvent::GameTeamStat {
+                team: reader.u8()?,
+                statistics: TeamStatistics {
+                    frame: reader.i32()?,
+                    metal_used: reader.f32()?,
+                    energy_used: reader.f32()?,
+                    metal_produced: reader.f32()?,
+                    energy_produced: reader.f32()?,
+                    metal_excess: reader.f32()?,
+                    energy_excess: reader.f32()?,
+                    metal_received: reader.f32()?,
+                    energy_received: reader.f32()?,
+                    metal_sent: reader.f32()?,
+                    energy_sent: reader.f32()?,
+                    damage_dealt: reader.f32()?,
+                    damage_received: reader.f32()?,
+                    units_produced: reader.i32()?,
+                    units_died: reader.i32()?,
+                    units_received: reader.i32()?,
+                    units_sent: reader.i32()?,
+                    units_captured: reader.i32()?,
+                    units_out_captured: reader.i32()?,
+                    units_killed: reader.i32()?,
+                },
+            },
+            message_type => EngineEvent::Unknown {
+                message_type,
+                data: reader.rest().to_vec(),
+            },
+        };
+
+        Ok(event)
+    }
+
+    /// Encodes the event into the packet the engine would send for it, the inverse of
+    /// `decode`.
+    ///
+    /// The game id of `ServerStartPlaying` is expected as hex, as `decode` returns it.
+    /// Missing or invalid digits are encoded as zeroes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+
+        match self {
+            EngineEvent::ServerStarted => packet.push(SERVER_STARTED),
+            EngineEvent::ServerQuit => packet.push(SERVER_QUIT),
+            EngineEvent::ServerStartPlaying { game_id, demo_name } => {
+                let message_size = 1 + 4 + GAME_ID_SIZE + demo_name.len();
+
+                packet.push(SERVER_STARTPLAYING);
+                packet.extend_from_slice(&(message_size as u32).to_le_bytes());
+                packet.extend((0..GAME_ID_SIZE).map(|index| {
+                    game_id
+                        .get(index * 2..index * 2 + 2)
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        .unwrap_or(0)
+                }));
+                packet.extend_from_slice(demo_name.as_bytes());
+            }
+            EngineEvent::ServerGameOver {
+                player,
+                winning_ally_teams,
+            } => {
+                let message_size = 1 + 1 + 1 + winning_ally_teams.len();
+
+                packet.extend_from_slice(&[SERVER_GAMEOVER, message_size as u8, *player]);
+                packet.extend_from_slice(winning_ally_teams);
+            }
+            EngineEvent::ServerMessage(message) => {
+                packet.push(SERVER_MESSAGE);
+                packet.extend_from_slice(message.as_bytes());
+            }
+            EngineEvent::ServerWarning(message) => {
+                packet.push(SERVER_WARNING);
+                packet.extend_from_slice(message.as_bytes());
+            }
+            EngineEvent::PlayerJoined { player, name } => {
+                packet.extend_from_slice(&[PLAYER_JOINED, *player]);
+                packet.extend_from_slice(name.as_bytes());
+            }
+            EngineEvent::PlayerLeft { player, reason } => {
+                let reason = match reason {
+                    LeaveReason::LostConnection => 0,
+                    LeaveReason::Left => 1,
+                    LeaveReason::Kicked => 2,
+                    LeaveReason::Unknown(reason) => *reason,
+                };
+
+                packet.extend_from_slice(&[PLAYER_LEFT, *player, reason]);
+            }
+            EngineEvent::PlayerReady { player, state } => {
+                let state = match state {
+                    ReadyState::NotReady => 0,
+                    ReadyState::Ready => 1,
+                    ReadyState::Unchanged => 2,
+                    ReadyState::Unknown(state) => *state,
+                };
+
+                packet.extend_from_slice(&[PLAYER_READY, *player, state]);
+            }
+            EngineEvent::PlayerChat {
+                player,
+                destination,
+                message,
+            } => {
+                let destination = match destination {
+                    ChatDestination::Allies => 252,
+                    ChatDestination::Spectators => 253,
+                    ChatDestination::Everyone => 254,
+                    ChatDestination::Player(player) => *player,
+                };
+
+                packet.extend_from_slice(&[PLAYER_CHAT, *player, destination]);
+                packet.extend_from_slice(message.as_bytes());
+            }
+            EngineEvent::PlayerDefeated { player } => {
+                packet.extend_from_slice(&[PLAYER_DEFEATED, *player]);
+            }
+            EngineEvent::GameLuaMsg {
+                player,
+                script,
+                mode,
+                data,
+            } => {
+                let message_size = 1 + 2 + 1 + 2 + 1 + data.len();
+
+                packet.extend_from_slice(&[GAME_LUAMSG, NETMSG_LUAMSG]);
+                packet.extend_from_slice(&(message_size as u16).to_le_bytes());
+                packet.push(*player);
+                packet.extend_from_slice(&script.to_le_bytes());
+                packet.push(*mode);
+                packet.extend_from_slice(data);
+            }
+            EngineEvent::GameTeamStat { team, statistics } => {
+                packet.extend_from_slice(&[GAME_TEAMSTAT, *team]);
+                packet.extend_from_slice(&statistics.frame.to_le_bytes());
+                for value in [
+                    statistics.metal_used,
+                    statistics.energy_used,
+                    statistics.metal_produced,
+                    statistics.energy_produced,
+                    statistics.metal_excess,
+                    statistics.energy_excess,
+                    statistics.metal_received,
+                    statistics.energy_received,
+                    statistics.metal_sent,
+                    statistics.energy_sent,
+                    statistics.damage_dealt,
+                    statistics.damage_received,
+                ] {
+                    packet.extend_from_slice(&value.to_le_bytes());
+                }
+                for value in [
+                    statistics.units_produced,
+                    statistics.units_died,
+                    statistics.units_received,
+                    statistics.units_sent,
+                    statistics.units_captured,
+                    statistics.units_out_captured,
+                    statistics.units_killed,
+                ] {
+                    packet.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            EngineEvent::Unknown { message_type, data } => {
+                packet.push(*message_type);
+                packet.extend_from_slice(data);
+            }
+        }
+
+        packet
+    }
+}
+
+struct PacketReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PacketReader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], EngineInterfaceError> {
+        if self.data.len() < count {
+            return Err(EngineInterfaceError::Decode(format!(
+                "Packet too short, expected {} more bytes but only {} are left",
+                count,
+                self.data.len()
+            )));
+        }
+
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], EngineInterfaceError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, EngineInterfaceError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EngineInterfaceError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, EngineInterfaceError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, EngineInterfaceError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, EngineInterfaceError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+
+    fn string(&mut self) -> String {
+        String::from_utf8_lossy(self.rest()).into_owned()
+    }
+}
+
+/// Listens for the messages a running engine sends to the address configured with
+/// `AutohostIP` and `AutohostPort` in its start script.
+///
+/// Packets are received on a background task and decoded into `EngineEvent`s. The
+/// event stream ends after the engine reports `ServerQuit`, after an error receiving
+/// from the socket, which is yielded first, or when the interface is dropped.
+///
+/// The engine doesn't announce the port it sends from, so the interface is bound to the
+/// address `SERVER_STARTED` was first received from. Packets from anywhere else are
+/// dropped, and packets are only sent back to that address.
+pub struct EngineInterface {
+    local_addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    engine_addr: Arc<Mutex<Option<SocketAddr>>>,
+    events: mpsc::Receiver<Result<EngineEvent, EngineInterfaceError>>,
+    receive_task: JoinHandle<()>,
+}
+
+impl EngineInterface {
+    /// Binds a UDP socket on a free port of `ip`. Must be called from within a tokio
+    /// runtime.
+    pub fn bind(ip: IpAddr) -> Result<Self, EngineInterfaceError> {
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(ip, 0))?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let local_addr = socket.local_addr()?;
+        let engine_addr = Arc::new(Mutex::new(None));
+
+        let (sender, events) = mpsc::channel(EVENT_CHANNEL_SIZE);
+        let receive_task = tokio::spawn(
+            receive_events(socket.clone(), engine_addr.clone(), sender).in_current_span(),
+        );
+
+        Ok(EngineInterface {
+            local_addr,
+            socket,
+            engine_addr,
+            events,
+            receive_task,
+        })
+    }
+
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for the next event of the game, `None` once the stream has ended.
+    ///
+    /// # Errors
+    ///
+    /// An `EngineInterfaceError::Receive` is returned if the socket failed, after which
+    /// the stream ends.
+    ///
+    pub async fn next_event(&mut self) -> Option<Result<EngineEvent, EngineInterfaceError>> {
+        self.events.recv().await
+    }
+
+    /// Sends a text packet to the engine. Text starting with `/` is executed as a
+    /// command, anything else is broadcast as a chat message from the host.
+    ///
+    /// # Errors
+    ///
+    /// An `EngineInterfaceError::NotListening` is returned if the engine hasn't started
+    /// yet, and an `EngineInterfaceError::Send` if the packet can't be sent.
+    ///
+    pub async fn send(&self, text: &str) -> Result<(), EngineInterfaceError> {
+        let engine_addr = self
+            .engine_addr
+            .lock()
+            .expect("engine address lock poisoned")
+            .ok_or(EngineInterfaceError::NotListening)?;
+
+        self.socket
+            .send_to(text.as_bytes(), engine_addr)
+            .await
+            .map_err(EngineInterfaceError::Send)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for EngineInterface {
+    fn drop(&mut self) {
+        self.receive_task.abort();
+    }
+}
+
+async fn receive_events(
+    socket: Arc<UdpSocket>,
+    engine_addr: Arc<Mutex<Option<SocketAddr>>>,
+    sender: mpsc::Sender<Result<EngineEvent, EngineInterfaceError>>,
+) {
+    let mut buffer = vec![0; MAX_PACKET_SIZE];
+
+    loop {
+        let (size, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) if is_transient(&e) => {
+                warn!(error = %e, "Failed to receive from the engine, retrying");
+                continue;
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to receive from the engine");
+                let _ = sender.send(Err(EngineInterfaceError::Receive(e))).await;
+                break;
+            }
+        };
+
+        // Malformed packets are dropped, the engine keeps sending regardless. They're
+        // logged, like unknown ones below, as both tell of an engine speaking another
+        // version of the protocol.
+        let message_type = buffer[..size].first().copied();
+        let event = match EngineEvent::decode(&buffer[..size]) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(from = %addr, ?message_type, size, error = %e, "Dropped a malformed engine packet");
+                continue;
+            }
+        };
+
+        {
+            let mut engine_addr = engine_addr.lock().expect("engine address lock poisoned");
+            match *engine_addr {
+                Some(engine_addr) if engine_addr != addr => {
+                    warn!(from = %addr, "Dropped a packet not sent by the engine");
+                    continue;
+                }
+                Some(_) => {}
+                None if event == EngineEvent::ServerStarted => *engine_addr = Some(addr),
+                None => {
+                    warn!(from = %addr, "Dropped a packet received before the engine started");
+                    continue;
+                }
+            }
+        }
+
+        if let EngineEvent::Unknown { message_type, .. } = &event {
+            warn!(from = %addr, message_type, size, "Received an unknown engine packet");
+        }
+
+        let is_quit = event == EngineEvent::ServerQuit;
+        if sender.send(Ok(event)).await.is_err() || is_quit {
+            break;
+        }
+    }
+}
+
+/// Whether a receive error leaves the socket usable. Some systems report an ICMP error
+/// caused by an earlier send on the next receive, such as when the engine had already
+/// quit.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_player_chat() {
+        let mut packet = vec![PLAYER_CHAT, 3, 254];
+        packet.extend_from_slice(b"gl hf");
+
+        let event = EngineEvent::decode(&packet).unwrap();
+
+        assert_eq!(
+            event,
+            EngineEvent::PlayerChat {
+                player: 3,
+                destination: ChatDestination::Everyone,
+                message: "gl hf".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_start_playing() {
+        let mut packet = vec![SERVER_STARTPLAYING];
+        packet.extend_from_slice(&29u32.to_le_bytes());
+        packet.extend_from_slice(&[0xab; GAME_ID_SIZE]);
+        packet.extend_from_slice(b"demo.sdfz");
+
+        let event = EngineEvent::decode(&packet).unwrap();
+
+        assert_eq!(
+            event,
+            EngineEvent::ServerStartPlaying {
+                game_id: "ab".repeat(GAME_ID_SIZE),
+                demo_name: "demo.sdfz".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_team_stat() {
+        let mut packet = vec![GAME_TEAMSTAT, 1];
+        packet.extend_from_slice(&900i32.to_le_bytes());
+        for value in 1..=12 {
+            packet.extend_from_slice(&(value as f32).to_le_bytes());
+        }
+        for value in 1..=7i32 {
+            packet.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let event = EngineEvent::decode(&packet).unwrap();
+
+        match event {
+            EngineEvent::GameTeamStat { team, statistics } => {
+                assert_eq!(team, 1);
+                assert_eq!(statistics.frame, 900);
+                assert_eq!(statistics.metal_used, 1.0);
+                assert_eq!(statistics.damage_received, 12.0);
+                assert_eq!(statistics.units_killed, 7);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_decode_lua_msg() {
+        // GAME_LUAMSG forwards the whole NETMSG_LUAMSG packet: message id, u16 packet
+        // size, player, u16 script, mode and data.
+        let packet = [GAME_LUAMSG, 50, 10, 0, 4, 0xf4, 0x01, 1, 1, 2, 3];
+
+        let event = EngineEvent::decode(&packet).unwrap();
+
+        assert_eq!(
+            event,
+            EngineEvent::GameLuaMsg {
+                player: 4,
+                script: 500,
+                mode: 1,
+                data: vec![1, 2, 3],
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_game_over() {
+        // The message size counts the type, size and player bytes with the ally teams.
+        let packet = [SERVER_GAMEOVER, 5, 2, 0, 3];
+
+        let event = EngineEvent::decode(&packet).unwrap();
+
+        assert_eq!(
+            event,
+            EngineEvent::ServerGameOver {
+                player: 2,
+                winning_ally_teams: vec![0, 3],
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_player_left() {
+        let event = EngineEvent::decode(&[PLAYER_LEFT, 1, 2]).unwrap();
+
+        assert_eq!(
+            event,
+            EngineEvent::PlayerLeft {
+                player: 1,
+                reason: LeaveReason::Kicked,
+            }
+        );
+    }
+
+    #[test]
+    fn test_encode_lua_msg() {
+        let event = EngineEvent::GameLuaMsg {
+            player: 4,
+            script: 500,
+            mode: 1,
+            data: vec![1, 2, 3],
+        };
+
+        assert_eq!(
+            event.encode(),
+            vec![GAME_LUAMSG, 50, 10, 0, 4, 0xf4, 0x01, 1, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_encode_game_over() {
+        let event = EngineEvent::ServerGameOver {
+            player: 2,
+            winning_ally_teams: vec![0, 3],
+        };
+
+        assert_eq!(event.encode(), vec![SERVER_GAMEOVER, 5, 2, 0, 3]);
+    }
+
+    #[test]
+    fn test_decode_fails_on_truncated_packet() {
+        let result = EngineEvent::decode(&[GAME_TEAMSTAT, 1, 0]);
+
+        assert!(matches!(result, Err(EngineInterfaceError::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn test_events_are_received_until_server_quit() {
+        let mut interface = EngineInterface::bind(AUTOHOST_IP).unwrap();
+        let engine = std::net::UdpSocket::bind((AUTOHOST_IP, 0)).unwrap();
+
+        for packet in [&[SERVER_STARTED][..], &[PLAYER_DEFEATED, 2], &[SERVER_QUIT]] {
+            engine.send_to(packet, interface.get_local_addr()).unwrap();
+        }
+
+        assert_eq!(
+            interface.next_event().await.unwrap().unwrap(),
+            EngineEvent::ServerStarted
+        );
+        assert_eq!(
+            interface.next_event().await.unwrap().unwrap(),
+            EngineEvent::PlayerDefeated { player: 2 }
+        );
+        assert_eq!(
+            interface.next_event().await.unwrap().unwrap(),
+            EngineEvent::ServerQuit
+        );
+        assert!(interface.next_event().await.is_none());
+    }
+
+    #[test]
+    fn test_only_socket_failures_end_the_stream() {
+        assert!(is_transient(&io::Error::from(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(!is_transient(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_before_engine_has_connected() {
+        let interface = EngineInterface::bind(AUTOHOST_IP).unwrap();
+
+        let result = interface.send("/pause").await;
+
+        assert!(matches!(result, Err(EngineInterfaceError::NotListening)));
+    }
+
+    #[tokio::test]
+    async fn test_send_replies_to_engine_address() {
+        let mut interface = EngineInterface::bind(AUTOHOST_IP).unwrap();
+        let engine = std::net::UdpSocket::bind((AUTOHOST_IP, 0)).unwrap();
+        engine
+            .send_to(&[SERVER_STARTED], interface.get_local_addr())
+            .unwrap();
+        interface.next_event().await;
+
+        interface.send("/nopause 1").await.unwrap();
+
+        let mut buffer = [0; 64];
+        let size = engine.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"/nopause 1");
+    }
+
+    #[tokio::test]
+    async fn test_packets_from_other_addresses_are_dropped() {
+        let mut interface = EngineInterface::bind(AUTOHOST_IP).unwrap();
+        let engine = std::net::UdpSocket::bind((AUTOHOST_IP, 0)).unwrap();
+        let intruder = std::net::UdpSocket::bind((AUTOHOST_IP, 0)).unwrap();
+
+        intruder
+            .send_to(&[PLAYER_DEFEATED, 1], interface.get_local_addr())
+            .unwrap();
+        engine
+            .send_to(&[SERVER_STARTED], interface.get_local_addr())
+            .unwrap();
+        intruder
+            .send_to(&[SERVER_QUIT], interface.get_local_addr())
+            .unwrap();
+        engine
+            .send_to(&[PLAYER_DEFEATED, 2], interface.get_local_addr())
+            .unwrap();
+
+        assert_eq!(
+            interface.next_event().await.unwrap().unwrap(),
+            EngineEvent::ServerStarted
+        );
+        assert_eq!(
+            interface.next_event().await.unwrap().unwrap(),
+            EngineEvent::PlayerDefeated { player: 2 }
+        );
+
+        interface.send("/nopause 1").await.unwrap();
+        let mut buffer = [0; 64];
+        let size = engine.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"/nopause 1");
+    }
+}
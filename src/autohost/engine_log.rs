@@ -0,0 +1,423 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::time::{Duration, SystemTime};
+
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Child;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinSet;
+use tracing::{warn, Instrument};
+
+const LINE_CHANNEL_SIZE: usize = 1024;
+/// How long to wait for the output pipes to close after the engine exited. Only a
+/// process the engine spawned could keep them open past that.
+const OUTPUT_END_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSource {
+    Stdout,
+    Stderr,
+    Infolog,
+}
+
+impl LogSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LogSource::Stdout => "stdout",
+            LogSource::Stderr => "stderr",
+            LogSource::Infolog => "infolog",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogKind {
+    Info,
+    Warning,
+    Error,
+    LuaError,
+    Desync,
+    GameOver,
+}
+
+/// A single line of engine output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EngineLogLine {
+    pub source: LogSource,
+    pub kind: LogKind,
+    pub text: String,
+}
+
+impl EngineLogLine {
+    pub fn new(source: LogSource, text: &str) -> Self {
+        EngineLogLine {
+            source,
+            kind: classify(text),
+            text: text.to_string(),
+        }
+    }
+}
+
+/// Classifies an engine log line by its content. The engine prefixes lines with a
+/// `[t=...][f=...]` timestamp and frame, followed by the log section and level.
+pub fn classify(line: &str) -> LogKind {
+    let line = line.to_lowercase();
+    let message = strip_prefixes(&line);
+
+    if line.contains("desync") || line.contains("sync error") {
+        LogKind::Desync
+    } else if line.contains("lua") && line.contains("error") {
+        LogKind::LuaError
+    } else if message.starts_with("error") || line.contains("error:") {
+        LogKind::Error
+    } else if message.starts_with("warning") || line.contains("warning:") {
+        LogKind::Warning
+    } else if line.contains("game over") || line.contains("gameover") || line.contains("gameend") {
+        LogKind::GameOver
+    } else {
+        LogKind::Info
+    }
+}
+
+fn strip_prefixes(mut line: &str) -> &str {
+    while let Some(rest) = line
+        .strip_prefix("[t=")
+        .or_else(|| line.strip_prefix("[f="))
+    {
+        line = match rest.find(']') {
+            Some(end) => rest[end + 1..].trim_start(),
+            None => return line,
+        };
+    }
+    line
+}
+
+/// Captures the output of an engine process, writing every line to a per-game log file
+/// and forwarding it, classified, to the reader of `next_line`.
+///
+/// The engine's `infolog.txt` is only complete once the process has exited, so it is
+/// appended by `capture_infolog` after the game ends and its output has been read,
+/// without the lines the engine already printed to stdout. An infolog older than the
+/// capture is left over from an earlier game and ignored. Lines are dropped from the
+/// stream, but never from the file, if they aren't read fast enough. The stream then
+/// reports how many were dropped once it catches up.
+pub struct EngineLog {
+    path: PathBuf,
+    infolog_path: PathBuf,
+    started_at: SystemTime,
+    readers: JoinSet<()>,
+    raw_lines: Option<mpsc::UnboundedSender<EngineLogLine>>,
+    lines: mpsc::Receiver<EngineLogLine>,
+}
+
+impl EngineLog {
+    /// Takes the piped stdout and stderr of `process`, if any, and starts writing them to
+    /// a new log file at `path`. Must be called from within a tokio runtime.
+    pub fn capture(
+        process: &mut Child,
+        path: &Path,
+        infolog_path: &Path,
+    ) -> Result<Self, io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::from_std(std::fs::File::create(path)?);
+
+        let (raw_sender, raw_lines) = mpsc::unbounded_channel();
+        let (sender, lines) = mpsc::channel(LINE_CHANNEL_SIZE);
+
+        let mut readers = JoinSet::new();
+        if let Some(stdout) = process.stdout.take() {
+            readers.spawn(read_lines(stdout, LogSource::Stdout, raw_sender.clone()));
+        }
+        if let Some(stderr) = process.stderr.take() {
+            readers.spawn(read_lines(stderr, LogSource::Stderr, raw_sender.clone()));
+        }
+        tokio::spawn(write_lines(file, path.to_path_buf(), raw_lines, sender).in_current_span());
+
+        Ok(EngineLog {
+            path: path.to_path_buf(),
+            infolog_path: infolog_path.to_path_buf(),
+            started_at: SystemTime::now(),
+            readers,
+            raw_lines: Some(raw_sender),
+            lines,
+        })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for the next line of output, `None` once the process' output has ended
+    /// and the infolog has been captured.
+    pub async fn next_line(&mut self) -> Option<EngineLogLine> {
+        self.lines.recv().await
+    }
+
+    /// Appends the engine's infolog to the log, once, after the rest of the output of
+    /// the exited process has been read. A missing or stale infolog is ignored, a
+    /// failure to read it is logged in its place.
+    pub async fn capture_infolog(&mut self) {
+        if self.raw_lines.is_none() {
+            return;
+        }
+
+        // The readers and the sender are only taken once they're done with, so the
+        // capture is simply repeated if it gets cancelled while waiting.
+        let output_end = async { while self.readers.join_next().await.is_some() {} };
+        if tokio::time::timeout(OUTPUT_END_TIMEOUT, output_end)
+            .await
+            .is_err()
+        {
+            warn!("The engine's output didn't end after it exited, capturing the infolog anyway");
+            self.readers.abort_all();
+        }
+        let infolog = self.read_infolog().await;
+        let Some(raw_lines) = self.raw_lines.take() else {
+            return;
+        };
+
+        match infolog {
+            Ok(None) => {}
+            Ok(Some(infolog)) => {
+                for line in String::from_utf8_lossy(&infolog).lines() {
+                    let _ = raw_lines.send(EngineLogLine::new(LogSource::Infolog, line));
+                }
+            }
+            Err(e) => {
+                let _ = raw_lines.send(EngineLogLine {
+                    source: LogSource::Infolog,
+                    kind: LogKind::Error,
+                    text: format!("Failed to read {}: {}", self.infolog_path.display(), e),
+                });
+            }
+        }
+    }
+
+    /// Reads the infolog, `None` if there is none written since the capture started.
+    async fn read_infolog(&self) -> Result<Option<Vec<u8>>, io::Error> {
+        let modified = match fs::metadata(&self.infolog_path).await {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if modified < self.started_at {
+            return Ok(None);
+        }
+
+        fs::read(&self.infolog_path).await.map(Some)
+    }
+}
+
+async fn read_lines<R: AsyncRead + Unpin>(
+    output: R,
+    source: LogSource,
+    raw_lines: mpsc::UnboundedSender<EngineLogLine>,
+) {
+    let mut output = BufReader::new(output).lines();
+
+    while let Ok(Some(line)) = output.next_line().await {
+        if raw_lines.send(EngineLogLine::new(source, &line)).is_err() {
+            break;
+        }
+    }
+}
+
+async fn write_lines(
+    file: File,
+    path: PathBuf,
+    mut raw_lines: mpsc::UnboundedReceiver<EngineLogLine>,
+    lines: mpsc::Sender<EngineLogLine>,
+) {
+    let mut file = Some(file);
+    let mut stdout_lines = PrintedLines::default();
+    let mut dropped = 0;
+    let mut dropped_total = 0;
+
+    while let Some(line) = raw_lines.recv().await {
+        match line.source {
+            LogSource::Stdout => stdout_lines.insert(&line.text),
+            LogSource::Infolog if stdout_lines.remove(&line.text) => continue,
+            _ => {}
+        }
+
+        // A failing log file mustn't stop the output from being read, or the engine
+        // would block once its pipes are full.
+        if let Some(writer) = file.as_mut() {
+            let entry = format!("[{}] {}\n", line.source.as_str(), line.text);
+            if writer.write_all(entry.as_bytes()).await.is_err() {
+                file = None;
+            }
+        }
+
+        if dropped > 0 {
+            let notice = EngineLogLine {
+                source: line.source,
+                kind: LogKind::Warning,
+                text: format!(
+                    "{} lines of output were dropped, see {}",
+                    dropped,
+                    path.display()
+                ),
+            };
+            if lines.try_send(notice).is_ok() {
+                dropped = 0;
+            }
+        }
+        if dropped > 0 {
+            dropped += 1;
+            dropped_total += 1;
+            continue;
+        }
+
+        if let Err(TrySendError::Full(_)) = lines.try_send(line) {
+            dropped += 1;
+            dropped_total += 1;
+        }
+    }
+
+    if dropped_total > 0 {
+        warn!(
+            dropped = dropped_total,
+            path = %path.display(),
+            "Engine output wasn't read fast enough, lines were only written to the game log"
+        );
+    }
+
+    if let Some(mut writer) = file {
+        let _ = writer.flush().await;
+    }
+}
+
+/// Counts the lines printed to stdout by their hash, so the copies of them in the
+/// infolog can be skipped without keeping the whole output in memory.
+#[derive(Default)]
+struct PrintedLines {
+    counts: HashMap<u64, usize>,
+}
+
+impl PrintedLines {
+    fn insert(&mut self, text: &str) {
+        *self.counts.entry(hash(text)).or_default() += 1;
+    }
+
+    /// Returns whether the line was printed, forgetting one printing of it.
+    fn remove(&mut self, text: &str) -> bool {
+        match self.counts.get_mut(&hash(text)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            ("[t=00:00:01.000000][f=-000001] Loading map", LogKind::Info),
+            (
+                "[t=00:00:01.000000][f=-000001] Error: file not found",
+                LogKind::Error,
+            ),
+            (
+                "[t=00:00:01.000000][f=0000010] Warning: low fps",
+                LogKind::Warning,
+            ),
+            (
+                "[t=00:00:01.000000][f=0000010] Error: [LuaRules::RunCallIn] error = 2",
+                LogKind::LuaError,
+            ),
+            (
+                "[t=00:10:01.000000][f=0018010] Sync error for Player in frame 18010",
+                LogKind::Desync,
+            ),
+            (
+                "[t=00:20:00.000000][f=0036000] [Game::GameEnd]",
+                LogKind::GameOver,
+            ),
+        ];
+
+        for (line, kind) in cases {
+            assert_eq!(classify(line), kind, "{}", line);
+        }
+    }
+
+    #[cfg(unix)]
+    async fn capture_with_infolog(
+        command: &str,
+        log_dir: &Path,
+        write_infolog: impl FnOnce(&Path),
+    ) -> Vec<EngineLogLine> {
+        let infolog_path = log_dir.join("infolog.txt");
+        let mut process = tokio::process::Command::new("sh")
+            .args(["-c", command])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut log =
+            EngineLog::capture(&mut process, &log_dir.join("game.log"), &infolog_path).unwrap();
+
+        process.wait().await.unwrap();
+        write_infolog(&infolog_path);
+        log.capture_infolog().await;
+        let mut lines = Vec::new();
+        while let Some(line) = log.next_line().await {
+            lines.push(line);
+        }
+
+        lines
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_infolog_lines_printed_to_stdout_are_skipped() {
+        let log_dir = tempfile::tempdir().unwrap();
+
+        let lines = capture_with_infolog("echo a; echo b", log_dir.path(), |path| {
+            // Past the coarse clock file times are taken from.
+            std::thread::sleep(Duration::from_millis(50));
+            std::fs::write(path, "a\nb\nb\nc\n").unwrap();
+        })
+        .await;
+
+        assert_eq!(
+            lines,
+            vec![
+                EngineLogLine::new(LogSource::Stdout, "a"),
+                EngineLogLine::new(LogSource::Stdout, "b"),
+                EngineLogLine::new(LogSource::Infolog, "b"),
+                EngineLogLine::new(LogSource::Infolog, "c"),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stale_infolog_is_ignored() {
+        let log_dir = tempfile::tempdir().unwrap();
+        std::fs::write(log_dir.path().join("infolog.txt"), "old game\n").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let lines = capture_with_infolog("echo a", log_dir.path(), |_| {}).await;
+
+        assert_eq!(lines, vec![EngineLogLine::new(LogSource::Stdout, "a")]);
+    }
+}
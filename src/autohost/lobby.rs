@@ -1,29 +1,57 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::sync::Arc;
+use std::time::Duration;
 
 use thiserror::Error;
+use tracing::{info, Span};
 
-use super::spring::LaunchError;
-use super::spring::Spring;
+use super::battle::{Battle, BattlePlayer};
+use super::engine_interface::{EngineEvent, EngineInterface, EngineInterfaceError, AUTOHOST_IP};
+use super::spring::{GameError, GameExit, GameUpdate, LaunchError};
+use super::spring::{Spring, SpringGame};
+use crate::server_coms::protocol::LobbyId;
 use crate::server_coms::server_error::ServerError;
 use crate::utils::config::{Config, ConfigError};
 use crate::utils::environment::{Environment, EnvironmentError};
+use crate::utils::metrics::Metrics;
+
+const START_SCRIPT_FILENAME: &str = "_script.txt";
 
 #[derive(Error, Debug)]
 pub enum LobbyError {
-    #[error("Spring error")]
+    #[error("Failed to start the engine")]
     Spring(#[from] LaunchError),
-    #[error("Environment error")]
+    #[error(transparent)]
     Environment(#[from] EnvironmentError),
-    #[error("Config error")]
+    #[error(transparent)]
     Config(#[from] ConfigError),
-    #[error("Server error")]
+    #[error(transparent)]
     Server(#[from] ServerError),
+    #[error("Failed to write the start script {}", .0.display())]
+    StartScript(PathBuf, #[source] io::Error),
+    #[error("Failed to set up the autohost interface")]
+    EngineInterface(#[from] EngineInterfaceError),
+    #[error("Failed to control the game")]
+    Game(#[from] GameError),
+    #[error("A game is already running")]
+    GameRunning,
+    #[error("No game has been started")]
+    NoGame,
 }
 
 pub struct Lobby<'a> {
     config: &'a dyn Config,
     spring: &'a dyn Spring,
     environment: &'a dyn Environment,
+    battle: Battle,
+    game_dir: PathBuf,
+    game: Option<SpringGame>,
+    span: Span,
+    metrics: Arc<Metrics>,
+    server_lobby_id: Option<LobbyId>,
 }
 
 impl<'a> Lobby<'_> {
@@ -31,17 +59,173 @@ impl<'a> Lobby<'_> {
         config: &'a dyn Config,
         spring: &'a dyn Spring,
         environment: &'a dyn Environment,
+        battle: Battle,
+        metrics: Arc<Metrics>,
     ) -> Lobby<'a> {
         Lobby {
             config,
             spring,
             environment,
+            battle,
+            game_dir: PathBuf::new(),
+            game: None,
+            span: Span::none(),
+            metrics,
+            server_lobby_id: None,
         }
     }
 
-    pub fn start_game(&self) -> Result<(), LobbyError> {
+    pub fn get_battle(&self) -> &Battle {
+        &self.battle
+    }
+
+    pub fn get_battle_mut(&mut self) -> &mut Battle {
+        &mut self.battle
+    }
+
+    /// Adds the player to the battle, keeping the `players` field of the lobby's span
+    /// up to date.
+    pub fn add_player(&mut self, player: BattlePlayer) {
+        self.battle.add_player(player);
+        self.span.record("players", self.battle.players.len());
+    }
+
+    pub fn remove_player(&mut self, name: &str) -> Option<BattlePlayer> {
+        let player = self.battle.remove_player(name);
+        self.span.record("players", self.battle.players.len());
+
+        player
+    }
+
+    pub fn get_game_dir(&self) -> &Path {
+        &self.game_dir
+    }
+
+    /// The id of the server's lobby for the battle, if the server opened one for it.
+    pub fn get_server_lobby_id(&self) -> Option<LobbyId> {
+        self.server_lobby_id
+    }
+
+    pub fn set_server_lobby_id(&mut self, lobby_id: Option<LobbyId>) {
+        self.server_lobby_id = lobby_id;
+    }
+
+    /// The span the lobby's games are logged in.
+    pub fn get_span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn set_span(&mut self, span: Span) {
+        self.span = span;
+    }
+
+    /// Sets the directory games are run in, relative to the configured write dir. By
+    /// default games run in the write dir itself.
+    pub fn set_game_dir(&mut self, game_dir: &Path) {
+        self.game_dir = game_dir.to_path_buf();
+    }
+
+    /// Writes a start script for the current battle state into the game dir and
+    /// launches a game with it.
+    ///
+    /// The start script points the engine at a freshly bound autohost interface, over
+    /// which the running game can be followed and controlled with `get_game`.
+    ///
+    /// # Errors
+    ///
+    /// A `LobbyError::StartScript` is returned if the start script can't be written,
+    /// and a `LobbyError::Spring` if the game fails to launch.
+    ///
+    pub fn start_game(&mut self) -> Result<(), LobbyError> {
+        if self.is_game_running() {
+            return Err(LobbyError::GameRunning);
+        }
+        let _entered = self.span.clone().entered();
+
+        let result = self.launch_game();
+        self.metrics.record_launch(result.is_ok());
+
+        result
+    }
+
+    fn launch_game(&mut self) -> Result<(), LobbyError> {
         let root_dir = self.environment.get_current_dir()?;
+        let game_dir = root_dir
+            .join(self.config.get_write_dir_relative_path())
+            .join(&self.game_dir);
+        let start_script_path = game_dir.join(START_SCRIPT_FILENAME);
+
+        let engine_interface = EngineInterface::bind(AUTOHOST_IP)?;
+        let autohost_addr = engine_interface.get_local_addr();
+
+        let mut start_script = self.battle.to_start_script();
+        start_script.autohost_ip = Some(autohost_addr.ip().to_string());
+        start_script.autohost_port = Some(autohost_addr.port());
+
+        fs::create_dir_all(&game_dir)
+            .and_then(|_| fs::write(&start_script_path, start_script.to_string()))
+            .map_err(|e| LobbyError::StartScript(start_script_path.clone(), e))?;
+        info!(
+            players = self.battle.players.len(),
+            autohost_port = autohost_addr.port(),
+            "Starting game"
+        );
+
+        self.game = Some(self.spring.launch(
+            self.config,
+            &root_dir,
+            &game_dir,
+            &start_script_path,
+            engine_interface,
+        )?);
+
+        Ok(())
+    }
+
+    pub fn get_game(&self) -> Option<&SpringGame> {
+        self.game.as_ref()
+    }
+
+    pub fn is_game_running(&mut self) -> bool {
+        self.game.as_mut().is_some_and(SpringGame::is_running)
+    }
+
+    /// Whether a game was started whose updates haven't all been read with
+    /// `next_game_update` yet.
+    pub fn has_game_updates(&self) -> bool {
+        matches!(&self.game, Some(game) if !game.is_finished())
+    }
+
+    /// Waits for the next event of the running game. Returns `None` if no game was
+    /// started or once the engine has quit.
+    pub async fn next_engine_event(&mut self) -> Option<Result<EngineEvent, LobbyError>> {
+        let event = self.game.as_mut()?.next_event().await?;
+        Some(event.map_err(LobbyError::from))
+    }
+
+    /// Waits for the next event or the exit of the last started game.
+    pub async fn next_game_update(&mut self) -> Result<GameUpdate, LobbyError> {
+        Ok(self.game_mut()?.next_update().await?)
+    }
+
+    /// Waits for the last started game to end, ignoring its remaining events.
+    pub async fn wait_for_game_end(&mut self) -> Result<GameExit, LobbyError> {
+        Ok(self.game_mut()?.wait().await?)
+    }
+
+    /// Stops the last started game, killing it if it hasn't exited within `timeout`.
+    pub async fn stop_game(&mut self, timeout: Duration) -> Result<GameExit, LobbyError> {
+        Ok(self.game_mut()?.stop(timeout).await?)
+    }
+
+    /// Asks the last started game to stop without waiting for it, see
+    /// `SpringGame::request_stop`. Its exit is reported by `next_game_update`.
+    pub async fn request_stop_game(&mut self, timeout: Duration) -> Result<(), LobbyError> {
+        self.game_mut()?.request_stop(timeout).await;
+        Ok(())
+    }
 
-        Ok(self.spring.launch(self.config, &root_dir)?)
+    fn game_mut(&mut self) -> Result<&mut SpringGame, LobbyError> {
+        self.game.as_mut().ok_or(LobbyError::NoGame)
     }
 }
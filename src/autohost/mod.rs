@@ -1,2 +1,9 @@
+pub mod admin;
+pub mod battle;
+pub mod battle_manager;
+pub mod daemon;
+pub mod engine_interface;
+pub mod engine_log;
 pub mod lobby;
 pub mod spring;
+pub mod start_script;
@@ -1,58 +1,610 @@
+use std::collections::HashSet;
 use std::io;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
 use std::result::Result;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use thiserror::Error;
+use tokio::process::{Child, Command};
+use tracing::info;
 
+use super::engine_interface::{EngineEvent, EngineInterface, EngineInterfaceError};
+use super::engine_log::{EngineLog, EngineLogLine};
 use crate::utils::config::Config;
+use crate::utils::metrics::Metrics;
 
 #[derive(Error, Debug)]
 pub enum LaunchError {
-    #[error("Spring failed to launch")]
-    LaunchFail(#[from] io::Error),
+    #[error("Failed to launch {}", .path.display())]
+    LaunchFail {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to open the game log {}", .path.display())]
+    Log {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum GameError {
+    #[error("Failed to wait for the game to exit")]
+    Wait(#[source] io::Error),
+    #[error("Failed to kill the game")]
+    Kill(#[source] io::Error),
+    #[error("Failed to follow the game's events")]
+    Events(#[source] EngineInterfaceError),
 }
 
 const SPRING_WRITEDIR_ENV_VAR: &str = "SPRING_WRITEDIR";
+const SPRING_DATADIR_ENV_VAR: &str = "SPRING_DATADIR";
+const INFOLOG_FILENAME: &str = "infolog.txt";
+const GAME_LOG_DIR: &str = "autohost_logs";
 
 pub trait Spring {
-    fn launch(&self, config: &dyn Config, root_dir: &Path) -> Result<(), LaunchError>;
+    fn launch(
+        &self,
+        config: &dyn Config,
+        root_dir: &Path,
+        game_dir: &Path,
+        start_script_path: &Path,
+        engine_interface: EngineInterface,
+    ) -> Result<SpringGame, LaunchError>;
+}
+
+/// How and when a game's engine process exited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameExit {
+    pub status: ExitStatus,
+    pub duration: Duration,
+}
+
+impl GameExit {
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+
+    /// The exit code of the engine, `None` if it was terminated by a signal.
+    pub fn code(&self) -> Option<i32> {
+        self.status.code()
+    }
+}
+
+/// An event reported by a running game, a line of its output or the exit of its
+/// engine process.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GameUpdate {
+    Event(EngineEvent),
+    Log(EngineLogLine),
+    Exited(GameExit),
+}
+
+/// A handle to a launched game, owning its engine process. Used for following the
+/// game's events and output, sending it commands over the engine's autohost interface
+/// and stopping it.
+pub struct SpringGame {
+    process: Child,
+    engine_interface: EngineInterface,
+    engine_log: EngineLog,
+    started_at: Instant,
+    events_ended: bool,
+    log_ended: bool,
+    exit: Option<GameExit>,
+    exit_reported: bool,
+    stopped: bool,
+    kill_at: Option<Instant>,
+    players: HashSet<u8>,
+    metrics: Arc<Metrics>,
+}
+
+impl SpringGame {
+    /// Follows a launched game, which stops being counted as running in `metrics` once
+    /// its engine exits. Counting it as started is up to whoever launched it.
+    pub fn new(
+        process: Child,
+        engine_interface: EngineInterface,
+        engine_log: EngineLog,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        SpringGame {
+            process,
+            engine_interface,
+            engine_log,
+            started_at: Instant::now(),
+            events_ended: false,
+            log_ended: false,
+            exit: None,
+            exit_reported: false,
+            stopped: false,
+            kill_at: None,
+            players: HashSet::new(),
+            metrics,
+        }
+    }
+
+    /// The path of the file the game's output is written to.
+    pub fn get_log_path(&self) -> &Path {
+        self.engine_log.get_path()
+    }
+
+    /// How the engine exited, once an exit was observed through `next_update`, `wait`
+    /// or `stop`.
+    pub fn get_exit(&self) -> Option<GameExit> {
+        self.exit
+    }
+
+    /// Whether the engine process is still alive. Unlike `get_exit`, this checks the
+    /// process itself, so a game that died without its updates being read yet isn't
+    /// reported as running.
+    pub fn is_running(&mut self) -> bool {
+        self.exit.is_none() && matches!(self.process.try_wait(), Ok(None))
+    }
+
+    /// Whether `next_update` has returned the exit of the game, after which there are
+    /// no more updates to follow.
+    pub fn is_finished(&self) -> bool {
+        self.exit_reported
+    }
+
+    /// The number of players connected to the game, as reported by the engine.
+    pub fn get_player_count(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn get_running_time(&self) -> Duration {
+        match self.exit {
+            Some(exit) => exit.duration,
+            None => self.started_at.elapsed(),
+        }
+    }
+
+    /// Waits for the next event of the game, `None` once the engine has quit or the
+    /// autohost interface failed, which is reported as a `GameError::Events` first.
+    pub async fn next_event(&mut self) -> Option<Result<EngineEvent, GameError>> {
+        let event = match self.engine_interface.next_event().await? {
+            Ok(event) => event,
+            Err(e) => return Some(Err(GameError::Events(e))),
+        };
+        self.observe(&event);
+
+        Some(Ok(event))
+    }
+
+    /// Waits for the next event or line of output of the game, or the exit of its
+    /// engine, whichever comes first. After the process has exited the rest of its
+    /// output, including the infolog, is returned before the `GameExit`, which every
+    /// following call returns again. A game asked to stop with `request_stop` is killed
+    /// from here once its timeout has passed.
+    ///
+    /// # Errors
+    ///
+    /// A `GameError::Wait` is returned if the status of the process can't be retrieved,
+    /// and a `GameError::Events` if the game's events can't be received anymore. The
+    /// game can still be followed afterwards, just without events.
+    ///
+    pub async fn next_update(&mut self) -> Result<GameUpdate, GameError> {
+        while self.exit.is_none() {
+            let kill_at = self.kill_at;
+            tokio::select! {
+                event = self.engine_interface.next_event(), if !self.events_ended => {
+                    match event {
+                        Some(Ok(event)) => {
+                            self.observe(&event);
+                            return Ok(GameUpdate::Event(event));
+                        }
+                        Some(Err(e)) => return Err(GameError::Events(e)),
+                        None => self.events_ended = true,
+                    }
+                }
+                line = self.engine_log.next_line(), if !self.log_ended => match line {
+                    Some(line) => return Ok(GameUpdate::Log(line)),
+                    None => self.log_ended = true,
+                },
+                status = self.process.wait() => {
+                    self.record_exit(status).await?;
+                }
+                _ = tokio::time::sleep_until(kill_at.unwrap_or_else(Instant::now).into()),
+                    if kill_at.is_some() =>
+                {
+                    self.kill_at = None;
+                    self.process.start_kill().map_err(GameError::Kill)?;
+                }
+            }
+        }
+
+        if !self.log_ended {
+            self.engine_log.capture_infolog().await;
+
+            match self.engine_log.next_line().await {
+                Some(line) => return Ok(GameUpdate::Log(line)),
+                None => self.log_ended = true,
+            }
+        }
+
+        let exit = self.wait().await?;
+        self.exit_reported = true;
+
+        Ok(GameUpdate::Exited(exit))
+    }
+
+    /// Waits for the engine process to exit, discarding any events and output that
+    /// haven't been read.
+    pub async fn wait(&mut self) -> Result<GameExit, GameError> {
+        if let Some(exit) = self.exit {
+            return Ok(exit);
+        }
+
+        let status = self.process.wait().await;
+        self.record_exit(status).await
+    }
+
+    /// Asks the engine to end the game and waits up to `timeout` for it to exit, after
+    /// which the process is killed.
+    ///
+    /// # Errors
+    ///
+    /// A `GameError::Kill` is returned if the process can't be killed.
+    ///
+    pub async fn stop(&mut self, timeout: Duration) -> Result<GameExit, GameError> {
+        if let Some(exit) = self.exit {
+            return Ok(exit);
+        }
+
+        // The engine may not be listening yet, or at all, in which case waiting for the
+        // timeout to kill it is all there is left to do.
+        self.stopped = true;
+        let _ = self.kill().await;
+
+        if let Ok(status) = tokio::time::timeout(timeout, self.process.wait()).await {
+            return self.record_exit(status).await;
+        }
+
+        self.process.kill().await.map_err(GameError::Kill)?;
+        self.wait().await
+    }
+
+    /// Asks the engine to end the game, killing it if it hasn't exited within `timeout`.
+    /// Unlike `stop` this doesn't wait for the exit, which is reported by `next_update`,
+    /// and the kill only happens while `next_update` is being awaited.
+    pub async fn request_stop(&mut self, timeout: Duration) {
+        if self.exit.is_some() {
+            return;
+        }
+
+        // As with `stop`, the engine may not be listening, which the kill takes care of.
+        self.stopped = true;
+        let _ = self.kill().await;
+        self.kill_at = Some(Instant::now() + timeout);
+    }
+
+    async fn record_exit(&mut self, status: io::Result<ExitStatus>) -> Result<GameExit, GameError> {
+        let exit = GameExit {
+            status: status.map_err(GameError::Wait)?,
+            duration: self.started_at.elapsed(),
+        };
+        // The exit is counted before capturing the infolog, which can be cancelled along
+        // with `next_update` and is resumed by its next call instead.
+        self.exit = Some(exit);
+        self.metrics
+            .game_ended(exit.duration, !exit.success() && !self.stopped);
+        self.metrics.players_left(self.players.len());
+        self.players.clear();
+
+        self.engine_log.capture_infolog().await;
+
+        Ok(exit)
+    }
+
+    fn observe(&mut self, event: &EngineEvent) {
+        self.metrics.engine_message_received();
+
+        match event {
+            EngineEvent::PlayerJoined { player, .. } if self.players.insert(*player) => {
+                self.metrics.player_joined();
+            }
+            EngineEvent::PlayerLeft { player, .. } if self.players.remove(player) => {
+                self.metrics.players_left(1);
+            }
+            _ => {}
+        }
+    }
+
+    /// Executes a chat command such as `/pause`, the leading `/` is optional.
+    pub async fn send_command(&self, command: &str) -> Result<(), EngineInterfaceError> {
+        self.engine_interface
+            .send(&format!("/{}", command.trim_start_matches('/')))
+            .await
+    }
+
+    /// Broadcasts a chat message to everyone in the game. Leading `/` characters are
+    /// stripped so a message can never be executed as a command.
+    pub async fn say(&self, message: &str) -> Result<(), EngineInterfaceError> {
+        self.engine_interface
+            .send(message.trim_start_matches('/'))
+            .await
+    }
+
+    pub async fn kick(&self, player_name: &str) -> Result<(), EngineInterfaceError> {
+        self.send_command(&format!("kick {}", player_name)).await
+    }
+
+    pub async fn set_paused(&self, paused: bool) -> Result<(), EngineInterfaceError> {
+        self.send_command(&format!("pause {}", u8::from(paused)))
+            .await
+    }
+
+    /// Prevents players from pausing the game.
+    pub async fn set_no_pause(&self, no_pause: bool) -> Result<(), EngineInterfaceError> {
+        self.send_command(&format!("nopause {}", u8::from(no_pause)))
+            .await
+    }
+
+    pub async fn set_min_speed(&self, speed: f32) -> Result<(), EngineInterfaceError> {
+        self.send_command(&format!("setminspeed {}", speed)).await
+    }
+
+    pub async fn set_max_speed(&self, speed: f32) -> Result<(), EngineInterfaceError> {
+        self.send_command(&format!("setmaxspeed {}", speed)).await
+    }
+
+    /// Starts the game without waiting for every player to be ready.
+    pub async fn force_start(&self) -> Result<(), EngineInterfaceError> {
+        self.send_command("forcestart").await
+    }
+
+    /// Ends the game for everyone, the engine quits afterwards.
+    pub async fn kill(&self) -> Result<(), EngineInterfaceError> {
+        self.send_command("kill").await
+    }
+}
+
+impl Drop for SpringGame {
+    fn drop(&mut self) {
+        if self.exit.is_none() {
+            self.metrics.game_abandoned();
+            self.metrics.players_left(self.players.len());
+        }
+    }
 }
 
 /// A Helper struct for launching `spring-headless` processes.
-#[derive(Default)]
-pub struct SpringHeadless {}
+pub struct SpringHeadless {
+    metrics: Arc<Metrics>,
+}
 
 impl SpringHeadless {
-    pub fn new() -> Self {
-        SpringHeadless {}
+    /// Launches games that are counted in `metrics`.
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        SpringHeadless { metrics }
     }
 }
 
 impl Spring for SpringHeadless {
-    /// Launch `spring-headless` as a detached process.
+    /// Launch `spring-headless` as a child process, returning a `SpringGame` that owns
+    /// the process and the `engine_interface` the start script points the engine at.
     ///
     /// The paths in the autohost config file are expected to be relative to the autohost
     /// root directory. The directory the autohost executable lives in.
-    /// Absolute paths to spring, `SPRING_WRITEDIR` and start script are created from the
-    /// root_dir and the config relative paths.
+    /// The absolute path to spring is created from the root_dir and the config relative
+    /// path. The engine runs with the `game_dir` as its `SPRING_WRITEDIR`, so games don't
+    /// share their infolog, demos and caches, and the configured write dir as its
+    /// `SPRING_DATADIR` for the maps and games downloaded there.
+    ///
+    /// The engine's stdout and stderr are captured into a log file per game, under
+    /// `autohost_logs` in the game dir.
     ///
     /// # Errors
     ///
-    /// A `LaunchError` is returned if `spring-headless` cannot be launched as a detached
+    /// A `LaunchError` is returned if `spring-headless` cannot be launched as a child
     /// process. This can happen for various reasons, such as a permissions error, or a
     /// wrong path from the config.
     ///
-    fn launch(&self, config: &dyn Config, root_dir: &Path) -> Result<(), LaunchError> {
+    fn launch(
+        &self,
+        config: &dyn Config,
+        root_dir: &Path,
+        game_dir: &Path,
+        start_script_path: &Path,
+        engine_interface: EngineInterface,
+    ) -> Result<SpringGame, LaunchError> {
         let spring_path = root_dir.join(config.get_spring_relative_path());
-        let write_dir_path = root_dir.join(config.get_write_dir_relative_path());
-        let start_script_path = root_dir.join(config.get_start_script_relative_path());
+        let data_dir_path = root_dir.join(config.get_write_dir_relative_path());
+
+        let mut process = Command::new(spring_path.as_path())
+            .env(SPRING_WRITEDIR_ENV_VAR, game_dir)
+            .env(SPRING_DATADIR_ENV_VAR, data_dir_path.as_path())
+            .arg(start_script_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|source| LaunchError::LaunchFail {
+                path: spring_path.clone(),
+                source,
+            })?;
+
+        let launched_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let log_path = game_dir
+            .join(GAME_LOG_DIR)
+            .join(format!("game_{}.log", launched_at));
+        let engine_log =
+            EngineLog::capture(&mut process, &log_path, &game_dir.join(INFOLOG_FILENAME)).map_err(
+                |source| LaunchError::Log {
+                    path: log_path.clone(),
+                    source,
+                },
+            )?;
+
+        info!(
+            pid = process.id(),
+            spring_path = %spring_path.display(),
+            log_path = %log_path.display(),
+            "Launched spring-headless"
+        );
+        self.metrics.game_started();
+
+        Ok(SpringGame::new(
+            process,
+            engine_interface,
+            engine_log,
+            self.metrics.clone(),
+        ))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::autohost::engine_interface::AUTOHOST_IP;
+    use crate::autohost::engine_log::{LogKind, LogSource};
+
+    fn spawn_game(program: &str, args: &[&str], log_dir: &Path) -> SpringGame {
+        spawn_counted_game(program, args, log_dir, Arc::default())
+    }
+
+    fn spawn_counted_game(
+        program: &str,
+        args: &[&str],
+        log_dir: &Path,
+        metrics: Arc<Metrics>,
+    ) -> SpringGame {
+        metrics.game_started();
+        let mut process = Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let engine_interface = EngineInterface::bind(AUTOHOST_IP).unwrap();
+        let engine_log = EngineLog::capture(
+            &mut process,
+            &log_dir.join("game.log"),
+            &log_dir.join(INFOLOG_FILENAME),
+        )
+        .unwrap();
+
+        SpringGame::new(process, engine_interface, engine_log, metrics)
+    }
+
+    async fn next_exit(game: &mut SpringGame) -> (Vec<EngineLogLine>, GameExit) {
+        let mut lines = Vec::new();
+        loop {
+            match game.next_update().await.unwrap() {
+                GameUpdate::Log(line) => lines.push(line),
+                GameUpdate::Exited(exit) => return (lines, exit),
+                GameUpdate::Event(event) => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_next_update_reports_exit_status() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut game = spawn_game("sh", &["-c", "exit 3"], log_dir.path());
+
+        let (_, exit) = next_exit(&mut game).await;
+
+        assert_eq!(exit.code(), Some(3));
+        assert_eq!(game.get_exit().and_then(|exit| exit.code()), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_next_update_reports_output_before_exit() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut game = spawn_game(
+            "sh",
+            &["-c", "echo 'Error: boom'; echo hello >&2"],
+            log_dir.path(),
+        );
+
+        let (mut lines, _) = next_exit(&mut game).await;
+
+        lines.sort_by_key(|line| line.text.clone());
+        assert_eq!(
+            lines,
+            vec![
+                EngineLogLine::new(LogSource::Stdout, "Error: boom"),
+                EngineLogLine::new(LogSource::Stderr, "hello"),
+            ]
+        );
+        assert_eq!(lines[0].kind, LogKind::Error);
+
+        let log = std::fs::read_to_string(game.get_log_path()).unwrap();
+        assert!(log.contains("[stdout] Error: boom\n"));
+        assert!(log.contains("[stderr] hello\n"));
+    }
+
+    #[tokio::test]
+    async fn test_exit_is_counted_when_next_update_is_cancelled() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(Metrics::new());
+        // The background sleep keeps stdout open, holding up the infolog capture.
+        let mut game = spawn_counted_game(
+            "sh",
+            &["-c", "sleep 1 & exit 0"],
+            log_dir.path(),
+            metrics.clone(),
+        );
+        assert_eq!(metrics.get_games_running(), 1);
+
+        let update = tokio::time::timeout(Duration::from_millis(300), game.next_update()).await;
+
+        assert!(update.is_err());
+        assert!(game.get_exit().is_some());
+        assert_eq!(metrics.get_games_running(), 0);
+        drop(game);
+        assert_eq!(metrics.get_games_running(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dead_game_is_not_running_before_its_exit_is_read() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut game = spawn_game("sleep", &["30"], log_dir.path());
+        assert!(game.is_running());
+
+        game.process.start_kill().unwrap();
+        while game.is_running() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(game.get_exit(), None);
+        assert!(!game.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_requested_stop_kills_game_after_timeout() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let mut game = spawn_counted_game("sleep", &["30"], log_dir.path(), metrics.clone());
+
+        game.request_stop(Duration::from_millis(50)).await;
+        assert!(game.is_running());
+        let (_, exit) = next_exit(&mut game).await;
+
+        assert!(!exit.success());
+        assert!(exit.duration < Duration::from_secs(30));
+        assert!(!metrics
+            .render()
+            .contains("\nautohost_engine_crashes_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_stop_kills_game_after_timeout() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut game = spawn_game("sleep", &["30"], log_dir.path());
 
-        Command::new(spring_path.as_path())
-            .env(SPRING_WRITEDIR_ENV_VAR, write_dir_path.as_path())
-            .arg(start_script_path.as_path())
-            .spawn()?;
+        let exit = game.stop(Duration::from_millis(50)).await.unwrap();
 
-        Ok(())
+        assert!(!exit.success());
+        assert!(exit.duration < Duration::from_secs(30));
     }
 }
@@ -0,0 +1,3 @@
+pub mod script;
+pub mod script_error;
+pub mod tdf;
@@ -0,0 +1,632 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result::Result;
+use std::str::FromStr;
+
+use super::script_error::ScriptError;
+use super::tdf::{self, Node, Section};
+
+const GAME_SECTION: &str = "game";
+const PLAYER_PREFIX: &str = "player";
+const TEAM_PREFIX: &str = "team";
+const ALLY_TEAM_PREFIX: &str = "allyteam";
+const AI_PREFIX: &str = "ai";
+const MOD_OPTIONS_SECTION: &str = "modoptions";
+const MAP_OPTIONS_SECTION: &str = "mapoptions";
+const RESTRICT_SECTION: &str = "restrict";
+const NUM_RESTRICTIONS_KEY: &str = "numrestrictions";
+
+/// How start positions are assigned, the `startpostype` key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartPosType {
+    Fixed = 0,
+    Random = 1,
+    ChooseInGame = 2,
+    ChooseBeforeGame = 3,
+}
+
+impl FromStr for StartPosType {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(StartPosType::Fixed),
+            "1" => Ok(StartPosType::Random),
+            "2" => Ok(StartPosType::ChooseInGame),
+            "3" => Ok(StartPosType::ChooseBeforeGame),
+            _ => Err(ScriptError::InvalidValue(format!(
+                "Unknown start position type: {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// A typed model of the `[game]` section of a spring start script.
+///
+/// Players, teams, ally teams and AIs are numbered by their position in their vector,
+/// eg. `players[0]` is written as `[player0]`. Entries the model doesn't know about are
+/// kept in `extra`, and the rest of the document in `other_sections`, so parsing and
+/// serializing a script doesn't lose anything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StartScript {
+    pub game_type: String,
+    pub map_name: String,
+    pub host_ip: Option<String>,
+    pub host_port: Option<u16>,
+    pub is_host: bool,
+    pub my_player_name: Option<String>,
+    pub start_pos_type: Option<StartPosType>,
+    pub autohost_ip: Option<String>,
+    pub autohost_port: Option<u16>,
+    pub players: Vec<Player>,
+    pub teams: Vec<Team>,
+    pub ally_teams: Vec<AllyTeam>,
+    pub ais: Vec<Ai>,
+    pub mod_options: BTreeMap<String, String>,
+    pub map_options: BTreeMap<String, String>,
+    pub restrictions: Vec<Restriction>,
+    pub extra: Section,
+    /// The top level sections besides `[game]`.
+    pub other_sections: Section,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Player {
+    pub name: String,
+    pub team: Option<usize>,
+    pub spectator: bool,
+    pub is_from_demo: bool,
+    pub country_code: Option<String>,
+    pub rank: Option<u32>,
+    pub skill: Option<String>,
+    pub password: Option<String>,
+    pub extra: Section,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Team {
+    pub team_leader: usize,
+    pub ally_team: usize,
+    pub handicap: i32,
+    pub rgb_color: Option<[f32; 3]>,
+    pub side: Option<String>,
+    pub start_pos: Option<(f32, f32)>,
+    pub extra: Section,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllyTeam {
+    pub num_allies: u32,
+    pub start_rect: Option<StartRect>,
+    pub extra: Section,
+}
+
+/// A start box, as fractions of the map size from the top left corner.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StartRect {
+    pub top: f64,
+    pub left: f64,
+    pub bottom: f64,
+    pub right: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ai {
+    pub name: String,
+    pub short_name: String,
+    pub version: Option<String>,
+    pub team: usize,
+    pub host: usize,
+    pub is_from_demo: bool,
+    pub options: BTreeMap<String, String>,
+    pub extra: Section,
+}
+
+/// Limits how many of a unit can be built, `0` disables it entirely.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Restriction {
+    pub unit: String,
+    pub limit: u32,
+}
+
+impl StartScript {
+    pub fn to_tdf(&self) -> Section {
+        let mut game = self.extra.clone();
+
+        game.set_value("gametype", &self.game_type);
+        game.set_value("mapname", &self.map_name);
+        game.set_value("ishost", bool_value(self.is_host));
+        set_optional(&mut game, "hostip", &self.host_ip);
+        set_optional(&mut game, "hostport", &self.host_port);
+        set_optional(&mut game, "myplayername", &self.my_player_name);
+        set_optional(
+            &mut game,
+            "startpostype",
+            &self
+                .start_pos_type
+                .map(|start_pos_type| start_pos_type as u8),
+        );
+        set_optional(&mut game, "autohostip", &self.autohost_ip);
+        set_optional(&mut game, "autohostport", &self.autohost_port);
+
+        for (id, player) in self.players.iter().enumerate() {
+            game.set_section(&format!("{}{}", PLAYER_PREFIX, id), player.to_tdf());
+        }
+        for (id, team) in self.teams.iter().enumerate() {
+            game.set_section(&format!("{}{}", TEAM_PREFIX, id), team.to_tdf());
+        }
+        for (id, ally_team) in self.ally_teams.iter().enumerate() {
+            game.set_section(&format!("{}{}", ALLY_TEAM_PREFIX, id), ally_team.to_tdf());
+        }
+        for (id, ai) in self.ais.iter().enumerate() {
+            game.set_section(&format!("{}{}", AI_PREFIX, id), ai.to_tdf());
+        }
+
+        set_options(&mut game, MOD_OPTIONS_SECTION, &self.mod_options);
+        set_options(&mut game, MAP_OPTIONS_SECTION, &self.map_options);
+
+        let restrict = game.get_section(RESTRICT_SECTION);
+        if !self.restrictions.is_empty() || restrict.is_some() {
+            let mut restrict = restrict.cloned().unwrap_or_default();
+            for (id, restriction) in self.restrictions.iter().enumerate() {
+                restrict.set_value(&format!("unit{}", id), &restriction.unit);
+                restrict.set_value(&format!("limit{}", id), restriction.limit);
+            }
+            game.set_value(NUM_RESTRICTIONS_KEY, self.restrictions.len());
+            game.set_section(RESTRICT_SECTION, restrict);
+        }
+
+        let mut root = self.other_sections.clone();
+        root.set_section(GAME_SECTION, game);
+        root
+    }
+
+    pub fn from_tdf(root: &Section) -> Result<Self, ScriptError> {
+        let mut other_sections = root.clone();
+        let mut game = match other_sections.remove(GAME_SECTION) {
+            Some(Node::Section(game)) => game,
+            Some(node) => return Err(expected_value(GAME_SECTION, &node)),
+            None => {
+                return Err(ScriptError::MissingValue(
+                    "Missing [game] section".to_string(),
+                ))
+            }
+        };
+
+        let players = take_indexed(&mut game, PLAYER_PREFIX)?
+            .iter()
+            .map(Player::from_tdf)
+            .collect::<Result<_, _>>()?;
+        let teams = take_indexed(&mut game, TEAM_PREFIX)?
+            .iter()
+            .map(Team::from_tdf)
+            .collect::<Result<_, _>>()?;
+        let ally_teams = take_indexed(&mut game, ALLY_TEAM_PREFIX)?
+            .iter()
+            .map(AllyTeam::from_tdf)
+            .collect::<Result<_, _>>()?;
+        let ais = take_indexed(&mut game, AI_PREFIX)?
+            .iter()
+            .map(Ai::from_tdf)
+            .collect::<Result<_, _>>()?;
+
+        let mod_options = take_options(&mut game, MOD_OPTIONS_SECTION);
+        let map_options = take_options(&mut game, MAP_OPTIONS_SECTION);
+        let restrictions = take_restrictions(&mut game)?;
+
+        Ok(StartScript {
+            game_type: take_required(&mut game, "gametype")?,
+            map_name: take_required(&mut game, "mapname")?,
+            is_host: take_bool(&mut game, "ishost")?,
+            host_ip: take_optional(&mut game, "hostip")?,
+            host_port: take_optional(&mut game, "hostport")?,
+            my_player_name: take_optional(&mut game, "myplayername")?,
+            start_pos_type: take_optional(&mut game, "startpostype")?,
+            autohost_ip: take_optional(&mut game, "autohostip")?,
+            autohost_port: take_optional(&mut game, "autohostport")?,
+            players,
+            teams,
+            ally_teams,
+            ais,
+            mod_options,
+            map_options,
+            restrictions,
+            extra: game,
+            other_sections,
+        })
+    }
+}
+
+impl FromStr for StartScript {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StartScript::from_tdf(&tdf::parse(s)?)
+    }
+}
+
+/// Serializes to the exact text format the engine expects as its start script.
+impl fmt::Display for StartScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_tdf())
+    }
+}
+
+impl Player {
+    pub fn to_tdf(&self) -> Section {
+        let mut section = self.extra.clone();
+
+        section.set_value("name", &self.name);
+        section.set_value("spectator", bool_value(self.spectator));
+        section.set_value("isfromdemo", bool_value(self.is_from_demo));
+        set_optional(&mut section, "team", &self.team);
+        set_optional(&mut section, "countrycode", &self.country_code);
+        set_optional(&mut section, "rank", &self.rank);
+        set_optional(&mut section, "skill", &self.skill);
+        set_optional(&mut section, "password", &self.password);
+
+        section
+    }
+
+    pub fn from_tdf(section: &Section) -> Result<Self, ScriptError> {
+        let mut section = section.clone();
+
+        Ok(Player {
+            name: take_required(&mut section, "name")?,
+            team: take_optional(&mut section, "team")?,
+            spectator: take_bool(&mut section, "spectator")?,
+            is_from_demo: take_bool(&mut section, "isfromdemo")?,
+            country_code: take_optional(&mut section, "countrycode")?,
+            rank: take_optional(&mut section, "rank")?,
+            skill: take_optional(&mut section, "skill")?,
+            password: take_optional(&mut section, "password")?,
+            extra: section,
+        })
+    }
+}
+
+impl Team {
+    pub fn to_tdf(&self) -> Section {
+        let mut section = self.extra.clone();
+
+        section.set_value("teamleader", self.team_leader);
+        section.set_value("allyteam", self.ally_team);
+        section.set_value("handicap", self.handicap);
+        set_optional(&mut section, "side", &self.side);
+
+        if let Some([red, green, blue]) = self.rgb_color {
+            section.set_value("rgbcolor", format!("{:?} {:?} {:?}", red, green, blue));
+        }
+        if let Some((x, z)) = self.start_pos {
+            section.set_value("startposx", x);
+            section.set_value("startposz", z);
+        }
+
+        section
+    }
+
+    pub fn from_tdf(section: &Section) -> Result<Self, ScriptError> {
+        let mut section = section.clone();
+
+        let rgb_color = match section.remove("rgbcolor") {
+            Some(Node::Value(value)) => Some(parse_color(&value)?),
+            Some(node) => return Err(expected_value("rgbcolor", &node)),
+            None => None,
+        };
+
+        let start_pos_x = take_optional(&mut section, "startposx")?;
+        let start_pos_z = take_optional(&mut section, "startposz")?;
+
+        Ok(Team {
+            team_leader: take_required(&mut section, "teamleader")?,
+            ally_team: take_required(&mut section, "allyteam")?,
+            handicap: take_optional(&mut section, "handicap")?.unwrap_or_default(),
+            side: take_optional(&mut section, "side")?,
+            rgb_color,
+            start_pos: start_pos_x.zip(start_pos_z),
+            extra: section,
+        })
+    }
+}
+
+impl AllyTeam {
+    pub fn to_tdf(&self) -> Section {
+        let mut section = self.extra.clone();
+
+        section.set_value("numallies", self.num_allies);
+
+        if let Some(rect) = self.start_rect {
+            section.set_value("startrecttop", rect.top);
+            section.set_value("startrectleft", rect.left);
+            section.set_value("startrectbottom", rect.bottom);
+            section.set_value("startrectright", rect.right);
+        }
+
+        section
+    }
+
+    pub fn from_tdf(section: &Section) -> Result<Self, ScriptError> {
+        let mut section = section.clone();
+
+        let top = take_optional(&mut section, "startrecttop")?;
+        let left = take_optional(&mut section, "startrectleft")?;
+        let bottom = take_optional(&mut section, "startrectbottom")?;
+        let right = take_optional(&mut section, "startrectright")?;
+
+        let start_rect = match (top, left, bottom, right) {
+            (Some(top), Some(left), Some(bottom), Some(right)) => Some(StartRect {
+                top,
+                left,
+                bottom,
+                right,
+            }),
+            (None, None, None, None) => None,
+            _ => {
+                return Err(ScriptError::MissingValue(
+                    "Incomplete start rect for ally team".to_string(),
+                ))
+            }
+        };
+
+        Ok(AllyTeam {
+            num_allies: take_optional(&mut section, "numallies")?.unwrap_or_default(),
+            start_rect,
+            extra: section,
+        })
+    }
+}
+
+impl Ai {
+    pub fn to_tdf(&self) -> Section {
+        let mut section = self.extra.clone();
+
+        section.set_value("name", &self.name);
+        section.set_value("shortname", &self.short_name);
+        section.set_value("team", self.team);
+        section.set_value("host", self.host);
+        section.set_value("isfromdemo", bool_value(self.is_from_demo));
+        set_optional(&mut section, "version", &self.version);
+        set_options(&mut section, "options", &self.options);
+
+        section
+    }
+
+    pub fn from_tdf(section: &Section) -> Result<Self, ScriptError> {
+        let mut section = section.clone();
+
+        Ok(Ai {
+            name: take_required(&mut section, "name")?,
+            short_name: take_required(&mut section, "shortname")?,
+            version: take_optional(&mut section, "version")?,
+            team: take_required(&mut section, "team")?,
+            host: take_required(&mut section, "host")?,
+            is_from_demo: take_bool(&mut section, "isfromdemo")?,
+            options: take_options(&mut section, "options"),
+            extra: section,
+        })
+    }
+}
+
+fn bool_value(value: bool) -> u8 {
+    u8::from(value)
+}
+
+fn set_optional<T: ToString>(section: &mut Section, key: &str, value: &Option<T>) {
+    if let Some(value) = value {
+        section.set_value(key, value.to_string());
+    }
+}
+
+/// Writes the options into the `[key]` section, keeping what it already holds. An empty
+/// section is only written if it already exists.
+fn set_options(section: &mut Section, key: &str, options: &BTreeMap<String, String>) {
+    let existing = section.get_section(key);
+    if options.is_empty() && existing.is_none() {
+        return;
+    }
+
+    let mut options_section = existing.cloned().unwrap_or_default();
+    for (option, value) in options {
+        options_section.set_value(option, value);
+    }
+    section.set_section(key, options_section);
+}
+
+fn expected_value(key: &str, node: &Node) -> ScriptError {
+    ScriptError::InvalidValue(format!("Expected a value for {}, found {:?}", key, node))
+}
+
+fn take_optional<T: FromStr>(section: &mut Section, key: &str) -> Result<Option<T>, ScriptError> {
+    match section.remove(key) {
+        Some(Node::Value(value)) => value.parse().map(Some).map_err(|_| {
+            ScriptError::InvalidValue(format!("Invalid value for {}: {:?}", key, value))
+        }),
+        Some(node) => Err(expected_value(key, &node)),
+        None => Ok(None),
+    }
+}
+
+fn take_required<T: FromStr>(section: &mut Section, key: &str) -> Result<T, ScriptError> {
+    take_optional(section, key)?
+        .ok_or_else(|| ScriptError::MissingValue(format!("Missing value for {}", key)))
+}
+
+fn take_bool(section: &mut Section, key: &str) -> Result<bool, ScriptError> {
+    Ok(take_optional::<i64>(section, key)?.unwrap_or_default() != 0)
+}
+
+/// Takes the values of the `[key]` section. The section itself is left in place with
+/// anything else it holds, such as nested sections, for `set_options` to write back.
+fn take_options(section: &mut Section, key: &str) -> BTreeMap<String, String> {
+    let Some(mut options_section) = section.get_section(key).cloned() else {
+        return BTreeMap::new();
+    };
+
+    let options: BTreeMap<_, _> = options_section
+        .entries()
+        .filter_map(|(key, node)| match node {
+            Node::Value(value) => Some((key.to_string(), value.clone())),
+            Node::Section(_) => None,
+        })
+        .collect();
+    for option in options.keys() {
+        options_section.remove(option);
+    }
+    section.set_section(key, options_section);
+
+    options
+}
+
+/// Removes the `[<prefix>N]` sections, which must be numbered from 0 without gaps.
+fn take_indexed(section: &mut Section, prefix: &str) -> Result<Vec<Section>, ScriptError> {
+    let mut indexed = BTreeMap::new();
+
+    for (key, node) in section.entries() {
+        let index = match key.strip_prefix(prefix) {
+            Some(index) if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) => index,
+            _ => continue,
+        };
+
+        match (index.parse::<usize>(), node) {
+            (Ok(index), Node::Section(child)) => {
+                indexed.insert(index, (key.to_string(), child.clone()));
+            }
+            _ => {
+                return Err(ScriptError::InvalidValue(format!(
+                    "Invalid entry [{}]",
+                    key
+                )))
+            }
+        }
+    }
+
+    let mut sections = Vec::with_capacity(indexed.len());
+    for (expected, (index, (key, child))) in indexed.into_iter().enumerate() {
+        if index != expected {
+            return Err(ScriptError::InvalidValue(format!(
+                "[{}] is out of sequence, expected [{}{}]",
+                key, prefix, expected
+            )));
+        }
+        section.remove(&key);
+        sections.push(child);
+    }
+
+    Ok(sections)
+}
+
+/// Takes the restrictions, leaving the `[restrict]` section in place with anything else
+/// it holds.
+fn take_restrictions(game: &mut Section) -> Result<Vec<Restriction>, ScriptError> {
+    let mut restrict = match game.get(RESTRICT_SECTION) {
+        Some(Node::Section(restrict)) => restrict.clone(),
+        Some(node) => return Err(expected_value(RESTRICT_SECTION, node)),
+        None => return Ok(Vec::new()),
+    };
+    game.remove(NUM_RESTRICTIONS_KEY);
+
+    let mut restrictions = Vec::new();
+    while let Some(unit) = take_optional(&mut restrict, &format!("unit{}", restrictions.len()))? {
+        let limit = take_required(&mut restrict, &format!("limit{}", restrictions.len()))?;
+        restrictions.push(Restriction { unit, limit });
+    }
+    game.set_section(RESTRICT_SECTION, restrict);
+
+    Ok(restrictions)
+}
+
+fn parse_color(value: &str) -> Result<[f32; 3], ScriptError> {
+    let components = value
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<f32>, _>>()
+        .ok()
+        .filter(|components| components.len() == 3)
+        .ok_or_else(|| ScriptError::InvalidValue(format!("Invalid rgbcolor: {:?}", value)))?;
+
+    Ok([components[0], components[1], components[2]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START_SCRIPT: &str = include_str!("../../../startscript.txt");
+
+    #[test]
+    fn test_start_script_round_trips() {
+        let script: StartScript = START_SCRIPT.parse().unwrap();
+
+        assert_eq!(script.to_string(), START_SCRIPT);
+    }
+
+    #[test]
+    fn test_start_script_is_parsed_into_typed_fields() {
+        let script: StartScript = START_SCRIPT.parse().unwrap();
+
+        assert_eq!(script.map_name, "DSDR 4.1");
+        assert_eq!(script.host_port, Some(43475));
+        assert_eq!(script.start_pos_type, Some(StartPosType::ChooseInGame));
+        assert_eq!(script.players.len(), 1);
+        assert_eq!(script.players[0].name, "eL_bArTo");
+        assert_eq!(script.players[0].team, Some(0));
+        assert_eq!(script.teams[0].rgb_color, Some([0.8, 0.0, 0.0]));
+        assert_eq!(
+            script.ally_teams[0].start_rect.map(|rect| rect.left),
+            Some(0.010653408860002767)
+        );
+        assert!(script.extra.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_entries_are_preserved() {
+        let input = "[game]\n{\n\t[ai0]\n\t{\n\t\thost = 0;\n\t\tisfromdemo = 0;\n\t\tname = Bot;\n\t\t[options]\n\t\t{\n\t\t\tdifficulty = hard;\n\t\t}\n\n\t\tshortname = BARb;\n\t\tteam = 1;\n\t}\n\n\tgametype = BAR;\n\tishost = 1;\n\tmapname = Map;\n\tnumrestrictions = 1;\n\t[restrict]\n\t{\n\t\tlimit0 = 0;\n\t\tunit0 = armwar;\n\t}\n\n\tunknownkey = kept;\n}\n\n\n";
+
+        let script: StartScript = input.parse().unwrap();
+
+        assert_eq!(script.ais[0].options["difficulty"], "hard");
+        assert_eq!(
+            script.restrictions,
+            vec![Restriction {
+                unit: "armwar".to_string(),
+                limit: 0
+            }]
+        );
+        assert_eq!(script.extra.get_value("unknownkey"), Some("kept"));
+        assert_eq!(script.to_string(), input);
+    }
+
+    #[test]
+    fn test_comments_and_unknown_sections_are_preserved() {
+        let input = "// Written by hand\n[GAME]\n{\n\tGameType = BAR; // the game\n\tishost = 1;\n\n\t// The map\n\tmapname = Map;\n\t[modoptions]\n\t{\n\t\tdeathmode = com;\n\t\t[tweaks]\n\t\t{\n\t\t\tspeed = 2;\n\t\t}\n\t}\n}\n[replay]\n{\n\tsaved = 1;\n}\n";
+
+        let mut script: StartScript = input.parse().unwrap();
+
+        assert_eq!(script.game_type, "BAR");
+        assert_eq!(script.mod_options["deathmode"], "com");
+        assert_eq!(script.to_string(), input);
+
+        script
+            .mod_options
+            .insert("deathmode".to_string(), "own".to_string());
+        let output = script.to_string();
+        assert!(output.contains("\t\tdeathmode = own;\n\t\t[tweaks]"));
+        assert!(output.contains("\t\t\tspeed = 2;"));
+        assert!(output.contains("[replay]"));
+    }
+
+    #[test]
+    fn test_parse_fails_on_unclosed_section() {
+        let result = "[game]\n{\n\tmapname = Map;\n".parse::<StartScript>();
+
+        assert!(matches!(result, Err(ScriptError::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_fails_on_gap_in_player_numbering() {
+        let input = "[game]\n{\n\tgametype = BAR;\n\tmapname = Map;\n\t[player1]\n\t{\n\t\tname = a;\n\t}\n}\n";
+
+        assert!(input.parse::<StartScript>().is_err());
+    }
+}
@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("Start script syntax error: {0}")]
+    Syntax(String),
+    #[error("Start script is missing a value: {0}")]
+    MissingValue(String),
+    #[error("Start script contains an invalid value: {0}")]
+    InvalidValue(String),
+}
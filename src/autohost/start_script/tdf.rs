@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::result::Result;
+use std::str::Chars;
+
+use super::script_error::ScriptError;
+
+/// An entry of a [`Section`], either a plain `key = value;` pair or a nested
+/// `[name] { ... }` section.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Value(String),
+    Section(Section),
+}
+
+/// A bracketed section of the text format spring uses for start scripts.
+///
+/// Keys are looked up case-insensitively, as the engine reads them, but written as
+/// they were parsed. Parsed entries keep their order along with the blank lines and
+/// comments around them, so a parsed script serializes back to the same text. New
+/// entries are inserted in key order, which is also the order lobby clients write them in.
+#[derive(Clone, Debug, Default)]
+pub struct Section {
+    entries: Vec<Entry>,
+    /// The blank lines and comments before the closing brace, `None` if not parsed.
+    trailing: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    /// The lowercase key entries are looked up by.
+    key: String,
+    /// The key as written.
+    name: String,
+    /// `None` once removed, so the entry keeps its place and comments if set again.
+    node: Option<Node>,
+    /// The blank lines and comments before the entry, `None` if not parsed.
+    leading: Option<Vec<String>>,
+    /// A comment following the entry on the same line.
+    comment: Option<String>,
+}
+
+impl Section {
+    pub fn new() -> Self {
+        Section::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Node> {
+        self.entry(key)?.node.as_ref()
+    }
+
+    pub fn get_value(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(Node::Value(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_section(&self, key: &str) -> Option<&Section> {
+        match self.get(key) {
+            Some(Node::Section(section)) => Some(section),
+            _ => None,
+        }
+    }
+
+    pub fn set_value(&mut self, key: &str, value: impl ToString) {
+        self.set(key, Node::Value(value.to_string()));
+    }
+
+    pub fn set_section(&mut self, key: &str, section: Section) {
+        self.set(key, Node::Section(section));
+    }
+
+    /// Removes the entry, leaving its place and comments behind in case it's set again.
+    pub fn remove(&mut self, key: &str) -> Option<Node> {
+        let key = key.to_lowercase();
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.key == key)?
+            .node
+            .take()
+    }
+
+    /// Moves every entry of `other` into this section, replacing entries with the same key.
+    pub fn merge(&mut self, other: Section) {
+        for entry in other.entries {
+            if let Some(node) = entry.node {
+                self.set(&entry.key, node);
+            }
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Node)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| Some((entry.key.as_str(), entry.node.as_ref()?)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().next().is_none()
+    }
+
+    fn entry(&self, key: &str) -> Option<&Entry> {
+        let key = key.to_lowercase();
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+
+    fn set(&mut self, key: &str, node: Node) {
+        let key = key.to_lowercase();
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.key == key) {
+            entry.node = Some(node);
+            return;
+        }
+
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| entry.key > key)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(
+            position,
+            Entry {
+                name: key.clone(),
+                key,
+                node: Some(node),
+                leading: None,
+                comment: None,
+            },
+        );
+    }
+
+    /// Adds a parsed entry, replacing the node of an earlier entry with the same key.
+    fn push_parsed(
+        &mut self,
+        name: String,
+        node: Node,
+        leading: Vec<String>,
+        comment: Option<String>,
+    ) {
+        let key = name.to_lowercase();
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.key == key) {
+            entry.node = Some(node);
+            return;
+        }
+
+        self.entries.push(Entry {
+            key,
+            name,
+            node: Some(node),
+            leading: Some(leading),
+            comment,
+        });
+    }
+
+    /// Writes the entries, separating sections from what follows them by a blank line
+    /// unless the layout was parsed.
+    fn write_entries(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "\t".repeat(depth);
+        let mut after_section = false;
+
+        for entry in &self.entries {
+            let Some(node) = &entry.node else {
+                // Only the comments of a removed entry are kept, its blank lines go with it.
+                let comments = entry
+                    .leading
+                    .iter()
+                    .flatten()
+                    .filter(|line| !line.is_empty());
+                for comment in comments {
+                    writeln!(f, "{}{}", indent, comment)?;
+                }
+                continue;
+            };
+
+            match &entry.leading {
+                Some(lines) => write_trivia(f, &indent, lines)?,
+                None if after_section => writeln!(f)?,
+                None => {}
+            }
+
+            let comment = entry
+                .comment
+                .as_ref()
+                .map(|comment| format!(" {}", comment))
+                .unwrap_or_default();
+            match node {
+                Node::Value(value) => {
+                    writeln!(f, "{}{} = {};{}", indent, entry.name, value, comment)?
+                }
+                Node::Section(section) => {
+                    writeln!(f, "{}[{}]", indent, entry.name)?;
+                    writeln!(f, "{}{{", indent)?;
+                    section.write_entries(f, depth + 1)?;
+                    writeln!(f, "{}}}{}", indent, comment)?;
+                }
+            }
+            after_section = matches!(node, Node::Section(_));
+        }
+
+        match &self.trailing {
+            Some(lines) => write_trivia(f, &indent, lines),
+            None if after_section => writeln!(f),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_trivia(f: &mut fmt::Formatter<'_>, indent: &str, lines: &[String]) -> fmt::Result {
+    for line in lines {
+        match line.is_empty() {
+            true => writeln!(f)?,
+            false => writeln!(f, "{}{}", indent, line)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Sections are equal if their entries are, regardless of order and layout.
+impl PartialEq for Section {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries().collect::<BTreeMap<_, _>>() == other.entries().collect::<BTreeMap<_, _>>()
+    }
+}
+
+/// Writes the section as a whole document, i.e. its entries are the top level
+/// sections such as `[game]`.
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_entries(f, 0)?;
+        match self.trailing {
+            Some(_) => Ok(()),
+            None => writeln!(f),
+        }
+    }
+}
+
+/// Parses a start script document into a root [`Section`] holding its top level sections.
+///
+/// # Errors
+///
+/// A `ScriptError::Syntax` is returned, with the offending line number, if the text is
+/// not a well formed sequence of sections and `key = value;` pairs.
+///
+pub fn parse(input: &str) -> Result<Section, ScriptError> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+
+    parser.parse_entries(false)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn parse_entries(&mut self, nested: bool) -> Result<Section, ScriptError> {
+        let mut section = Section::new();
+
+        loop {
+            let mut leading = self.read_trivia();
+
+            match self.chars.peek() {
+                None if nested => return Err(self.error("unexpected end of input, missing '}'")),
+                None => {
+                    section.trailing = Some(leading);
+                    return Ok(section);
+                }
+                Some('}') if nested => {
+                    self.next();
+                    section.trailing = Some(leading);
+                    return Ok(section);
+                }
+                Some('}') => return Err(self.error("unexpected '}'")),
+                Some('[') => {
+                    self.next();
+                    let name = self.read_until(']')?;
+                    leading.extend(self.read_comments());
+
+                    if self.next() != Some('{') {
+                        return Err(self.error(&format!("expected '{{' after [{}]", name)));
+                    }
+                    leading.extend(self.finish_line());
+
+                    let child = self.parse_entries(true)?;
+                    let comment = self.finish_line();
+                    section.push_parsed(name, Node::Section(child), leading, comment);
+                }
+                Some(_) => {
+                    let key = self.read_until('=')?;
+                    let value = self.read_until(';')?;
+                    let comment = self.finish_line();
+                    section.push_parsed(key, Node::Value(value), leading, comment);
+                }
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    /// Reads up to the `end` character, which is consumed, and returns the trimmed text.
+    /// Entries never span multiple lines, so a newline or a brace is a syntax error.
+    fn read_until(&mut self, end: char) -> Result<String, ScriptError> {
+        let mut text = String::new();
+
+        loop {
+            match self.next() {
+                Some(c) if c == end => return Ok(text.trim().to_string()),
+                Some(c @ ('\n' | '{' | '}')) => {
+                    return Err(self.error(&format!("unexpected {:?}, expected '{}'", c, end)))
+                }
+                Some(c) => text.push(c),
+                None => {
+                    return Err(self.error(&format!("unexpected end of input, expected '{}'", end)))
+                }
+            }
+        }
+    }
+
+    /// Reads the rest of the line an entry ended on, returning its comment if any. Stops
+    /// early if another entry follows on the same line.
+    fn finish_line(&mut self) -> Option<String> {
+        self.skip_spaces();
+
+        match self.chars.peek().copied() {
+            Some('\n') => {
+                self.next();
+                None
+            }
+            Some('/') if self.is_at_comment() => Some(self.read_comment()),
+            _ => None,
+        }
+    }
+
+    /// Reads the blank and comment lines up to the next entry or closing brace, blank
+    /// lines as empty strings.
+    fn read_trivia(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        loop {
+            self.skip_spaces();
+
+            match self.chars.peek().copied() {
+                Some('\n') => {
+                    self.next();
+                    lines.push(String::new());
+                }
+                Some('/') if self.is_at_comment() => lines.push(self.read_comment()),
+                _ => return lines,
+            }
+        }
+    }
+
+    /// Skips whitespace across lines, returning the comments skipped over.
+    fn read_comments(&mut self) -> Vec<String> {
+        let mut comments = Vec::new();
+
+        loop {
+            match self.chars.peek().copied() {
+                Some(c) if c.is_whitespace() => {
+                    self.next();
+                }
+                Some('/') if self.is_at_comment() => comments.push(self.read_comment()),
+                _ => return comments,
+            }
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.chars.peek(), Some(c) if c.is_whitespace() && *c != '\n') {
+            self.next();
+        }
+    }
+
+    fn is_at_comment(&self) -> bool {
+        let mut lookahead = self.chars.clone();
+        lookahead.next() == Some('/') && lookahead.next() == Some('/')
+    }
+
+    /// Reads a `//` comment up to the end of the line, which is consumed.
+    fn read_comment(&mut self) -> String {
+        let mut comment = String::new();
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                break;
+            }
+            comment.push(c);
+        }
+
+        comment.trim_end().to_string()
+    }
+
+    fn error(&self, message: &str) -> ScriptError {
+        ScriptError::Syntax(format!("line {}: {}", self.line, message))
+    }
+}
@@ -0,0 +1,222 @@
+//! Stands in for `spring-headless` in tests of whole games, without the engine.
+//!
+//! Like the engine, it is launched with the path of a start script and sends the events
+//! of a game to the `AutohostIP` and `AutohostPort` from that script: every player joins
+//! and readies up, the game starts, the first player chats, every team reports its
+//! statistics and the game ends, after which the process exits.
+//!
+//! The game can be tweaked with a `fake_spring_scenario.json` in `SPRING_DATADIR`, see
+//! `Scenario` for what it may contain.
+
+use std::env;
+use std::fs;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use bar_autohost::autohost::engine_interface::{
+    ChatDestination, EngineEvent, ReadyState, TeamStatistics,
+};
+use bar_autohost::autohost::start_script::script::StartScript;
+
+const SPRING_WRITEDIR_ENV_VAR: &str = "SPRING_WRITEDIR";
+const SPRING_DATADIR_ENV_VAR: &str = "SPRING_DATADIR";
+const SCENARIO_FILENAME: &str = "fake_spring_scenario.json";
+const INFOLOG_FILENAME: &str = "infolog.txt";
+const GAME_ID: &str = "00112233445566778899aabbccddeeff";
+const DEMO_NAME: &str = "fake.sdfz";
+const FRAMES_PER_SECOND: i32 = 30;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Scenario {
+    /// The code the process exits with.
+    exit_code: i32,
+    /// Sent by the first player to everyone once the game has started.
+    chat: Vec<String>,
+    winning_ally_teams: Vec<u8>,
+    /// Keeps the game running until the autohost sends `/kill`.
+    wait_for_kill: bool,
+    /// Keeps waiting after `/kill`, like a hung engine, until the process is killed.
+    ignore_kill: bool,
+    /// The pause between two events, so they arrive in order.
+    event_delay_ms: u64,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            exit_code: 0,
+            chat: vec!["gl hf".to_string()],
+            winning_ally_teams: vec![0],
+            wait_for_kill: false,
+            ignore_kill: false,
+            event_delay_ms: 5,
+        }
+    }
+}
+
+struct Game {
+    socket: Option<UdpSocket>,
+    event_delay: Duration,
+    log: Vec<String>,
+}
+
+impl Game {
+    fn log(&mut self, line: &str) {
+        println!("{}", line);
+        self.log.push(line.to_string());
+    }
+
+    fn send(&mut self, event: EngineEvent) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+
+        if let Err(e) = socket.send(&event.encode()) {
+            eprintln!("Warning: Failed to send {:?} to the autohost: {}", event, e);
+        }
+        thread::sleep(self.event_delay);
+    }
+
+    /// Waits for the autohost to send `/kill`, answering everything else as the engine
+    /// would with a server message. An ignored `/kill` is answered like the rest.
+    fn wait_for_kill(&mut self, ignore_kill: bool) {
+        let Some(socket) = self
+            .socket
+            .as_ref()
+            .and_then(|socket| socket.try_clone().ok())
+        else {
+            return;
+        };
+        let mut buffer = [0; 1024];
+
+        while let Ok(size) = socket.recv(&mut buffer) {
+            let text = String::from_utf8_lossy(&buffer[..size]).into_owned();
+            self.log(&format!("Received from autohost: {}", text));
+
+            if text == "/kill" && !ignore_kill {
+                return;
+            }
+            self.send(EngineEvent::ServerMessage(text));
+        }
+    }
+}
+
+fn main() {
+    let Some(start_script_path) = env::args().nth(1) else {
+        eprintln!("Error: Usage: fake-spring-headless <start script>");
+        process::exit(1);
+    };
+
+    let script: StartScript = match fs::read_to_string(&start_script_path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| text.parse().map_err(|e| format!("{:?}", e)))
+    {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Error: Failed to read the start script: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let scenario: Scenario = env::var_os(SPRING_DATADIR_ENV_VAR)
+        .map(|data_dir| Path::new(&data_dir).join(SCENARIO_FILENAME))
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|text| match serde_json::from_str(&text) {
+            Ok(scenario) => scenario,
+            Err(e) => {
+                eprintln!("Error: Invalid {}: {}", SCENARIO_FILENAME, e);
+                process::exit(1);
+            }
+        })
+        .unwrap_or_default();
+
+    let mut game = Game {
+        socket: connect(&script),
+        event_delay: Duration::from_millis(scenario.event_delay_ms),
+        log: Vec::new(),
+    };
+
+    play(&mut game, &script, &scenario);
+
+    if let Some(write_dir) = env::var_os(SPRING_WRITEDIR_ENV_VAR) {
+        let infolog = game.log.join("\n") + "\n";
+        let _ = fs::write(Path::new(&write_dir).join(INFOLOG_FILENAME), infolog);
+    }
+
+    process::exit(scenario.exit_code);
+}
+
+fn connect(script: &StartScript) -> Option<UdpSocket> {
+    let (Some(ip), Some(port)) = (&script.autohost_ip, script.autohost_port) else {
+        eprintln!("Warning: No autohost interface in the start script");
+        return None;
+    };
+
+    let socket = UdpSocket::bind((ip.as_str(), 0)).ok()?;
+    socket.connect((ip.as_str(), port)).ok()?;
+
+    Some(socket)
+}
+
+fn play(game: &mut Game, script: &StartScript, scenario: &Scenario) {
+    game.log(&format!(
+        "Fake spring-headless hosting {} on {}",
+        script.game_type, script.map_name
+    ));
+    game.send(EngineEvent::ServerStarted);
+
+    for (player, details) in script.players.iter().enumerate() {
+        game.log(&format!("Player {} joined", details.name));
+        game.send(EngineEvent::PlayerJoined {
+            player: player as u8,
+            name: details.name.clone(),
+        });
+        if !details.spectator {
+            game.send(EngineEvent::PlayerReady {
+                player: player as u8,
+                state: ReadyState::Ready,
+            });
+        }
+    }
+
+    game.send(EngineEvent::ServerStartPlaying {
+        game_id: GAME_ID.to_string(),
+        demo_name: DEMO_NAME.to_string(),
+    });
+
+    for message in &scenario.chat {
+        game.send(EngineEvent::PlayerChat {
+            player: 0,
+            destination: ChatDestination::Everyone,
+            message: message.clone(),
+        });
+    }
+
+    if scenario.wait_for_kill {
+        game.wait_for_kill(scenario.ignore_kill);
+    }
+
+    for team in 0..script.teams.len() {
+        game.send(EngineEvent::GameTeamStat {
+            team: team as u8,
+            statistics: TeamStatistics {
+                frame: 60 * FRAMES_PER_SECOND,
+                units_produced: 10,
+                ..Default::default()
+            },
+        });
+    }
+
+    game.log("Game over");
+    game.send(EngineEvent::ServerGameOver {
+        player: 0,
+        winning_ally_teams: scenario.winning_ally_teams.clone(),
+    });
+    game.send(EngineEvent::ServerQuit);
+}
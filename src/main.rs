@@ -1,28 +1,138 @@
-use bar_autohost::server_coms::server::{Server, TeiServer};
-use bar_autohost::utils::config::AutohostConfig;
-use bar_autohost::utils::environment::AutohostEnvironment;
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bar_autohost::server_coms::server::TeiServer;
+use bar_autohost::server_coms::token_store::FileTokenStore;
+use bar_autohost::utils::args::Args;
+use bar_autohost::utils::config::{self, AutohostConfig, Config};
+use bar_autohost::utils::config_watcher::FileConfigWatcher;
+use bar_autohost::utils::environment::{AutohostEnvironment, Environment};
+use bar_autohost::utils::error_report::{ErrorChain, ErrorReport, FailureKind};
+use bar_autohost::utils::health::Health;
+use bar_autohost::utils::http_api::HttpApi;
 use bar_autohost::utils::http_client::TeiHttpClient;
+use bar_autohost::utils::logging;
+use bar_autohost::utils::metrics::Metrics;
 use bar_autohost::utils::websocket_client::TachyonClient;
 
-use bar_autohost::autohost::lobby::{Lobby, LobbyError};
+use bar_autohost::autohost::admin;
+use bar_autohost::autohost::battle_manager::BattleManager;
+use bar_autohost::autohost::daemon::Daemon;
 use bar_autohost::autohost::spring::SpringHeadless;
 
+const GAME_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const USAGE: &str = "Usage: bar-autohost [--config <path>] [--profile <name>] [--check-config]";
+
+/// Runs the autohost, and reports what stopped it with the whole chain of causes. The
+/// exit code tells the kind of failure apart, see `FailureKind`.
 #[tokio::main]
-async fn main() -> Result<(), LobbyError> {
+async fn main() -> ExitCode {
+    let Err(e) = run().await else {
+        return ExitCode::SUCCESS;
+    };
+
+    tracing::error!(error = %ErrorChain(e.as_ref()), "Autohost stopped");
+    let report = ErrorReport::new(e.as_ref());
+    eprintln!("{}", report);
+    if report.get_kind() == FailureKind::Usage {
+        eprintln!("{}", USAGE);
+    }
+
+    ExitCode::from(report.get_kind().get_exit_code())
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(env::args().skip(1))?;
+
     let environment = AutohostEnvironment::new();
-    let config = AutohostConfig::build()?;
-    let spring = SpringHeadless::new();
-    let http_client = TeiHttpClient::new();
-    let mut socket_client = TachyonClient::new();
+    let root_dir = environment.get_current_dir()?;
+    let config = AutohostConfig::build(args.get_config_path(), args.get_profile())?;
+    config::validate(&config, &root_dir)?;
 
-    let lobby = Lobby::new(&config, &spring, &environment);
+    if args.is_check_config() {
+        println!("The config is valid");
+        return Ok(());
+    }
 
-    let mut server = TeiServer::new(&config, &http_client, &mut socket_client);
-    server.start_session().await?;
+    logging::init(config.get_logging(), &root_dir)?;
+    tracing::info!(
+        version = env!("CARGO_PKG_VERSION"),
+        domain = config.get_server_domain(),
+        "Starting autohost"
+    );
 
-    lobby.start_game()?;
+    let metrics = Arc::new(Metrics::new());
+    let health = Arc::new(Health::new(
+        &root_dir.join(config.get_spring_relative_path()),
+    ));
+    let mut admin_requests = None;
+    let _http_api = match config.get_http_api() {
+        Some(http_api_config) => {
+            let mut http_api = HttpApi::new(metrics.clone(), health.clone());
+            if let Some(token) = &http_api_config.admin_token {
+                let (client, requests) = admin::channel();
+                http_api.set_admin(token, client);
+                admin_requests = Some(requests);
+            }
+            Some(http_api.spawn(http_api_config.address)?)
+        }
+        None => None,
+    };
 
-    server.end_session().await?;
+    let spring = SpringHeadless::new(metrics.clone());
+    let ca_bundle_path = config.get_server_ca_bundle_path().map(Path::new);
+    let accept_invalid_certs = config.get_server_accept_invalid_certs();
+    let http_client = TeiHttpClient::with_tls(ca_bundle_path, accept_invalid_certs)?;
+    let mut socket_client = TachyonClient::with_tls(ca_bundle_path, accept_invalid_certs)?;
+    let token_store = FileTokenStore::new(&root_dir.join(config.get_token_store_relative_path()));
+
+    let battle_manager = BattleManager::new(&config, &spring, &environment, metrics.clone());
+    let mut server = TeiServer::new(&config, &http_client, &mut socket_client, &token_store);
+    server.set_metrics(metrics);
+    server.set_health(health.clone());
+    let mut config_watcher = FileConfigWatcher::new(
+        args.get_config_path(),
+        args.get_profile(),
+        &root_dir,
+        config.clone(),
+    );
+
+    let mut daemon = Daemon::new(&mut server, battle_manager, GAME_STOP_TIMEOUT);
+    daemon.set_config_watcher(&mut config_watcher);
+    daemon.set_health(health);
+    if let Some(admin_requests) = admin_requests {
+        daemon.set_admin_requests(admin_requests);
+    }
+    daemon.run(shutdown_signal()).await?;
 
     Ok(())
 }
+
+/// Resolves once the process is asked to terminate, with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+/// Exponential backoff between reconnection attempts.
+///
+/// The delay doubles with every attempt up to a maximum, and up to half of it is random
+/// so that autohosts disconnected together don't all reconnect at once.
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Self {
+        Backoff {
+            initial_delay,
+            max_delay,
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max_delay);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay / 2 + (delay / 2).mul_f64(fastrand::f64())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay()).collect();
+
+        for (delay, ceiling) in delays.iter().zip([1, 2, 4, 8, 10, 10]) {
+            let ceiling = Duration::from_secs(ceiling);
+            assert!(*delay >= ceiling / 2 && *delay <= ceiling, "{:?}", delay);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
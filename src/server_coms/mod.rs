@@ -1,3 +1,7 @@
+pub mod backoff;
+pub mod protocol;
 mod responses;
 pub mod server;
 pub mod server_error;
+pub mod server_request;
+pub mod token_store;
@@ -0,0 +1,350 @@
+//! The Tachyon commands used by an autohost.
+//!
+//! Every message is a JSON object naming its command in `cmd`. Commands sent by the
+//! client are prefixed with `c.`, the ones sent by the server with `s.`. A client may add
+//! a `msg_id` to a request, which the server echoes in its response.
+
+use std::collections::HashMap;
+use std::result::Result;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::autohost::battle::Battle;
+
+pub type LobbyId = u64;
+pub type UserId = u64;
+pub type MsgId = u64;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("Failed to encode message")]
+    Encode(#[source] serde_json::Error),
+    #[error("Failed to decode message")]
+    Decode(#[source] serde_json::Error),
+}
+
+/// A lobby as the server knows it. The id is assigned by the server when it is opened.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LobbyDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<LobbyId>,
+    pub name: String,
+    pub game_name: String,
+    pub map_name: String,
+    pub port: u16,
+}
+
+impl LobbyDetails {
+    pub fn new(name: &str, battle: &Battle) -> Self {
+        LobbyDetails {
+            id: None,
+            name: name.to_string(),
+            game_name: battle.game_version.clone(),
+            map_name: battle.map_name.clone(),
+            port: battle.host_port,
+        }
+    }
+
+    pub fn to_battle(&self) -> Battle {
+        Battle::new(&self.game_name, &self.map_name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub id: UserId,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostStatus {
+    pub in_progress: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// A command sent by the autohost.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd")]
+pub enum Request {
+    #[serde(rename = "c.auth.get_token")]
+    GetToken {
+        email: String,
+        password: String,
+        ttl: String,
+    },
+    #[serde(rename = "c.auth.disconnect")]
+    Disconnect,
+    #[serde(rename = "c.system.ping")]
+    Ping,
+    #[serde(rename = "c.lobby.create")]
+    OpenLobby { lobby: LobbyDetails },
+    #[serde(rename = "c.lobby.update")]
+    UpdateLobby { lobby: LobbyDetails },
+    #[serde(rename = "c.lobby.close")]
+    CloseLobby { lobby_id: LobbyId },
+    #[serde(rename = "c.lobby_host.update_host_status")]
+    UpdateHostStatus {
+        lobby_id: LobbyId,
+        status: HostStatus,
+    },
+}
+
+/// A command sent by the server, either in response to a `Request` or unprompted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd")]
+pub enum ServerMessage {
+    #[serde(rename = "s.system.pong")]
+    Pong,
+    #[serde(rename = "s.system.error")]
+    Error { error: String },
+    #[serde(rename = "s.auth.disconnect")]
+    Disconnected {
+        result: Outcome,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    #[serde(rename = "s.lobby.create")]
+    LobbyOpened {
+        result: Outcome,
+        #[serde(default)]
+        lobby: Option<LobbyDetails>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    #[serde(rename = "s.lobby.update")]
+    LobbyUpdated {
+        result: Outcome,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    #[serde(rename = "s.lobby.close")]
+    LobbyClosed {
+        result: Outcome,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    #[serde(rename = "s.lobby_host.update_host_status")]
+    HostStatusUpdated {
+        result: Outcome,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    #[serde(rename = "s.lobby_host.open_request")]
+    OpenRequest { lobby: LobbyDetails },
+    #[serde(rename = "s.lobby_host.start_request")]
+    StartRequest { lobby_id: LobbyId },
+    #[serde(rename = "s.lobby_host.close_request")]
+    CloseRequest { lobby_id: LobbyId },
+    /// A user joined a lobby, to play in `ally_team` unless spectating.
+    #[serde(rename = "s.lobby.add_user")]
+    UserJoined {
+        lobby_id: LobbyId,
+        user: User,
+        #[serde(default)]
+        ally_team: Option<usize>,
+        #[serde(default)]
+        spectator: bool,
+    },
+    #[serde(rename = "s.lobby.remove_user")]
+    UserLeft { lobby_id: LobbyId, user: User },
+    #[serde(other)]
+    Unknown,
+}
+
+impl ServerMessage {
+    /// The reason given by the server if the message reports a failed request.
+    pub fn get_failure(&self) -> Option<&str> {
+        match self {
+            ServerMessage::Error { error } => Some(error),
+            ServerMessage::LobbyOpened {
+                result: Outcome::Failure,
+                reason,
+                ..
+            }
+            | ServerMessage::Disconnected {
+                result: Outcome::Failure,
+                reason,
+            }
+            | ServerMessage::LobbyUpdated {
+                result: Outcome::Failure,
+                reason,
+            }
+            | ServerMessage::LobbyClosed {
+                result: Outcome::Failure,
+                reason,
+            }
+            | ServerMessage::HostStatusUpdated {
+                result: Outcome::Failure,
+                reason,
+            } => Some(reason.as_deref().unwrap_or("no reason given")),
+            _ => None,
+        }
+    }
+}
+
+/// A message along with the id correlating a request to its response.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<MsgId>,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+/// Matches server messages to the requests they respond to.
+pub struct Correlator {
+    next_msg_id: MsgId,
+    pending: HashMap<MsgId, oneshot::Sender<ServerMessage>>,
+}
+
+impl Default for Correlator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Correlator {
+    pub fn new() -> Self {
+        Correlator {
+            next_msg_id: 1,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Encodes a request under a fresh `msg_id`, returned along with it. The returned
+    /// receiver resolves once its response is passed to `dispatch`.
+    pub fn request(
+        &mut self,
+        request: Request,
+    ) -> Result<(MsgId, String, oneshot::Receiver<ServerMessage>), ProtocolError> {
+        let msg_id = self.next_msg_id;
+
+        let text = serde_json::to_string(&Envelope {
+            msg_id: Some(msg_id),
+            message: request,
+        })
+        .map_err(ProtocolError::Encode)?;
+
+        self.next_msg_id += 1;
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(msg_id, sender);
+
+        Ok((msg_id, text, receiver))
+    }
+
+    /// Decodes a server message and hands it to the request awaiting it. Messages no
+    /// request is waiting for are returned instead.
+    pub fn dispatch(&mut self, text: &str) -> Result<Option<ServerMessage>, ProtocolError> {
+        let envelope: Envelope<ServerMessage> =
+            serde_json::from_str(text).map_err(ProtocolError::Decode)?;
+
+        let Some(sender) = envelope.msg_id.and_then(|id| self.pending.remove(&id)) else {
+            return Ok(Some(envelope.message));
+        };
+
+        // The request may have been given up on, in which case the response is dropped.
+        let _ = sender.send(envelope.message);
+
+        Ok(None)
+    }
+
+    /// Drops a request that is no longer waited for, e.g. after it timed out. Its
+    /// response is returned by `dispatch` like any other message if it still arrives.
+    pub fn cancel(&mut self, msg_id: MsgId) {
+        self.pending.remove(&msg_id);
+    }
+
+    /// Drops every pending request, e.g. when the connection is lost.
+    pub fn cancel_all(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_is_encoded_with_cmd_and_msg_id() {
+        let mut correlator = Correlator::new();
+
+        let (_, text, _response) = correlator
+            .request(Request::CloseLobby { lobby_id: 7 })
+            .unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"cmd": "c.lobby.close", "msg_id": 1, "lobby_id": 7})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_responses_resolve_their_request() {
+        let mut correlator = Correlator::new();
+        let (_, _, first) = correlator.request(Request::Ping).unwrap();
+        let (_, _, second) = correlator.request(Request::Ping).unwrap();
+
+        let event = correlator
+            .dispatch(r#"{"cmd": "s.lobby_host.start_request", "lobby_id": 3}"#)
+            .unwrap();
+        assert_eq!(event, Some(ServerMessage::StartRequest { lobby_id: 3 }));
+
+        assert_eq!(
+            correlator
+                .dispatch(r#"{"cmd": "s.system.pong", "msg_id": 2}"#)
+                .unwrap(),
+            None
+        );
+        drop(first);
+        assert_eq!(second.await.unwrap(), ServerMessage::Pong);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_is_no_longer_pending() {
+        let mut correlator = Correlator::new();
+        let (msg_id, _, response) = correlator.request(Request::Ping).unwrap();
+
+        correlator.cancel(msg_id);
+
+        assert!(correlator.pending.is_empty());
+        assert!(response.await.is_err());
+        assert_eq!(
+            correlator
+                .dispatch(r#"{"cmd": "s.system.pong", "msg_id": 1}"#)
+                .unwrap(),
+            Some(ServerMessage::Pong)
+        );
+    }
+
+    #[test]
+    fn test_unknown_commands_are_decoded() {
+        let mut correlator = Correlator::new();
+
+        let message = correlator
+            .dispatch(r#"{"cmd": "s.user.something_new", "whatever": [1, 2]}"#)
+            .unwrap();
+
+        assert_eq!(message, Some(ServerMessage::Unknown));
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let message: ServerMessage = serde_json::from_str(
+            r#"{"cmd": "s.lobby.create", "result": "failure", "reason": "Not allowed"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(message.get_failure(), Some("Not allowed"));
+    }
+}
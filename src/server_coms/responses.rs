@@ -9,6 +9,5 @@ pub struct SuccessfulTokenResponse {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ErrorResponse {
-    #[allow(dead_code)]
     pub detail: String,
 }
@@ -1,48 +1,108 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::result::Result;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use serde::Serialize;
+use tokio::sync::oneshot;
+use tracing::{debug, info, info_span, warn, Span};
 use urlencoding::encode;
 
+use crate::autohost::battle::{Battle, BattlePlayer};
+use crate::autohost::battle_manager::BattleId;
 use crate::utils::config::Config;
+use crate::utils::error_report::ErrorChain;
+use crate::utils::health::Health;
 use crate::utils::http_client::HttpClient;
-use crate::utils::http_request;
-use crate::utils::websocket_client::WebsocketClient;
+use crate::utils::http_request::{self, HttpRequestError};
+use crate::utils::metrics::Metrics;
+use crate::utils::websocket_client::{WebsocketClient, WebsocketError};
 
+use super::backoff::Backoff;
+use super::protocol::{Correlator, HostStatus, LobbyDetails, LobbyId, Request, ServerMessage};
 use super::responses::{ErrorResponse, SuccessfulTokenResponse};
 use super::server_error::ServerError;
+use super::server_request::ServerRequest;
+use super::token_store::{Token, TokenStore};
 
-const ENDPOINT_BASE: &str = "teiserver/api";
 const TOKEN_REQUEST_ENDPOINT: &str = "request_token";
-const DISCONNECT_ENDPOINT: &str = "disconnect";
 const CLIENT_NAME: &str = "bar-autohost";
 const CLIENT_HASH: &str = "ef37ced34460ba9db08eeacc323f07386ad68402"; // sha1 hash
 const TOKEN_TTL: u64 = 60 * 60 * 24;
-
-#[derive(Serialize)]
-struct Authenticate<'a> {
-    pub cmd: &'a str,
-    pub email: &'a str,
-    pub password: &'a str,
-    pub ttl: &'a str,
-}
-
-#[derive(Serialize)]
-struct Disconnect {
-    command: String,
-}
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60 * 5);
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60 * 60);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const TOKEN_REFRESH_RETRY_DELAY: Duration = Duration::from_secs(60 * 5);
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60 * 2);
 
 #[async_trait]
 pub trait Server {
     async fn start_session(&mut self) -> Result<(), ServerError>;
     async fn end_session(&mut self) -> Result<(), ServerError>;
+
+    /// Waits for the next request from the server. Must be cancel safe, as it is raced
+    /// against the updates of running games.
+    async fn next_request(&mut self) -> Result<ServerRequest, ServerError>;
+
+    /// Announces a battle opened by the autohost, or updates the server's lobby of it if
+    /// it was opened on the server's request.
+    async fn battle_opened(
+        &mut self,
+        battle_id: BattleId,
+        battle: &Battle,
+        lobby_id: Option<LobbyId>,
+    ) -> Result<(), ServerError>;
+
+    async fn battle_closed(&mut self, battle_id: BattleId) -> Result<(), ServerError>;
+
+    /// Reports whether a game is running for the battle.
+    async fn battle_status_changed(
+        &mut self,
+        battle_id: BattleId,
+        game_running: bool,
+    ) -> Result<(), ServerError>;
+}
+
+/// A lobby announced to the server for one of the autohost's battles.
+struct AnnouncedLobby {
+    lobby: LobbyDetails,
+    game_running: bool,
+    /// Whether the server opened the lobby itself, in which case it keeps its id across
+    /// reconnections instead of being created again.
+    server_opened: bool,
+    /// Whether the server is up to date with the lobby on the current connection.
+    synced: bool,
 }
 
+/// A session with a Teiserver instance over Tachyon.
+///
+/// The session is supervised by `next_request`: when the connection drops, it reconnects
+/// with an exponential backoff and announces the open battles again, updating the lobbies
+/// the server opened itself rather than creating them anew. Battles opened while
+/// disconnected are announced once the connection is back.
+///
+/// The login token is kept in a `TokenStore` so it is reused across restarts. It is
+/// refreshed ahead of its expiry, and replaced right away if the server rejects it.
 pub struct TeiServer<'a> {
     config: &'a (dyn Config + Sync + Send),
     http_client: &'a (dyn HttpClient + Sync + Send),
     socket_client: &'a mut (dyn WebsocketClient + Sync + Send),
-    token: String,
+    token_store: &'a (dyn TokenStore + Sync + Send),
+    token: Option<Token>,
+    token_refresh_at: Option<Instant>,
+    correlator: Correlator,
+    messages: VecDeque<ServerMessage>,
+    lobbies: BTreeMap<BattleId, AnnouncedLobby>,
+    connected: bool,
+    needs_restore: bool,
+    backoff: Backoff,
+    next_attempt_at: Instant,
+    session_count: u32,
+    span: Span,
+    metrics: Arc<Metrics>,
+    health: Option<Arc<Health>>,
 }
 
 impl<'a> TeiServer<'_> {
@@ -50,107 +110,595 @@ impl<'a> TeiServer<'_> {
         config: &'a (dyn Config + Sync + Send),
         http_client: &'a (dyn HttpClient + Sync + Send),
         socket_client: &'a mut (dyn WebsocketClient + Sync + Send),
+        token_store: &'a (dyn TokenStore + Sync + Send),
     ) -> TeiServer<'a> {
         TeiServer {
             config,
             http_client,
             socket_client,
-            token: String::new(),
+            token_store,
+            token: None,
+            token_refresh_at: None,
+            correlator: Correlator::new(),
+            messages: VecDeque::new(),
+            lobbies: BTreeMap::new(),
+            connected: false,
+            needs_restore: false,
+            backoff: Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY),
+            next_attempt_at: Instant::now(),
+            session_count: 0,
+            span: Span::none(),
+            metrics: Arc::default(),
+            health: None,
         }
     }
 
+    /// Sets the metrics reconnections and token refreshes are counted in.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Sets the health whether the session is connected is reported in.
+    pub fn set_health(&mut self, health: Arc<Health>) {
+        health.set_server_connected(self.connected);
+        self.health = Some(health);
+    }
+
+    fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        if let Some(health) = &self.health {
+            health.set_server_connected(connected);
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
     async fn fetch_token(&mut self) -> Result<String, ServerError> {
-        let authenticate_endpoint_url = format!(
-            "https://{}/{}/{}",
-            self.config.get_server_domain(),
-            ENDPOINT_BASE,
-            TOKEN_REQUEST_ENDPOINT,
-        );
+        let authenticate_endpoint_url = self
+            .config
+            .get_server_http_endpoint()
+            .get_url(self.config.get_server_domain(), TOKEN_REQUEST_ENDPOINT);
 
-        let authenticate_request = Authenticate {
-            cmd: "c.auth.get_token",
-            email: self.config.get_server_login_email(),
-            password: self.config.get_server_login_password(),
-            ttl: &TOKEN_TTL.to_string(),
+        let authenticate_request = Request::GetToken {
+            email: self.config.get_server_login_email().to_string(),
+            password: self.config.get_server_login_password().to_string(),
+            ttl: TOKEN_TTL.to_string(),
         };
 
-        let response = http_request::post(
+        let response: SuccessfulTokenResponse = http_request::post(
             self.http_client,
             &authenticate_endpoint_url,
             &authenticate_request,
         )
         .await
-        .map_err(|e| ServerError::SessionStart(format!("Token request failed: {:?}", e)))?;
-
-        if let Ok(response) = serde_json::from_str::<SuccessfulTokenResponse>(&response) {
-            Ok(response.token_value)
-        } else if let Ok(error_response) = serde_json::from_str::<ErrorResponse>(&response) {
-            Err(ServerError::SessionStart(format!(
-                "Error received for token request: {:?}",
-                error_response
-            )))
+        .map_err(|e| match e {
+            HttpRequestError::Unauthorized(body) => ServerError::LoginRefused {
+                url: authenticate_endpoint_url.clone(),
+                reason: match serde_json::from_str::<ErrorResponse>(&body) {
+                    Ok(error_response) => error_response.detail,
+                    Err(_) => body,
+                },
+            },
+            source => ServerError::Token {
+                url: authenticate_endpoint_url.clone(),
+                source,
+            },
+        })?;
+
+        Ok(response.token_value)
+    }
+
+    /// Ends the session over Tachyon, then closes the websocket with a normal close
+    /// frame.
+    async fn disconnect(&mut self) -> Result<(), ServerError> {
+        let result = if self.connected {
+            self.request_disconnect().await
         } else {
-            Err(ServerError::SessionStart(format!(
-                "Unknown response for token request: {:?}",
-                response
-            )))
+            Ok(())
+        };
+
+        // The connection may already have been lost, which is as good as closed.
+        let _ = self.socket_client.close().await;
+        self.set_connected(false);
+
+        result
+    }
+
+    async fn request_disconnect(&mut self) -> Result<(), ServerError> {
+        let (msg_id, text, mut response) = self.correlator.request(Request::Disconnect)?;
+        if self.socket_client.send(&text).await.is_err() {
+            self.correlator.cancel(msg_id);
+            return Ok(());
+        }
+
+        let received =
+            tokio::time::timeout(DISCONNECT_TIMEOUT, self.receive_until(&mut response)).await;
+        self.correlator.cancel(msg_id);
+
+        match received {
+            Ok(Ok(message)) => match message.get_failure() {
+                Some(reason) => Err(ServerError::SessionEnd(format!(
+                    "Server rejected the disconnect: {}",
+                    reason
+                ))),
+                None => Ok(()),
+            },
+            // The server may end the session by closing the connection without a response.
+            Ok(Err(_)) | Err(_) => Ok(()),
         }
     }
 
-    async fn disconnect(&mut self) -> Result<(), ServerError> {
-        // TODO: Find out what is expected here. Currently returns "Not Found"
-        let disconnect_url = format!(
-            "https://{}/{}/{}",
+    fn set_token(&mut self, token: Token) {
+        self.token_refresh_at =
+            Some(Instant::now() + token.get_time_left().saturating_sub(TOKEN_REFRESH_MARGIN));
+        self.token = Some(token);
+    }
+
+    /// Makes sure a token that isn't about to expire is at hand, preferring the stored
+    /// one over logging in again.
+    async fn ensure_token(&mut self) -> Result<(), ServerError> {
+        if matches!(&self.token, Some(token) if !token.expires_within(TOKEN_EXPIRY_MARGIN)) {
+            return Ok(());
+        }
+
+        match self.token_store.load(
+            self.config.get_server_domain(),
+            self.config.get_server_login_email(),
+        ) {
+            Ok(Some(token)) if !token.expires_within(TOKEN_EXPIRY_MARGIN) => {
+                self.set_token(token);
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(parent: &self.span, error = %ErrorChain(&e), "Failed to load the stored token")
+            }
+        }
+
+        self.refresh_token().await
+    }
+
+    /// Logs in for a new token and stores it.
+    async fn refresh_token(&mut self) -> Result<(), ServerError> {
+        let token = Token::new(
+            &self.fetch_token().await?,
+            Duration::from_secs(TOKEN_TTL),
             self.config.get_server_domain(),
-            ENDPOINT_BASE,
-            DISCONNECT_ENDPOINT,
+            self.config.get_server_login_email(),
         );
+        info!(parent: &self.span, "Logged in for a new token");
+        self.metrics.token_refreshed();
+
+        if let Err(e) = self.token_store.save(&token) {
+            warn!(parent: &self.span, error = %ErrorChain(&e), "Failed to store the token");
+        }
+        self.set_token(token);
 
-        let disconnect_request = Disconnect {
-            command: "disconnect".to_string(),
+        Ok(())
+    }
+
+    fn forget_token(&mut self) {
+        self.token = None;
+        self.token_refresh_at = None;
+
+        if let Err(e) = self.token_store.clear() {
+            warn!(parent: &self.span, error = %ErrorChain(&e), "Failed to clear the stored token");
+        }
+    }
+
+    /// The websocket endpoint, without the token and client details.
+    fn get_websocket_url(&self) -> String {
+        self.config
+            .get_server_websocket_endpoint()
+            .get_url(self.config.get_server_domain(), "")
+    }
+
+    async fn open_socket(&mut self) -> Result<(), WebsocketError> {
+        let token = self.token.as_ref().map_or("", |token| &token.value);
+        let websock_server_url = format!(
+            "{}/?token={}&client_hash={}&client_name={}",
+            self.get_websocket_url(),
+            encode(token),
+            CLIENT_HASH,
+            CLIENT_NAME,
+        );
+
+        self.socket_client.connect(&websock_server_url).await
+    }
+
+    async fn connect(&mut self) -> Result<(), ServerError> {
+        self.ensure_token().await?;
+
+        let result = match self.open_socket().await {
+            // The token was revoked or has expired early, so a new one is fetched.
+            Err(WebsocketError::Rejected(status @ (401 | 403))) => {
+                info!(parent: &self.span, status, "Token rejected, logging in again");
+                self.forget_token();
+                self.ensure_token().await?;
+                self.open_socket().await
+            }
+            result => result,
         };
 
-        http_request::post(self.http_client, &disconnect_url, &disconnect_request)
-            .await
-            .map_err(|e| {
-                ServerError::SessionEnd(format!("Error occured while ending session: {:?}", e))
-            })?;
+        result.map_err(|source| ServerError::Connect {
+            url: self.get_websocket_url(),
+            source,
+        })?;
+        self.set_connected(true);
+        info!(parent: &self.span, "Connected to the server");
+
+        Ok(())
+    }
+
+    fn connection_lost(&mut self) {
+        self.set_connected(false);
+        self.needs_restore = true;
+        self.correlator.cancel_all();
+        for announced in self.lobbies.values_mut() {
+            announced.synced = false;
+            if !announced.server_opened {
+                announced.lobby.id = None;
+            }
+        }
+    }
+
+    /// Reconnects until it succeeds, then announces the battles the server doesn't know
+    /// about. Safe to cancel, as it picks up where it was left off when called again.
+    async fn restore_session(&mut self) {
+        loop {
+            if !self.connected {
+                tokio::time::sleep_until(self.next_attempt_at.into()).await;
+                self.next_attempt_at = Instant::now() + self.backoff.next_delay();
+
+                if let Err(e) = self.connect().await {
+                    warn!(parent: &self.span, error = %ErrorChain(&e), "Failed to reconnect to the server");
+                    continue;
+                }
+                self.metrics.server_reconnected();
+            }
+
+            match self.announce_lobbies().await {
+                Err(ServerError::Connection(e)) => {
+                    warn!(parent: &self.span, error = %ErrorChain(&e), "Lost connection to the server");
+                }
+                result => {
+                    if let Err(e) = result {
+                        warn!(parent: &self.span, error = %ErrorChain(&e), "Failed to announce battles");
+                    }
+                    self.needs_restore = false;
+                    self.backoff.reset();
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn announce_lobbies(&mut self) -> Result<(), ServerError> {
+        let unsynced: Vec<BattleId> = self
+            .lobbies
+            .iter()
+            .filter(|(_, announced)| !announced.synced)
+            .map(|(battle_id, _)| *battle_id)
+            .collect();
+
+        let mut result = Ok(());
+        for battle_id in unsynced {
+            match self.sync_lobby(battle_id).await {
+                Err(e @ ServerError::Connection(_)) => return Err(e),
+                Err(e) => result = Err(e),
+                Ok(()) => {}
+            }
+        }
+
+        result
+    }
+
+    /// Brings the server up to date with a battle's lobby, creating it unless the server
+    /// opened it itself.
+    async fn sync_lobby(&mut self, battle_id: BattleId) -> Result<(), ServerError> {
+        let Some(announced) = self.lobbies.get(&battle_id) else {
+            return Ok(());
+        };
+        let lobby = announced.lobby.clone();
+        let game_running = announced.game_running;
+
+        let lobby_id = match lobby.id {
+            // The server's lobby only lacks the host port the battle was given.
+            Some(lobby_id) => {
+                self.request(Request::UpdateLobby { lobby }).await?;
+                info!(parent: &self.span, battle_id, lobby_id, "Updated the server's lobby");
+                lobby_id
+            }
+            None => self.open_lobby(battle_id, lobby).await?,
+        };
+        if let Some(announced) = self.lobbies.get_mut(&battle_id) {
+            announced.synced = true;
+        }
+
+        if game_running {
+            self.send_status(lobby_id, game_running).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn open_lobby(
+        &mut self,
+        battle_id: BattleId,
+        lobby: LobbyDetails,
+    ) -> Result<LobbyId, ServerError> {
+        let lobby_id = match self.request(Request::OpenLobby { lobby }).await? {
+            ServerMessage::LobbyOpened {
+                lobby:
+                    Some(LobbyDetails {
+                        id: Some(lobby_id), ..
+                    }),
+                ..
+            } => lobby_id,
+            response => {
+                return Err(ServerError::Request(format!(
+                    "Unexpected response to opening a lobby: {:?}",
+                    response
+                )))
+            }
+        };
+
+        if let Some(announced) = self.lobbies.get_mut(&battle_id) {
+            announced.lobby.id = Some(lobby_id);
+        }
+        info!(parent: &self.span, battle_id, lobby_id, "Announced battle");
+
+        Ok(lobby_id)
+    }
+
+    async fn send_status(
+        &mut self,
+        lobby_id: LobbyId,
+        game_running: bool,
+    ) -> Result<(), ServerError> {
+        self.request(Request::UpdateHostStatus {
+            lobby_id,
+            status: HostStatus {
+                in_progress: game_running,
+            },
+        })
+        .await?;
 
         Ok(())
     }
+
+    /// Sends a request and waits up to `REQUEST_TIMEOUT` for its response. Messages
+    /// received in the meantime are kept for `next_request`.
+    async fn request(&mut self, request: Request) -> Result<ServerMessage, ServerError> {
+        let (msg_id, text, mut response) = self.correlator.request(request)?;
+
+        let exchange = async {
+            self.socket_client.send(&text).await?;
+            self.receive_until(&mut response).await
+        };
+        // A late response is then ignored along with the other messages that aren't
+        // server requests.
+        let received = match tokio::time::timeout(REQUEST_TIMEOUT, exchange).await {
+            Ok(received) => received,
+            Err(_) => {
+                self.correlator.cancel(msg_id);
+                return Err(ServerError::Timeout(REQUEST_TIMEOUT));
+            }
+        };
+
+        match received {
+            Ok(message) => match message.get_failure() {
+                Some(reason) => Err(ServerError::Request(reason.to_string())),
+                None => Ok(message),
+            },
+            Err(e) => {
+                warn!(parent: &self.span, error = %ErrorChain(&e), "Lost connection to the server");
+                self.connection_lost();
+                Err(ServerError::Connection(e))
+            }
+        }
+    }
+
+    async fn receive_until(
+        &mut self,
+        response: &mut oneshot::Receiver<ServerMessage>,
+    ) -> Result<ServerMessage, WebsocketError> {
+        loop {
+            let text = self.socket_client.receive().await?;
+            if let Ok(Some(message)) = self.correlator.dispatch(&text) {
+                self.messages.push_back(message);
+            }
+
+            if let Ok(message) = response.try_recv() {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn battle_id(&self, lobby_id: LobbyId) -> Option<BattleId> {
+        self.lobbies
+            .iter()
+            .find(|(_, announced)| announced.lobby.id == Some(lobby_id))
+            .map(|(battle_id, _)| *battle_id)
+    }
+
+    fn to_server_request(&self, message: ServerMessage) -> Option<ServerRequest> {
+        match message {
+            ServerMessage::OpenRequest { lobby } => {
+                Some(ServerRequest::OpenBattle(lobby.to_battle(), lobby.id))
+            }
+            ServerMessage::StartRequest { lobby_id } => {
+                Some(ServerRequest::StartGame(self.battle_id(lobby_id)?))
+            }
+            ServerMessage::CloseRequest { lobby_id } => {
+                Some(ServerRequest::CloseBattle(self.battle_id(lobby_id)?))
+            }
+            ServerMessage::UserJoined {
+                lobby_id,
+                user,
+                ally_team,
+                spectator,
+            } => {
+                let ally_team = ally_team.filter(|_| !spectator);
+                let mut player = BattlePlayer::new(&user.name, ally_team);
+                player.country_code = user.country;
+                Some(ServerRequest::AddPlayer(self.battle_id(lobby_id)?, player))
+            }
+            ServerMessage::UserLeft { lobby_id, user } => Some(ServerRequest::RemovePlayer(
+                self.battle_id(lobby_id)?,
+                user.name,
+            )),
+            _ => None,
+        }
+    }
 }
 
 #[async_trait]
 impl Server for TeiServer<'_> {
     async fn start_session(&mut self) -> Result<(), ServerError> {
-        if self.token.is_empty() {
-            self.token = self.fetch_token().await?;
+        self.session_count += 1;
+        self.span = info_span!(
+            "session",
+            id = self.session_count,
+            domain = %self.config.get_server_domain()
+        );
+
+        self.connect().await
+    }
+
+    async fn end_session(&mut self) -> Result<(), ServerError> {
+        let result = self.disconnect().await;
+
+        self.needs_restore = false;
+        self.correlator.cancel_all();
+        self.messages.clear();
+        self.lobbies.clear();
+
+        // The token is forgotten along with the session, while its stored copy is kept
+        // for the next start of the autohost.
+        self.token = None;
+        self.token_refresh_at = None;
+        info!(parent: &self.span, "Session ended");
+
+        result
+    }
+
+    async fn next_request(&mut self) -> Result<ServerRequest, ServerError> {
+        loop {
+            if !self.connected || self.needs_restore {
+                self.restore_session().await;
+            }
+
+            let message = match self.messages.pop_front() {
+                Some(message) => message,
+                None => {
+                    let token_refresh_at = self.token_refresh_at;
+                    let received = tokio::select! {
+                        received = self.socket_client.receive() => received,
+                        _ = sleep_until(token_refresh_at) => {
+                            if let Err(e) = self.refresh_token().await {
+                                warn!(parent: &self.span, error = %ErrorChain(&e), "Failed to refresh the token");
+                                self.token_refresh_at =
+                                    Some(Instant::now() + TOKEN_REFRESH_RETRY_DELAY);
+                            }
+                            continue;
+                        }
+                    };
+                    let text = match received {
+                        Ok(text) => text,
+                        Err(e) => {
+                            warn!(parent: &self.span, error = %ErrorChain(&e), "Lost connection to the server");
+                            self.connection_lost();
+                            continue;
+                        }
+                    };
+                    // A message that can't be decoded is skipped rather than dropping
+                    // the connection.
+                    match self.correlator.dispatch(&text) {
+                        Ok(Some(message)) => message,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            debug!(parent: &self.span, error = %ErrorChain(&e), text, "Skipped a message");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            if let Some(request) = self.to_server_request(message) {
+                return Ok(request);
+            }
         }
+    }
 
-        let websock_server_url = format!(
-            "wss://{}/tachyon/websocket/?token={}&client_hash={}&client_name={}",
-            self.config.get_server_domain(),
-            encode(&self.token),
-            CLIENT_HASH,
-            CLIENT_NAME,
+    async fn battle_opened(
+        &mut self,
+        battle_id: BattleId,
+        battle: &Battle,
+        lobby_id: Option<LobbyId>,
+    ) -> Result<(), ServerError> {
+        let mut lobby = LobbyDetails::new(&format!("{} #{}", CLIENT_NAME, battle_id), battle);
+        lobby.id = lobby_id;
+        self.lobbies.insert(
+            battle_id,
+            AnnouncedLobby {
+                lobby,
+                game_running: false,
+                server_opened: lobby_id.is_some(),
+                synced: false,
+            },
         );
 
-        self.socket_client
-            .connect(&websock_server_url)
-            .map_err(|e| {
-                ServerError::SessionStart(format!("Error occured while starting session: {:?}", e))
-            })?;
+        // Battles opened while disconnected are announced once reconnected.
+        if !self.connected {
+            return Ok(());
+        }
 
-        Ok(())
+        self.sync_lobby(battle_id).await
     }
 
-    async fn end_session(&mut self) -> Result<(), ServerError> {
-        if !self.token.is_empty() {
-            self.disconnect().await?;
-            self.token.clear();
+    async fn battle_closed(&mut self, battle_id: BattleId) -> Result<(), ServerError> {
+        let lobby_id = self
+            .lobbies
+            .remove(&battle_id)
+            .and_then(|announced| announced.lobby.id);
+
+        match lobby_id {
+            Some(lobby_id) if self.connected => {
+                self.request(Request::CloseLobby { lobby_id }).await?;
+                Ok(())
+            }
+            _ => Ok(()),
         }
+    }
 
-        Ok(())
+    async fn battle_status_changed(
+        &mut self,
+        battle_id: BattleId,
+        game_running: bool,
+    ) -> Result<(), ServerError> {
+        let Some(announced) = self.lobbies.get_mut(&battle_id) else {
+            return Err(ServerError::Request(format!(
+                "Battle {} has no lobby",
+                battle_id
+            )));
+        };
+        announced.game_running = game_running;
+
+        match announced.lobby.id {
+            Some(lobby_id) if self.connected => self.send_status(lobby_id, game_running).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sleeps until the given instant, or forever if there is none.
+async fn sleep_until(instant: Option<Instant>) {
+    match instant {
+        Some(instant) => tokio::time::sleep_until(instant.into()).await,
+        None => std::future::pending().await,
     }
 }
 
@@ -159,68 +707,74 @@ mod tests {
 
     use async_trait::async_trait;
     use reqwest::header::HeaderMap;
+    use reqwest::StatusCode;
+
+    use std::io;
+    use std::path::Path;
+    use std::sync::Mutex;
 
-    use crate::utils::http_client::HttpClientError;
+    use tokio_tungstenite::tungstenite::Error as TungsteniteError;
+
+    use super::super::token_store::TokenStoreError;
+    use crate::utils::config::fake::FakeConfig;
+    use crate::utils::http_client::{HttpClientError, HttpResponse};
     use crate::utils::websocket_client::WebsocketError;
 
     use super::*;
 
-    struct FakeConfig {
-        pub spring_relative_path: String,
-        pub start_script_relative_path: String,
-        pub write_dir_relative_path: String,
-        pub server_domain: String,
-        pub server_login_email: String,
-        pub server_login_password: String,
-    }
-
-    impl FakeConfig {
-        fn new() -> FakeConfig {
-            FakeConfig {
-                spring_relative_path: "fake_string_relative_path".to_string(),
-                start_script_relative_path: "fake_start_script_relative_path".to_string(),
-                write_dir_relative_path: "fake_write_dir_relative_path".to_string(),
-                server_domain: "fake_string_server_domain".to_string(),
-                server_login_email: "fake_string_server_login_email".to_string(),
-                server_login_password: "fake_server_login_password".to_string(),
-            }
-        }
+    #[derive(Default)]
+    struct FakeTokenStore {
+        token: Mutex<Option<Token>>,
     }
 
-    impl Config for FakeConfig {
-        fn get_spring_relative_path(&self) -> &str {
-            &self.spring_relative_path
-        }
-
-        fn get_start_script_relative_path(&self) -> &str {
-            &self.start_script_relative_path
+    impl FakeTokenStore {
+        fn build_with_token(token: Token) -> Self {
+            FakeTokenStore {
+                token: Mutex::new(Some(token)),
+            }
         }
+    }
 
-        fn get_write_dir_relative_path(&self) -> &str {
-            &self.write_dir_relative_path
-        }
+    fn build_stored_token(config: &FakeConfig, ttl: Duration) -> Token {
+        Token::new(
+            "stored_token",
+            ttl,
+            config.get_server_domain(),
+            config.get_server_login_email(),
+        )
+    }
 
-        fn get_server_domain(&self) -> &str {
-            &self.server_domain
+    impl TokenStore for FakeTokenStore {
+        fn load(&self, domain: &str, email: &str) -> Result<Option<Token>, TokenStoreError> {
+            let token = self.token.lock().unwrap().clone();
+            Ok(token.filter(|token| token.is_for(domain, email)))
         }
 
-        fn get_server_login_email(&self) -> &str {
-            &self.server_login_email
+        fn save(&self, token: &Token) -> Result<(), TokenStoreError> {
+            *self.token.lock().unwrap() = Some(token.clone());
+            Ok(())
         }
 
-        fn get_server_login_password(&self) -> &str {
-            &self.server_login_password
+        fn clear(&self) -> Result<(), TokenStoreError> {
+            *self.token.lock().unwrap() = None;
+            Ok(())
         }
     }
 
     struct FakeHttpClient {
-        response: Option<String>,
+        response: Option<(StatusCode, String)>,
     }
 
     impl FakeHttpClient {
         fn build_with_successful_response(response: String) -> Self {
             FakeHttpClient {
-                response: Some(response),
+                response: Some((StatusCode::OK, response)),
+            }
+        }
+
+        fn build_with_status(status: StatusCode, response: &str) -> Self {
+            FakeHttpClient {
+                response: Some((status, response.to_string())),
             }
         }
 
@@ -231,51 +785,110 @@ mod tests {
 
     #[async_trait]
     impl HttpClient for FakeHttpClient {
+        async fn get(
+            &self,
+            url: &str,
+            headers: HeaderMap,
+        ) -> Result<HttpResponse, HttpClientError> {
+            self.post(url, String::new(), headers).await
+        }
+
         async fn post(
             &self,
             _url: &str,
             _body: String,
             _headers: HeaderMap,
-        ) -> Result<String, HttpClientError> {
-            if let Some(response) = &self.response {
-                Ok(response.clone())
+        ) -> Result<HttpResponse, HttpClientError> {
+            if let Some((status, body)) = &self.response {
+                Ok(HttpResponse {
+                    status: *status,
+                    headers: HeaderMap::new(),
+                    body: body.clone(),
+                })
             } else {
-                Err(HttpClientError::RequestFailed("Oh noes!".to_string()))
+                Err(HttpClientError::RequestFailed(
+                    io::Error::new(io::ErrorKind::ConnectionRefused, "Oh noes!").into(),
+                ))
             }
         }
     }
 
     struct FakeWebsocketClient {
         should_connect: bool,
+        incoming: VecDeque<Option<String>>,
+        sent: Vec<String>,
+        connections: usize,
+        closed: bool,
     }
 
     impl FakeWebsocketClient {
         fn build(should_connect: bool) -> Self {
-            FakeWebsocketClient { should_connect }
+            FakeWebsocketClient {
+                should_connect,
+                incoming: VecDeque::new(),
+                sent: Vec::new(),
+                connections: 0,
+                closed: false,
+            }
+        }
+
+        /// Messages to receive in order, where `None` drops the connection.
+        fn build_with_incoming(incoming: &[Option<&str>]) -> Self {
+            FakeWebsocketClient {
+                should_connect: true,
+                incoming: incoming
+                    .iter()
+                    .map(|text| text.map(str::to_string))
+                    .collect(),
+                sent: Vec::new(),
+                connections: 0,
+                closed: false,
+            }
         }
     }
 
+    #[async_trait]
     impl WebsocketClient for FakeWebsocketClient {
-        fn connect(&mut self, _server_url: &str) -> Result<(), WebsocketError> {
+        async fn connect(&mut self, _server_url: &str) -> Result<(), WebsocketError> {
             if self.should_connect {
+                self.connections += 1;
                 Ok(())
             } else {
-                Err(WebsocketError::Connection(
-                    "Something went wrong!".to_string(),
-                ))
+                Err(WebsocketError::Connection(Box::new(TungsteniteError::Io(
+                    io::ErrorKind::ConnectionRefused.into(),
+                ))))
             }
         }
+
+        async fn send(&mut self, message: &str) -> Result<(), WebsocketError> {
+            self.sent.push(message.to_string());
+            Ok(())
+        }
+
+        async fn receive(&mut self) -> Result<String, WebsocketError> {
+            match self.incoming.pop_front() {
+                Some(Some(text)) => Ok(text),
+                Some(None) => Err(WebsocketError::Closed),
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn close(&mut self) -> Result<(), WebsocketError> {
+            self.closed = true;
+            Ok(())
+        }
     }
 
     #[tokio::test]
     async fn test_session_start_fails_when_http_client_returns_error() {
-        let config = FakeConfig::new();
+        let config = FakeConfig::default();
 
         let http_client = FakeHttpClient::build_with_failed_response();
 
         let mut websock_client = FakeWebsocketClient::build(false);
 
-        let mut server = TeiServer::new(&config, &http_client, &mut websock_client);
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
 
         let result = server.start_session().await;
         assert!(result.is_err());
@@ -283,7 +896,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_session_start_fails_when_websock_client_returns_error() {
-        let config = FakeConfig::new();
+        let config = FakeConfig::default();
 
         let successful_server_response = SuccessfulTokenResponse {
             token_value: "fake_token".to_string(),
@@ -298,7 +911,8 @@ mod tests {
 
         let mut websock_client = FakeWebsocketClient::build(false);
 
-        let mut server = TeiServer::new(&config, &http_client, &mut websock_client);
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
 
         let result = server.start_session().await;
         assert!(result.is_err());
@@ -306,7 +920,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_session_start_succeeds() {
-        let config = FakeConfig::new();
+        let config = FakeConfig::default();
 
         let successful_server_response = SuccessfulTokenResponse {
             token_value: "fake_token".to_string(),
@@ -321,9 +935,303 @@ mod tests {
 
         let mut websock_client = FakeWebsocketClient::build(true);
 
-        let mut server = TeiServer::new(&config, &http_client, &mut websock_client);
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
 
         let result = server.start_session().await;
         assert!(result.is_ok());
     }
+
+    fn build_token_http_client() -> FakeHttpClient {
+        let successful_server_response = SuccessfulTokenResponse {
+            token_value: "fake_token".to_string(),
+            result: "fake_result".to_string(),
+        };
+
+        FakeHttpClient::build_with_successful_response(
+            serde_json::to_string(&successful_server_response).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_server_requests_refer_to_announced_battles() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[
+            Some(r#"{"cmd": "s.lobby_host.start_request", "lobby_id": 5}"#),
+            Some(
+                r#"{"cmd": "s.lobby.create", "msg_id": 1, "result": "success",
+                    "lobby": {"id": 5, "name": "", "game_name": "", "map_name": "", "port": 8452}}"#,
+            ),
+        ]);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        server
+            .battle_opened(3, &Battle::default(), None)
+            .await
+            .unwrap();
+
+        let request = server.next_request().await.unwrap();
+        assert!(matches!(request, ServerRequest::StartGame(3)));
+    }
+
+    #[tokio::test]
+    async fn test_joined_users_play_in_their_ally_team() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[
+            Some(
+                r#"{"cmd": "s.lobby.create", "msg_id": 1, "result": "success",
+                    "lobby": {"id": 5, "name": "", "game_name": "", "map_name": "", "port": 8452}}"#,
+            ),
+            Some(
+                r#"{"cmd": "s.lobby.add_user", "lobby_id": 5, "ally_team": 1,
+                    "user": {"id": 1, "name": "player", "country": "NL"}}"#,
+            ),
+            Some(
+                r#"{"cmd": "s.lobby.add_user", "lobby_id": 5, "ally_team": 0, "spectator": true,
+                    "user": {"id": 2, "name": "spectator"}}"#,
+            ),
+        ]);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        server
+            .battle_opened(3, &Battle::default(), None)
+            .await
+            .unwrap();
+
+        let ServerRequest::AddPlayer(3, player) = server.next_request().await.unwrap() else {
+            panic!("Expected the player to be added");
+        };
+        assert_eq!(player.ally_team, Some(1));
+        assert_eq!(player.country_code.as_deref(), Some("NL"));
+        let ServerRequest::AddPlayer(3, spectator) = server.next_request().await.unwrap() else {
+            panic!("Expected the spectator to be added");
+        };
+        assert_eq!(spectator.ally_team, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_times_out_without_response() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build(true);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        let result = server.battle_opened(3, &Battle::default(), None).await;
+
+        assert!(matches!(result, Err(ServerError::Timeout(REQUEST_TIMEOUT))));
+        assert!(server.is_connected());
+    }
+
+    #[tokio::test]
+    async fn test_open_battles_are_announced_again_after_reconnecting() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[
+            Some(
+                r#"{"cmd": "s.lobby.create", "msg_id": 1, "result": "success",
+                    "lobby": {"id": 5, "name": "", "game_name": "", "map_name": "", "port": 8452}}"#,
+            ),
+            None,
+            Some(
+                r#"{"cmd": "s.lobby.create", "msg_id": 2, "result": "success",
+                    "lobby": {"id": 6, "name": "", "game_name": "", "map_name": "", "port": 8452}}"#,
+            ),
+            Some(r#"{"cmd": "s.lobby_host.start_request", "lobby_id": 5}"#),
+            Some(r#"{"cmd": "s.lobby_host.start_request", "lobby_id": 6}"#),
+        ]);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        server
+            .battle_opened(3, &Battle::default(), None)
+            .await
+            .unwrap();
+
+        let request = server.next_request().await.unwrap();
+        assert!(matches!(request, ServerRequest::StartGame(3)));
+        assert!(server.is_connected());
+        drop(server);
+        assert_eq!(websock_client.connections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_opened_battles_keep_their_lobby_after_reconnecting() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[
+            Some(r#"{"cmd": "s.lobby.update", "msg_id": 1, "result": "success"}"#),
+            None,
+            Some(r#"{"cmd": "s.lobby.update", "msg_id": 2, "result": "success"}"#),
+            Some(r#"{"cmd": "s.lobby_host.close_request", "lobby_id": 5}"#),
+        ]);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        server
+            .battle_opened(3, &Battle::default(), Some(5))
+            .await
+            .unwrap();
+
+        let request = server.next_request().await.unwrap();
+        assert!(matches!(request, ServerRequest::CloseBattle(3)));
+        drop(server);
+        assert_eq!(websock_client.connections, 2);
+        assert!(websock_client
+            .sent
+            .iter()
+            .all(|text| !text.contains("c.lobby.create")));
+    }
+
+    #[tokio::test]
+    async fn test_session_start_reuses_stored_token() {
+        let config = FakeConfig::default();
+        let http_client = FakeHttpClient::build_with_failed_response();
+        let mut websock_client = FakeWebsocketClient::build(true);
+        let token_store = FakeTokenStore::build_with_token(build_stored_token(
+            &config,
+            Duration::from_secs(60 * 60),
+        ));
+
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+
+        let result = server.start_session().await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_session_start_logs_in_when_stored_token_is_for_another_server() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build(true);
+        let token_store = FakeTokenStore::build_with_token(Token::new(
+            "stored_token",
+            Duration::from_secs(60 * 60),
+            "other.net",
+            config.get_server_login_email(),
+        ));
+
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+
+        let stored = token_store
+            .load(config.get_server_domain(), config.get_server_login_email())
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.value, "fake_token");
+    }
+
+    #[tokio::test]
+    async fn test_session_start_logs_in_when_stored_token_expires() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build(true);
+        let token_store =
+            FakeTokenStore::build_with_token(build_stored_token(&config, Duration::from_secs(60)));
+
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+
+        let stored = token_store
+            .load(config.get_server_domain(), config.get_server_login_email())
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.value, "fake_token");
+    }
+
+    #[tokio::test]
+    async fn test_session_end_disconnects_over_tachyon() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[Some(
+            r#"{"cmd": "s.auth.disconnect", "msg_id": 1, "result": "success"}"#,
+        )]);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        server.end_session().await.unwrap();
+
+        assert!(!server.is_connected());
+        drop(server);
+        assert_eq!(
+            websock_client.sent,
+            vec![r#"{"msg_id":1,"cmd":"c.auth.disconnect"}"#]
+        );
+        assert!(websock_client.closed);
+    }
+
+    #[tokio::test]
+    async fn test_connection_is_reported_in_health() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[Some(
+            r#"{"cmd": "s.auth.disconnect", "msg_id": 1, "result": "success"}"#,
+        )]);
+        let token_store = FakeTokenStore::default();
+        let health = Arc::new(Health::new(Path::new("spring-headless")));
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.set_health(health.clone());
+
+        server.start_session().await.unwrap();
+        let connected = is_server_ready(&health);
+        server.end_session().await.unwrap();
+
+        assert!(connected);
+        assert!(!is_server_ready(&health));
+    }
+
+    fn is_server_ready(health: &Health) -> bool {
+        health
+            .check_readiness()
+            .checks
+            .iter()
+            .any(|check| check.name == "server" && check.ready)
+    }
+
+    #[tokio::test]
+    async fn test_session_end_fails_when_server_rejects_disconnect() {
+        let config = FakeConfig::default();
+        let http_client = build_token_http_client();
+        let mut websock_client = FakeWebsocketClient::build_with_incoming(&[Some(
+            r#"{"cmd": "s.auth.disconnect", "msg_id": 1, "result": "failure", "reason": "No"}"#,
+        )]);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+        server.start_session().await.unwrap();
+        let result = server.end_session().await;
+
+        assert!(matches!(result, Err(ServerError::SessionEnd(_))));
+        drop(server);
+        assert!(websock_client.closed);
+    }
+
+    #[tokio::test]
+    async fn test_session_start_fails_when_login_is_rate_limited() {
+        let config = FakeConfig::default();
+        let http_client = FakeHttpClient::build_with_status(StatusCode::TOO_MANY_REQUESTS, "");
+        let mut websock_client = FakeWebsocketClient::build(true);
+
+        let token_store = FakeTokenStore::default();
+        let mut server = TeiServer::new(&config, &http_client, &mut websock_client, &token_store);
+
+        let result = server.start_session().await;
+        assert!(matches!(
+            result,
+            Err(ServerError::Token {
+                source: HttpRequestError::RateLimited { .. },
+                ..
+            })
+        ));
+    }
 }
@@ -1,9 +1,35 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
+use super::protocol::ProtocolError;
+use crate::utils::http_request::HttpRequestError;
+use crate::utils::websocket_client::WebsocketError;
+
 #[derive(Error, Debug)]
 pub enum ServerError {
-    #[error("Session start error")]
-    SessionStart(String),
-    #[error("Session end error")]
+    #[error("Login to {url} was refused: {reason}")]
+    LoginRefused { url: String, reason: String },
+    #[error("Failed to get a token from {url}")]
+    Token {
+        url: String,
+        #[source]
+        source: HttpRequestError,
+    },
+    #[error("Failed to connect to {url}")]
+    Connect {
+        url: String,
+        #[source]
+        source: WebsocketError,
+    },
+    #[error("Failed to end the session: {0}")]
     SessionEnd(String),
+    #[error("Lost the connection to the server")]
+    Connection(#[from] WebsocketError),
+    #[error("Failed to exchange messages with the server")]
+    Protocol(#[from] ProtocolError),
+    #[error("Request failed: {0}")]
+    Request(String),
+    #[error("The server didn't respond within {0:?}")]
+    Timeout(Duration),
 }
@@ -0,0 +1,19 @@
+use super::protocol::LobbyId;
+use crate::autohost::battle::{Battle, BattlePlayer};
+use crate::autohost::battle_manager::BattleId;
+
+/// A request from the server for the autohost to act on.
+#[derive(Clone, Debug)]
+pub enum ServerRequest {
+    /// Open a lobby for a new battle, along with the id of the lobby the server opened
+    /// for it, if any.
+    OpenBattle(Battle, Option<LobbyId>),
+    /// Start a game for an open battle.
+    StartGame(BattleId),
+    /// Close an open battle, stopping its game if one is running.
+    CloseBattle(BattleId),
+    /// Add a player who joined an open battle.
+    AddPlayer(BattleId, BattlePlayer),
+    /// Remove a player, by name, who left an open battle.
+    RemovePlayer(BattleId, String),
+}
@@ -0,0 +1,196 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TokenStoreError {
+    #[error("Failed to access the token store")]
+    Io(#[from] io::Error),
+    #[error("Invalid token store content")]
+    Format(#[from] serde_json::Error),
+}
+
+/// A login token along with its expiry, in seconds since the Unix epoch, and the
+/// server domain and login email it was issued for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub value: String,
+    pub expires_at: u64,
+    // Tokens stored before these were kept don't match any account.
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub email: String,
+}
+
+impl Token {
+    pub fn new(value: &str, ttl: Duration, domain: &str, email: &str) -> Self {
+        Token {
+            value: value.to_string(),
+            expires_at: (SystemTime::now() + ttl)
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |expiry| expiry.as_secs()),
+            domain: domain.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    /// Whether the token was issued to the `email` account on the `domain` server.
+    pub fn is_for(&self, domain: &str, email: &str) -> bool {
+        self.domain == domain && self.email == email
+    }
+
+    /// The time left until the token expires, zero if it already has.
+    pub fn get_time_left(&self) -> Duration {
+        (UNIX_EPOCH + Duration::from_secs(self.expires_at))
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    }
+
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.get_time_left() <= margin
+    }
+}
+
+pub trait TokenStore {
+    /// Loads the stored token, `None` if there is none for the `email` account on the
+    /// `domain` server.
+    fn load(&self, domain: &str, email: &str) -> Result<Option<Token>, TokenStoreError>;
+    fn save(&self, token: &Token) -> Result<(), TokenStoreError>;
+    fn clear(&self) -> Result<(), TokenStoreError>;
+}
+
+/// Keeps the token in a JSON file only readable by its owner, so it survives restarts.
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new(path: &Path) -> Self {
+        FileTokenStore {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self, domain: &str, email: &str) -> Result<Option<Token>, TokenStoreError> {
+        let token: Token = match fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(token).filter(|token| token.is_for(domain, email)))
+    }
+
+    fn save(&self, token: &Token) -> Result<(), TokenStoreError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+            options.mode(0o600);
+            // The mode only applies to new files, so an existing one is fixed up first.
+            if self.path.exists() {
+                fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+
+        options
+            .open(&self.path)?
+            .write_all(&serde_json::to_vec(token)?)?;
+
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAIN: &str = "server.test";
+    const EMAIL: &str = "autohost@server.test";
+
+    #[test]
+    fn test_token_is_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileTokenStore::new(&dir.path().join("token.json"));
+        let token = Token::new("fake_token", Duration::from_secs(60), DOMAIN, EMAIL);
+
+        assert_eq!(store.load(DOMAIN, EMAIL).unwrap(), None);
+        store.save(&token).unwrap();
+        assert_eq!(store.load(DOMAIN, EMAIL).unwrap(), Some(token));
+        store.clear().unwrap();
+        assert_eq!(store.load(DOMAIN, EMAIL).unwrap(), None);
+    }
+
+    #[test]
+    fn test_token_for_another_account_is_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        let store = FileTokenStore::new(&path);
+        store
+            .save(&Token::new(
+                "fake_token",
+                Duration::from_secs(60),
+                DOMAIN,
+                EMAIL,
+            ))
+            .unwrap();
+
+        assert_eq!(store.load("other.test", EMAIL).unwrap(), None);
+        assert_eq!(store.load(DOMAIN, "other@server.test").unwrap(), None);
+
+        fs::write(&path, r#"{"value": "fake_token", "expires_at": 0}"#).unwrap();
+        assert_eq!(store.load(DOMAIN, EMAIL).unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_token_file_is_only_readable_by_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        FileTokenStore::new(&path)
+            .save(&Token::new(
+                "fake_token",
+                Duration::from_secs(60),
+                DOMAIN,
+                EMAIL,
+            ))
+            .unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let token = Token::new("fake_token", Duration::from_secs(60 * 60), DOMAIN, EMAIL);
+
+        assert!(!token.expires_within(Duration::from_secs(60)));
+        assert!(token.expires_within(Duration::from_secs(60 * 60 * 2)));
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use thiserror::Error;
+
+const CONFIG_FLAG: &str = "--config";
+const PROFILE_FLAG: &str = "--profile";
+const CHECK_CONFIG_FLAG: &str = "--check-config";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ArgsError {
+    #[error("{0} requires a value")]
+    MissingValue(String),
+    #[error("Unknown argument {0}")]
+    Unknown(String),
+}
+
+/// The command line arguments of the autohost.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    config_path: Option<PathBuf>,
+    profile: Option<String>,
+    check_config: bool,
+}
+
+impl Args {
+    /// Parses the arguments following the program name. Values are given either as the
+    /// next argument, `--config path`, or joined with `=`, `--config=path`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ArgsError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+
+            match flag.as_str() {
+                CONFIG_FLAG => {
+                    let value = value.or_else(|| args.next());
+                    parsed.config_path =
+                        Some(PathBuf::from(value.ok_or(ArgsError::MissingValue(flag))?));
+                }
+                PROFILE_FLAG => {
+                    let value = value.or_else(|| args.next());
+                    parsed.profile = Some(value.ok_or(ArgsError::MissingValue(flag))?);
+                }
+                CHECK_CONFIG_FLAG if value.is_none() => parsed.check_config = true,
+                _ => return Err(ArgsError::Unknown(flag)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// The config file given with `--config`, if any.
+    pub fn get_config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
+    /// The config profile given with `--profile`, if any.
+    pub fn get_profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Whether only the config should be checked, with `--check-config`.
+    pub fn is_check_config(&self) -> bool {
+        self.check_config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = parse(&["--config", "/etc/autohost.toml", "--profile=dev"]).unwrap();
+
+        assert_eq!(
+            args.get_config_path(),
+            Some(Path::new("/etc/autohost.toml"))
+        );
+        assert_eq!(args.get_profile(), Some("dev"));
+        assert!(!args.is_check_config());
+        assert!(parse(&["--check-config"]).unwrap().is_check_config());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse(&["--profile"]),
+            Err(ArgsError::MissingValue("--profile".to_string()))
+        );
+        assert_eq!(
+            parse(&["--verbose"]),
+            Err(ArgsError::Unknown("--verbose".to_string()))
+        );
+    }
+}
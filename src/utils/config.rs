@@ -1,48 +1,680 @@
 use figment::{
     providers::{Env, Format, Toml},
-    Figment,
+    Figment, Provider,
 };
 use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use thiserror::Error;
+use tracing_subscriber::EnvFilter;
 
-const CONFIG_FILENAME: &str = "config.toml";
+pub const CONFIG_FILENAME: &str = "config.toml";
+const ENV_PREFIX: &str = "BAR_";
+const PROFILE_ENV_VAR: &str = "BAR_PROFILE";
+const PROFILE_KEY: &str = "profile";
+const DEFAULT_SPRING_RELATIVE_PATH: &str = "spring-headless";
+const DEFAULT_WRITE_DIR_RELATIVE_PATH: &str = "data";
+const DEFAULT_MIN_HOST_PORT: u16 = 8452;
+const DEFAULT_MAX_HOST_PORT: u16 = 8551;
+const DEFAULT_MAX_CONCURRENT_GAMES: usize = 10;
+const DEFAULT_TOKEN_STORE_RELATIVE_PATH: &str = "autohost_token.json";
+const DEFAULT_HTTP_SCHEME: &str = "https";
+const DEFAULT_HTTP_BASE_PATH: &str = "teiserver/api";
+const DEFAULT_WEBSOCKET_SCHEME: &str = "wss";
+const DEFAULT_WEBSOCKET_BASE_PATH: &str = "tachyon/websocket";
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_HTTP_API_PORT: u16 = 9100;
+const WRITE_CHECK_FILENAME: &str = ".autohost_write_check";
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
-    #[error("Config error")]
-    BuildError(#[from] figment::Error),
+    #[error("Failed to load the config")]
+    BuildError(#[from] Box<figment::Error>),
+    #[error("Config file {0} not found")]
+    NotFound(PathBuf),
+    #[error("Config profile {0:?} not found")]
+    UnknownProfile(String),
+    #[error("Only one of {0} and {1} may be set")]
+    Conflict(String, String),
+    #[error("Failed to read secret from {0}")]
+    Secret(PathBuf, #[source] io::Error),
+    #[error("Invalid config:\n{}", format_problems(.0))]
+    Invalid(Vec<ConfigProblem>),
+}
+
+/// A problem with a config value, found by `validate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    pub fn new(key: &str, message: &str) -> Self {
+        ConfigProblem {
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+fn format_problems(problems: &[ConfigProblem]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!("  - {}", problem))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Where an endpoint of the server is reached, relative to its domain.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Endpoint {
+    pub scheme: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    pub base_path: String,
+}
+
+impl Endpoint {
+    pub fn new(scheme: &str, port: Option<u16>, base_path: &str) -> Self {
+        Endpoint {
+            scheme: scheme.to_string(),
+            port,
+            base_path: base_path.to_string(),
+        }
+    }
+
+    /// Builds the URL of `path` under the endpoint's base path on `domain`.
+    pub fn get_url(&self, domain: &str, path: &str) -> String {
+        let port = self
+            .port
+            .map(|port| format!(":{}", port))
+            .unwrap_or_default();
+        let path: Vec<_> = [self.base_path.as_str(), path]
+            .iter()
+            .map(|segment| segment.trim_matches('/'))
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        format!("{}://{}{}/{}", self.scheme, domain, port, path.join("/"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// A JSON object per line, for log collectors.
+    Json,
+}
+
+/// How and where the autohost logs, set in the `[logging]` table.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// The minimum level logged, or a filter like `info,bar_autohost::server_coms=debug`.
+    /// Overridden by the `RUST_LOG` env var.
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// The file to append the log to instead of writing it to stdout.
+    #[serde(default)]
+    pub relative_path: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::default(),
+            level: default_log_level(),
+            relative_path: None,
+        }
+    }
+}
+
+fn default_log_level() -> String {
+    DEFAULT_LOG_LEVEL.to_string()
+}
+
+/// The local HTTP API serving the metrics, health probes and admin API, set in the
+/// `[http_api]` table. It is only started if the table is present.
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+pub struct HttpApiConfig {
+    /// The address to listen on, only reachable from this machine by default.
+    #[serde(default = "default_http_api_address")]
+    pub address: SocketAddr,
+    /// The bearer token admin requests must present. The admin API is disabled without
+    /// one.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// A file holding the admin token, so it can be kept out of the config.
+    #[serde(default)]
+    pub admin_token_file: Option<PathBuf>,
+}
+
+impl Default for HttpApiConfig {
+    fn default() -> Self {
+        HttpApiConfig {
+            address: default_http_api_address(),
+            admin_token: None,
+            admin_token_file: None,
+        }
+    }
+}
+
+/// Keeps the admin token out of logs.
+impl fmt::Debug for HttpApiConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpApiConfig")
+            .field("address", &self.address)
+            .field(
+                "admin_token",
+                &self.admin_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("admin_token_file", &self.admin_token_file)
+            .finish()
+    }
+}
+
+fn default_http_api_address() -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, DEFAULT_HTTP_API_PORT).into()
 }
 
 pub trait Config {
     fn get_spring_relative_path(&self) -> &str;
-    fn get_start_script_relative_path(&self) -> &str;
     fn get_write_dir_relative_path(&self) -> &str;
     fn get_server_domain(&self) -> &str;
     fn get_server_login_email(&self) -> &str;
     fn get_server_login_password(&self) -> &str;
+    fn get_min_host_port(&self) -> u16;
+    fn get_max_host_port(&self) -> u16;
+    fn get_max_concurrent_games(&self) -> usize;
+    fn get_token_store_relative_path(&self) -> &str;
+    fn get_server_http_endpoint(&self) -> &Endpoint;
+    fn get_server_websocket_endpoint(&self) -> &Endpoint;
+    fn get_server_ca_bundle_path(&self) -> Option<&str>;
+    fn get_server_accept_invalid_certs(&self) -> bool;
+    fn get_logging(&self) -> &LoggingConfig;
+    fn get_http_api(&self) -> Option<&HttpApiConfig>;
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct AutohostConfig {
+    #[serde(default = "default_spring_relative_path")]
     spring_relative_path: String,
-    start_script_relative_path: String,
+    #[serde(default = "default_write_dir_relative_path")]
     write_dir_relative_path: String,
     server_domain: String,
     server_login_email: String,
+    #[serde(default)]
     server_login_password: String,
+    /// A file holding the password, so it can be kept out of the config.
+    #[serde(default)]
+    server_login_password_file: Option<PathBuf>,
+    #[serde(default = "default_min_host_port")]
+    min_host_port: u16,
+    #[serde(default = "default_max_host_port")]
+    max_host_port: u16,
+    #[serde(default = "default_max_concurrent_games")]
+    max_concurrent_games: usize,
+    #[serde(default = "default_token_store_relative_path")]
+    token_store_relative_path: String,
+    #[serde(default = "default_server_http_endpoint")]
+    server_http_endpoint: Endpoint,
+    #[serde(default = "default_server_websocket_endpoint")]
+    server_websocket_endpoint: Endpoint,
+    #[serde(default)]
+    server_ca_bundle_path: Option<String>,
+    /// Disables TLS certificate verification. Only meant for development servers.
+    #[serde(default)]
+    server_accept_invalid_certs: bool,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    http_api: Option<HttpApiConfig>,
+}
+
+fn default_spring_relative_path() -> String {
+    DEFAULT_SPRING_RELATIVE_PATH.to_string()
+}
+
+fn default_write_dir_relative_path() -> String {
+    DEFAULT_WRITE_DIR_RELATIVE_PATH.to_string()
+}
+
+fn default_min_host_port() -> u16 {
+    DEFAULT_MIN_HOST_PORT
+}
+
+fn default_max_host_port() -> u16 {
+    DEFAULT_MAX_HOST_PORT
+}
+
+fn default_max_concurrent_games() -> usize {
+    DEFAULT_MAX_CONCURRENT_GAMES
+}
+
+fn default_token_store_relative_path() -> String {
+    DEFAULT_TOKEN_STORE_RELATIVE_PATH.to_string()
+}
+
+fn default_server_http_endpoint() -> Endpoint {
+    Endpoint::new(DEFAULT_HTTP_SCHEME, None, DEFAULT_HTTP_BASE_PATH)
+}
+
+fn default_server_websocket_endpoint() -> Endpoint {
+    Endpoint::new(DEFAULT_WEBSOCKET_SCHEME, None, DEFAULT_WEBSOCKET_BASE_PATH)
 }
 
 /// The `AutohostConfig` uses the [figment crate](https://docs.rs/figment/latest/figment/)
 /// To deserialize configuration data from the `config.toml` file to
 /// be used by the autohost. Env vars can also be used with a few minor changes.
 impl AutohostConfig {
-    pub fn build() -> Result<Self, ConfigError> {
-        Ok(Figment::new()
-            .merge(Toml::file(CONFIG_FILENAME))
-            .merge(Env::prefixed("BAR_"))
-            .extract()?)
+    /// Reads the config file at `path`, `config.toml` in the current directory by
+    /// default, overridden by the `[profile.<name>]` table of the file if a profile is
+    /// selected, and then by `BAR_` env vars. Without a `profile`, it is taken from the
+    /// `BAR_PROFILE` env var, if set.
+    ///
+    /// # Errors
+    ///
+    /// A `ConfigError::NotFound` is returned if a given `path` doesn't exist, and a
+    /// `ConfigError::UnknownProfile` if the file has no table for the profile.
+    ///
+    pub fn build(path: Option<&Path>, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) if !path.is_file() => return Err(ConfigError::NotFound(path.into())),
+            Some(path) => path,
+            None => Path::new(CONFIG_FILENAME),
+        };
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| env::var(PROFILE_ENV_VAR).ok());
+
+        Self::extract(
+            Toml::file(path),
+            profile.as_deref(),
+            Env::prefixed(ENV_PREFIX).ignore(&[PROFILE_KEY]),
+        )
     }
+
+    fn extract(
+        file: impl Provider,
+        profile: Option<&str>,
+        env: impl Provider,
+    ) -> Result<Self, ConfigError> {
+        let file = Figment::from(file);
+        let mut figment = Figment::new().merge(file.clone());
+
+        if let Some(profile) = profile {
+            let key = format!("{}.{}", PROFILE_KEY, profile);
+            if !file.contains(&key) {
+                return Err(ConfigError::UnknownProfile(profile.to_string()));
+            }
+            figment = figment.merge(file.focus(&key));
+        }
+
+        let mut config: AutohostConfig = figment.merge(env).extract().map_err(Box::new)?;
+        config.read_secrets()?;
+
+        Ok(config)
+    }
+
+    fn read_secrets(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = &self.server_login_password_file {
+            if !self.server_login_password.is_empty() {
+                return Err(ConfigError::Conflict(
+                    "server_login_password".to_string(),
+                    "server_login_password_file".to_string(),
+                ));
+            }
+            self.server_login_password = read_secret(path)?;
+        }
+
+        if let Some(http_api) = &mut self.http_api {
+            if let Some(path) = &http_api.admin_token_file {
+                if http_api.admin_token.is_some() {
+                    return Err(ConfigError::Conflict(
+                        "http_api.admin_token".to_string(),
+                        "http_api.admin_token_file".to_string(),
+                    ));
+                }
+                http_api.admin_token = Some(read_secret(path)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_secret(path: &Path) -> Result<String, ConfigError> {
+    let secret =
+        fs::read_to_string(path).map_err(|e| ConfigError::Secret(path.to_path_buf(), e))?;
+
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The settings that differ between two configs, by whether they can be applied to a
+/// running autohost. Settings are named by their config key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigChanges {
+    /// Changes taking effect for the battles and games started from now on.
+    pub applied: Vec<&'static str>,
+    /// Changes only taking effect once the autohost is restarted.
+    pub needs_restart: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn between(old: &dyn Config, new: &dyn Config) -> Self {
+        let mut changes = ConfigChanges::default();
+
+        let mut compare = |key, changed: bool, applied: bool| match (changed, applied) {
+            (false, _) => {}
+            (true, true) => changes.applied.push(key),
+            (true, false) => changes.needs_restart.push(key),
+        };
+
+        compare(
+            "min_host_port",
+            old.get_min_host_port() != new.get_min_host_port(),
+            true,
+        );
+        compare(
+            "max_host_port",
+            old.get_max_host_port() != new.get_max_host_port(),
+            true,
+        );
+        compare(
+            "max_concurrent_games",
+            old.get_max_concurrent_games() != new.get_max_concurrent_games(),
+            true,
+        );
+        compare(
+            "spring_relative_path",
+            old.get_spring_relative_path() != new.get_spring_relative_path(),
+            false,
+        );
+        compare(
+            "write_dir_relative_path",
+            old.get_write_dir_relative_path() != new.get_write_dir_relative_path(),
+            false,
+        );
+        compare(
+            "server_domain",
+            old.get_server_domain() != new.get_server_domain(),
+            false,
+        );
+        compare(
+            "server_login_email",
+            old.get_server_login_email() != new.get_server_login_email(),
+            false,
+        );
+        compare(
+            "server_login_password",
+            old.get_server_login_password() != new.get_server_login_password(),
+            false,
+        );
+        compare(
+            "token_store_relative_path",
+            old.get_token_store_relative_path() != new.get_token_store_relative_path(),
+            false,
+        );
+        compare(
+            "server_http_endpoint",
+            old.get_server_http_endpoint() != new.get_server_http_endpoint(),
+            false,
+        );
+        compare(
+            "server_websocket_endpoint",
+            old.get_server_websocket_endpoint() != new.get_server_websocket_endpoint(),
+            false,
+        );
+        compare(
+            "server_ca_bundle_path",
+            old.get_server_ca_bundle_path() != new.get_server_ca_bundle_path(),
+            false,
+        );
+        compare(
+            "server_accept_invalid_certs",
+            old.get_server_accept_invalid_certs() != new.get_server_accept_invalid_certs(),
+            false,
+        );
+        compare("logging", old.get_logging() != new.get_logging(), false);
+        compare("http_api", old.get_http_api() != new.get_http_api(), false);
+
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.needs_restart.is_empty()
+    }
+}
+
+/// Checks the config against the machine the autohost runs on, with relative paths
+/// resolved against `root_dir`. Every problem found is collected, so they can all be
+/// fixed in one go.
+///
+/// # Errors
+///
+/// A `ConfigError::Invalid` listing the problems is returned if there are any.
+///
+pub fn validate(config: &dyn Config, root_dir: &Path) -> Result<(), ConfigError> {
+    let mut problems = Vec::new();
+
+    check_executable(
+        &root_dir.join(config.get_spring_relative_path()),
+        "spring_relative_path",
+        &mut problems,
+    );
+    check_writable_dir(
+        &root_dir.join(config.get_write_dir_relative_path()),
+        "write_dir_relative_path",
+        &mut problems,
+    );
+
+    let token_store_path = root_dir.join(config.get_token_store_relative_path());
+    if !token_store_path.parent().is_some_and(Path::is_dir) {
+        problems.push(ConfigProblem::new(
+            "token_store_relative_path",
+            &format!(
+                "the directory of {} does not exist",
+                token_store_path.display()
+            ),
+        ));
+    }
+
+    if let Some(ca_bundle_path) = config.get_server_ca_bundle_path() {
+        if !root_dir.join(ca_bundle_path).is_file() {
+            problems.push(ConfigProblem::new(
+                "server_ca_bundle_path",
+                &format!("{} is not a file", ca_bundle_path),
+            ));
+        }
+    }
+
+    if !is_valid_domain(config.get_server_domain()) {
+        problems.push(ConfigProblem::new(
+            "server_domain",
+            &format!(
+                "{:?} is not a host name, expected something like \"server.net\"",
+                config.get_server_domain()
+            ),
+        ));
+    }
+    check_scheme(
+        config.get_server_http_endpoint(),
+        &["http", "https"],
+        "server_http_endpoint",
+        &mut problems,
+    );
+    check_scheme(
+        config.get_server_websocket_endpoint(),
+        &["ws", "wss"],
+        "server_websocket_endpoint",
+        &mut problems,
+    );
+
+    let logging = config.get_logging();
+    if EnvFilter::try_new(&logging.level).is_err() {
+        problems.push(ConfigProblem::new(
+            "logging.level",
+            &format!("{:?} is not a log level or filter", logging.level),
+        ));
+    }
+    if let Some(log_path) = &logging.relative_path {
+        let log_path = root_dir.join(log_path);
+        if !log_path.parent().is_some_and(Path::is_dir) {
+            problems.push(ConfigProblem::new(
+                "logging.relative_path",
+                &format!("the directory of {} does not exist", log_path.display()),
+            ));
+        }
+    }
+
+    if config.get_server_login_email().trim().is_empty() {
+        problems.push(ConfigProblem::new(
+            "server_login_email",
+            "must not be empty",
+        ));
+    }
+    if config.get_server_login_password().is_empty() {
+        problems.push(ConfigProblem::new(
+            "server_login_password",
+            "must not be empty",
+        ));
+    }
+
+    if let Some(http_api) = config.get_http_api() {
+        if http_api.admin_token.as_deref().is_some_and(str::is_empty) {
+            problems.push(ConfigProblem::new(
+                "http_api.admin_token",
+                "must not be empty",
+            ));
+        }
+    }
+
+    let (min_port, max_port) = (config.get_min_host_port(), config.get_max_host_port());
+    if min_port == 0 {
+        problems.push(ConfigProblem::new("min_host_port", "must not be 0"));
+    }
+    if min_port > max_port {
+        problems.push(ConfigProblem::new(
+            "max_host_port",
+            &format!("{} is lower than min_host_port {}", max_port, min_port),
+        ));
+    } else if config.get_max_concurrent_games() > usize::from(max_port - min_port) + 1 {
+        problems.push(ConfigProblem::new(
+            "max_concurrent_games",
+            &format!(
+                "{} games don't fit into the host ports {}-{}",
+                config.get_max_concurrent_games(),
+                min_port,
+                max_port
+            ),
+        ));
+    }
+    if config.get_max_concurrent_games() == 0 {
+        problems.push(ConfigProblem::new("max_concurrent_games", "must not be 0"));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid(problems))
+    }
+}
+
+pub(crate) fn check_executable(path: &Path, key: &str, problems: &mut Vec<ConfigProblem>) {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => {
+            return problems.push(ConfigProblem::new(
+                key,
+                &format!("{} is not a file", path.display()),
+            ))
+        }
+        Err(e) => {
+            return problems.push(ConfigProblem::new(
+                key,
+                &format!("{} can't be accessed: {}", path.display(), e),
+            ))
+        }
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if metadata.permissions().mode() & 0o111 == 0 {
+            problems.push(ConfigProblem::new(
+                key,
+                &format!("{} is not executable", path.display()),
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+}
+
+fn check_writable_dir(path: &Path, key: &str, problems: &mut Vec<ConfigProblem>) {
+    if !path.is_dir() {
+        return problems.push(ConfigProblem::new(
+            key,
+            &format!("{} is not a directory", path.display()),
+        ));
+    }
+
+    // Permissions don't tell the whole story, eg. on read-only mounts, so the directory
+    // is checked by writing to it.
+    let check_path = path.join(WRITE_CHECK_FILENAME);
+    match fs::write(&check_path, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&check_path);
+        }
+        Err(e) => problems.push(ConfigProblem::new(
+            key,
+            &format!("{} is not writable: {}", path.display(), e),
+        )),
+    }
+}
+
+fn check_scheme(
+    endpoint: &Endpoint,
+    schemes: &[&str],
+    key: &str,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    if !schemes.contains(&endpoint.scheme.as_str()) {
+        problems.push(ConfigProblem::new(
+            &format!("{}.scheme", key),
+            &format!("{:?} is not one of {}", endpoint.scheme, schemes.join(", ")),
+        ));
+    }
+}
+
+/// Whether `domain` is a host name or IPv4 address, without a scheme, port or path.
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
 }
 
 impl Config for AutohostConfig {
@@ -50,10 +682,6 @@ impl Config for AutohostConfig {
         &self.spring_relative_path
     }
 
-    fn get_start_script_relative_path(&self) -> &str {
-        &self.start_script_relative_path
-    }
-
     fn get_write_dir_relative_path(&self) -> &str {
         &self.write_dir_relative_path
     }
@@ -69,4 +697,349 @@ impl Config for AutohostConfig {
     fn get_server_login_password(&self) -> &str {
         &self.server_login_password
     }
+
+    fn get_min_host_port(&self) -> u16 {
+        self.min_host_port
+    }
+
+    fn get_max_host_port(&self) -> u16 {
+        self.max_host_port
+    }
+
+    fn get_max_concurrent_games(&self) -> usize {
+        self.max_concurrent_games
+    }
+
+    fn get_token_store_relative_path(&self) -> &str {
+        &self.token_store_relative_path
+    }
+
+    fn get_server_http_endpoint(&self) -> &Endpoint {
+        &self.server_http_endpoint
+    }
+
+    fn get_server_websocket_endpoint(&self) -> &Endpoint {
+        &self.server_websocket_endpoint
+    }
+
+    fn get_server_ca_bundle_path(&self) -> Option<&str> {
+        self.server_ca_bundle_path.as_deref()
+    }
+
+    fn get_server_accept_invalid_certs(&self) -> bool {
+        self.server_accept_invalid_certs
+    }
+
+    fn get_logging(&self) -> &LoggingConfig {
+        &self.logging
+    }
+
+    fn get_http_api(&self) -> Option<&HttpApiConfig> {
+        self.http_api.as_ref()
+    }
+}
+
+/// The config unit tests run with, holding the defaults of a deployed autohost
+/// except for the values a test sets.
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::*;
+
+    pub struct FakeConfig {
+        pub spring_relative_path: String,
+        pub write_dir_relative_path: String,
+        pub server_domain: String,
+        pub server_login_email: String,
+        pub server_login_password: String,
+        pub min_host_port: u16,
+        pub max_host_port: u16,
+        pub max_concurrent_games: usize,
+        pub token_store_relative_path: String,
+        pub server_http_endpoint: Endpoint,
+        pub server_websocket_endpoint: Endpoint,
+        pub logging: LoggingConfig,
+        pub http_api: Option<HttpApiConfig>,
+    }
+
+    impl Default for FakeConfig {
+        fn default() -> Self {
+            FakeConfig {
+                spring_relative_path: default_spring_relative_path(),
+                write_dir_relative_path: default_write_dir_relative_path(),
+                server_domain: "server.net".to_string(),
+                server_login_email: "autohost@server.net".to_string(),
+                server_login_password: "password".to_string(),
+                min_host_port: default_min_host_port(),
+                max_host_port: default_max_host_port(),
+                max_concurrent_games: default_max_concurrent_games(),
+                token_store_relative_path: default_token_store_relative_path(),
+                server_http_endpoint: default_server_http_endpoint(),
+                server_websocket_endpoint: default_server_websocket_endpoint(),
+                logging: LoggingConfig::default(),
+                http_api: None,
+            }
+        }
+    }
+
+    impl Config for FakeConfig {
+        fn get_spring_relative_path(&self) -> &str {
+            &self.spring_relative_path
+        }
+
+        fn get_write_dir_relative_path(&self) -> &str {
+            &self.write_dir_relative_path
+        }
+
+        fn get_server_domain(&self) -> &str {
+            &self.server_domain
+        }
+
+        fn get_server_login_email(&self) -> &str {
+            &self.server_login_email
+        }
+
+        fn get_server_login_password(&self) -> &str {
+            &self.server_login_password
+        }
+
+        fn get_min_host_port(&self) -> u16 {
+            self.min_host_port
+        }
+
+        fn get_max_host_port(&self) -> u16 {
+            self.max_host_port
+        }
+
+        fn get_max_concurrent_games(&self) -> usize {
+            self.max_concurrent_games
+        }
+
+        fn get_token_store_relative_path(&self) -> &str {
+            &self.token_store_relative_path
+        }
+
+        fn get_server_http_endpoint(&self) -> &Endpoint {
+            &self.server_http_endpoint
+        }
+
+        fn get_server_websocket_endpoint(&self) -> &Endpoint {
+            &self.server_websocket_endpoint
+        }
+
+        fn get_server_ca_bundle_path(&self) -> Option<&str> {
+            None
+        }
+
+        fn get_server_accept_invalid_certs(&self) -> bool {
+            false
+        }
+
+        fn get_logging(&self) -> &LoggingConfig {
+            &self.logging
+        }
+
+        fn get_http_api(&self) -> Option<&HttpApiConfig> {
+            self.http_api.as_ref()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_url() {
+        let endpoint = Endpoint::new("http", Some(4000), "/teiserver/api/");
+
+        assert_eq!(
+            endpoint.get_url("localhost", "request_token"),
+            "http://localhost:4000/teiserver/api/request_token"
+        );
+        assert_eq!(
+            default_server_websocket_endpoint().get_url("server.net", ""),
+            "wss://server.net/tachyon/websocket"
+        );
+    }
+
+    fn build_config(spring_relative_path: &str) -> AutohostConfig {
+        AutohostConfig {
+            spring_relative_path: spring_relative_path.to_string(),
+            write_dir_relative_path: "data".to_string(),
+            server_domain: "server.net".to_string(),
+            server_login_email: "autohost@server.net".to_string(),
+            server_login_password: "password".to_string(),
+            server_login_password_file: None,
+            min_host_port: default_min_host_port(),
+            max_host_port: default_max_host_port(),
+            max_concurrent_games: default_max_concurrent_games(),
+            token_store_relative_path: default_token_store_relative_path(),
+            server_http_endpoint: default_server_http_endpoint(),
+            server_websocket_endpoint: default_server_websocket_endpoint(),
+            server_ca_bundle_path: None,
+            server_accept_invalid_certs: false,
+            logging: LoggingConfig::default(),
+            http_api: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_accepts_valid_config() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_dir = tempfile::tempdir().unwrap();
+        let spring_path = root_dir.path().join("spring-headless");
+        fs::write(&spring_path, "").unwrap();
+        fs::set_permissions(&spring_path, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir(root_dir.path().join("data")).unwrap();
+
+        let result = validate(&build_config("spring-headless"), root_dir.path());
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(!root_dir
+            .path()
+            .join("data")
+            .join(WRITE_CHECK_FILENAME)
+            .exists());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let mut config = build_config("missing/spring-headless");
+        config.server_domain = "https://server.net/".to_string();
+        config.server_login_password = String::new();
+        config.min_host_port = 9000;
+        config.max_host_port = 8000;
+        config.server_websocket_endpoint.scheme = "https".to_string();
+
+        let problems = match validate(&config, root_dir.path()) {
+            Err(ConfigError::Invalid(problems)) => problems,
+            result => panic!("Unexpected result {:?}", result),
+        };
+
+        let keys: Vec<_> = problems
+            .iter()
+            .map(|problem| problem.key.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "spring_relative_path",
+                "write_dir_relative_path",
+                "server_domain",
+                "server_websocket_endpoint.scheme",
+                "server_login_password",
+                "max_host_port",
+            ]
+        );
+    }
+
+    const CONFIG: &str = r#"
+        server_domain = "server.net"
+        server_login_email = "autohost@server.net"
+        server_login_password = "password"
+
+        [profile.dev]
+        server_domain = "localhost"
+        max_concurrent_games = 2
+
+        [profile.dev.server_http_endpoint]
+        scheme = "http"
+        port = 4000
+        base_path = "teiserver/api"
+    "#;
+
+    #[test]
+    fn test_extract_applies_defaults_and_profile() {
+        let config =
+            AutohostConfig::extract(Toml::string(CONFIG), Some("dev"), Toml::string("")).unwrap();
+
+        assert_eq!(config.get_spring_relative_path(), "spring-headless");
+        assert_eq!(config.get_server_domain(), "localhost");
+        assert_eq!(config.get_max_concurrent_games(), 2);
+        assert_eq!(config.get_min_host_port(), DEFAULT_MIN_HOST_PORT);
+        assert_eq!(config.get_server_http_endpoint().port, Some(4000));
+
+        let config = AutohostConfig::extract(
+            Toml::string(CONFIG),
+            None,
+            Toml::string("max_concurrent_games = 5"),
+        )
+        .unwrap();
+        assert_eq!(config.get_server_domain(), "server.net");
+        assert_eq!(config.get_max_concurrent_games(), 5);
+
+        let result = AutohostConfig::extract(Toml::string(CONFIG), Some("prod"), Toml::string(""));
+        assert!(matches!(result, Err(ConfigError::UnknownProfile(_))));
+    }
+
+    #[test]
+    fn test_password_is_read_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let password_path = dir.path().join("password");
+        fs::write(&password_path, "secret\n").unwrap();
+        let file = format!(
+            "server_domain = \"server.net\"\n\
+             server_login_email = \"autohost@server.net\"\n\
+             server_login_password_file = {:?}\n",
+            password_path
+        );
+
+        let config = AutohostConfig::extract(Toml::string(&file), None, Toml::string("")).unwrap();
+        assert_eq!(config.get_server_login_password(), "secret");
+
+        let result = AutohostConfig::extract(
+            Toml::string(&file),
+            None,
+            Toml::string("server_login_password = \"password\""),
+        );
+        assert!(matches!(result, Err(ConfigError::Conflict(_, _))));
+    }
+
+    #[test]
+    fn test_admin_token_is_read_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_path = dir.path().join("admin_token");
+        fs::write(&token_path, "token\r\n").unwrap();
+        let file = format!(
+            "server_domain = \"server.net\"\n\
+             server_login_email = \"autohost@server.net\"\n\
+             server_login_password = \"password\"\n\
+             [http_api]\n\
+             admin_token_file = {:?}\n",
+            token_path
+        );
+
+        let config = AutohostConfig::extract(Toml::string(&file), None, Toml::string("")).unwrap();
+        let http_api = config.get_http_api().unwrap();
+        assert_eq!(http_api.admin_token.as_deref(), Some("token"));
+        assert!(!format!("{:?}", http_api).contains("\"token\""));
+    }
+
+    #[test]
+    fn test_changes_between_configs() {
+        let old = build_config("spring-headless");
+        let mut new = old.clone();
+        new.max_concurrent_games = 20;
+        new.server_domain = "localhost".to_string();
+
+        let changes = ConfigChanges::between(&old, &new);
+
+        assert_eq!(changes.applied, ["max_concurrent_games"]);
+        assert_eq!(changes.needs_restart, ["server_domain"]);
+        assert!(ConfigChanges::between(&old, &old.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_domain_validation() {
+        assert!(is_valid_domain("server.beyondallreason.info"));
+        assert!(is_valid_domain("localhost"));
+        assert!(is_valid_domain("127.0.0.1"));
+        assert!(!is_valid_domain(""));
+        assert!(!is_valid_domain("server.net:4000"));
+        assert!(!is_valid_domain("-server.net"));
+        assert!(!is_valid_domain("server..net"));
+    }
 }
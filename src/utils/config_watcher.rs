@@ -0,0 +1,193 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use super::config::{self, AutohostConfig, Config, ConfigChanges, ConfigError, CONFIG_FILENAME};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup = ();
+
+/// A config read again while the autohost is running, along with what changed.
+pub struct ConfigReload {
+    pub config: Box<dyn Config + Send + Sync>,
+    pub changes: ConfigChanges,
+}
+
+#[async_trait]
+pub trait ConfigWatcher {
+    /// Waits until the config should be reloaded, then reads and validates it. Must be
+    /// cancel safe, as it is raced against the requests of the server.
+    ///
+    /// The changes are relative to the last config reloaded successfully. A config
+    /// that fails to load or validate is reported once, and the next reload is still
+    /// compared to the last good one.
+    async fn next_reload(&mut self) -> Result<ConfigReload, ConfigError>;
+}
+
+/// Reloads the config whenever its file is modified, or the process receives a SIGHUP.
+///
+/// The file is polled for changes of its modification time, which doesn't need any
+/// support from the platform and copes with editors replacing the file.
+pub struct FileConfigWatcher {
+    path: Option<PathBuf>,
+    profile: Option<String>,
+    root_dir: PathBuf,
+    config: AutohostConfig,
+    modified: Option<SystemTime>,
+    interval: Interval,
+    hangup: Hangup,
+}
+
+impl FileConfigWatcher {
+    /// Watches the config built from `path` and `profile`, like `AutohostConfig::build`,
+    /// starting from the `config` currently in use. Must be called from within a tokio
+    /// runtime.
+    pub fn new(
+        path: Option<&Path>,
+        profile: Option<&str>,
+        root_dir: &Path,
+        config: AutohostConfig,
+    ) -> Self {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut watcher = FileConfigWatcher {
+            path: path.map(Path::to_path_buf),
+            profile: profile.map(str::to_string),
+            root_dir: root_dir.to_path_buf(),
+            config,
+            modified: None,
+            interval,
+            hangup: listen_for_hangup(),
+        };
+        watcher.modified = watcher.get_modified();
+
+        watcher
+    }
+
+    fn get_file_path(&self) -> &Path {
+        self.path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(CONFIG_FILENAME))
+    }
+
+    fn get_modified(&self) -> Option<SystemTime> {
+        fs::metadata(self.get_file_path())
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn reload(&mut self) -> Result<ConfigReload, ConfigError> {
+        let config = AutohostConfig::build(self.path.as_deref(), self.profile.as_deref())?;
+        config::validate(&config, &self.root_dir)?;
+
+        let changes = ConfigChanges::between(&self.config, &config);
+        self.config = config.clone();
+
+        Ok(ConfigReload {
+            config: Box::new(config),
+            changes,
+        })
+    }
+}
+
+#[async_trait]
+impl ConfigWatcher for FileConfigWatcher {
+    async fn next_reload(&mut self) -> Result<ConfigReload, ConfigError> {
+        loop {
+            tokio::select! {
+                _ = self.interval.tick() => {
+                    let modified = self.get_modified();
+                    if modified == self.modified {
+                        continue;
+                    }
+                    self.modified = modified;
+                }
+                _ = hangup(&mut self.hangup) => {}
+            }
+
+            return self.reload();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn listen_for_hangup() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup()).ok()
+}
+
+#[cfg(not(unix))]
+fn listen_for_hangup() -> Hangup {}
+
+/// Resolves on every SIGHUP, never where there are no signals.
+async fn hangup(hangup: &mut Hangup) {
+    #[cfg(unix)]
+    if let Some(hangup) = hangup {
+        hangup.recv().await;
+        return;
+    }
+    #[cfg(not(unix))]
+    let _ = hangup;
+
+    std::future::pending().await
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+        spring_relative_path = "spring-headless"
+        write_dir_relative_path = "."
+        server_domain = "server.net"
+        server_login_email = "autohost@server.net"
+        server_login_password = "password"
+    "#;
+
+    #[tokio::test]
+    async fn test_modified_file_is_reloaded() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let spring_path = root_dir.path().join("spring-headless");
+        fs::write(&spring_path, "").unwrap();
+        fs::set_permissions(&spring_path, fs::Permissions::from_mode(0o755)).unwrap();
+        let config_path = root_dir.path().join("config.toml");
+        fs::write(&config_path, CONFIG).unwrap();
+
+        let config = AutohostConfig::build(Some(&config_path), None).unwrap();
+        let mut watcher = FileConfigWatcher::new(Some(&config_path), None, root_dir.path(), config);
+        // Modification times may be as coarse as a second.
+        let modified = SystemTime::now() + Duration::from_secs(5);
+        fs::write(
+            &config_path,
+            format!("{}\nmax_concurrent_games = 3\n", CONFIG),
+        )
+        .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&config_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let reload = tokio::time::timeout(Duration::from_secs(10), watcher.next_reload())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(reload.config.get_max_concurrent_games(), 3);
+        assert_eq!(reload.changes.applied, ["max_concurrent_games"]);
+        assert!(reload.changes.needs_restart.is_empty());
+    }
+}
@@ -6,7 +6,7 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum EnvironmentError {
-    #[error("Envinronment error")]
+    #[error("Failed to find the current directory")]
     EnvarRetrievalFailure(#[from] std::io::Error),
 }
 
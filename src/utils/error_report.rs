@@ -0,0 +1,228 @@
+use std::error::Error;
+use std::fmt;
+use std::iter;
+
+use super::args::ArgsError;
+use super::config::ConfigError;
+use super::environment::EnvironmentError;
+use super::http_api::HttpApiError;
+use super::http_client::HttpClientError;
+use super::http_request::HttpRequestError;
+use super::logging::LoggingError;
+use super::websocket_client::WebsocketError;
+use crate::autohost::engine_interface::EngineInterfaceError;
+use crate::autohost::spring::{GameError, LaunchError};
+use crate::server_coms::server_error::ServerError;
+
+/// What stopped the autohost, which decides the exit code and the fix suggested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    Other,
+    Usage,
+    Config,
+    Environment,
+    Login,
+    Server,
+    Engine,
+}
+
+impl FailureKind {
+    /// Classifies an error by the outermost error of its chain with a known kind. A
+    /// refused login anywhere in the chain takes precedence, as the server errors it
+    /// is wrapped in would otherwise hide it.
+    pub fn of(error: &(dyn Error + 'static)) -> Self {
+        if chain(error).any(is_login_failure) {
+            return FailureKind::Login;
+        }
+
+        chain(error).find_map(kind_of).unwrap_or(FailureKind::Other)
+    }
+
+    /// The exit code of the process, distinct for every kind of failure.
+    pub fn get_exit_code(&self) -> u8 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::Usage => 2,
+            FailureKind::Config => 3,
+            FailureKind::Environment => 4,
+            FailureKind::Login => 5,
+            FailureKind::Server => 6,
+            FailureKind::Engine => 7,
+        }
+    }
+
+    pub fn get_suggestion(&self) -> Option<&'static str> {
+        match self {
+            FailureKind::Other | FailureKind::Usage => None,
+            FailureKind::Config => {
+                Some("Fix the config, then check it with `bar-autohost --check-config`")
+            }
+            FailureKind::Environment => {
+                Some("Make sure the autohost is started from a directory it can access")
+            }
+            FailureKind::Login => Some(
+                "Check server_login_email and the password, and that the account may host \
+                 battles",
+            ),
+            FailureKind::Server => Some(
+                "Check server_domain and the server endpoints, and that the server can be \
+                 reached from this machine",
+            ),
+            FailureKind::Engine => Some(
+                "Check that spring_relative_path points at an executable spring-headless, \
+                 and that the write dir is writable",
+            ),
+        }
+    }
+}
+
+/// Displays an error followed by each of its causes on a line of its own, then the fix
+/// suggested for its kind of failure.
+pub struct ErrorReport<'a> {
+    error: &'a (dyn Error + 'static),
+    kind: FailureKind,
+}
+
+impl<'a> ErrorReport<'a> {
+    pub fn new(error: &'a (dyn Error + 'static)) -> Self {
+        ErrorReport {
+            error,
+            kind: FailureKind::of(error),
+        }
+    }
+
+    pub fn get_kind(&self) -> FailureKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for ErrorReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error: {}", self.error)?;
+        for cause in chain(self.error).skip(1) {
+            write!(f, "\n  Caused by: {}", cause)?;
+        }
+        if let Some(suggestion) = self.kind.get_suggestion() {
+            write!(f, "\nHelp: {}", suggestion)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Displays an error and its causes on a single line, separated by colons, for logs.
+pub struct ErrorChain<'a>(pub &'a (dyn Error + 'static));
+
+impl fmt::Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        for cause in chain(self.0).skip(1) {
+            write!(f, ": {}", cause)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn chain<'a>(error: &'a (dyn Error + 'static)) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    iter::successors(Some(error), |&error| error.source())
+}
+
+fn is_login_failure(error: &(dyn Error + 'static)) -> bool {
+    matches!(error.downcast_ref(), Some(ServerError::LoginRefused { .. }))
+        || matches!(
+            error.downcast_ref(),
+            Some(WebsocketError::Rejected(401 | 403))
+        )
+}
+
+fn kind_of(error: &(dyn Error + 'static)) -> Option<FailureKind> {
+    if error.is::<ArgsError>() {
+        Some(FailureKind::Usage)
+    } else if error.is::<ConfigError>() || error.is::<LoggingError>() || error.is::<HttpApiError>()
+    {
+        Some(FailureKind::Config)
+    } else if error.is::<EnvironmentError>() {
+        Some(FailureKind::Environment)
+    } else if error.is::<ServerError>()
+        || error.is::<HttpRequestError>()
+        || error.is::<HttpClientError>()
+        || error.is::<WebsocketError>()
+    {
+        Some(FailureKind::Server)
+    } else if error.is::<LaunchError>()
+        || error.is::<GameError>()
+        || error.is::<EngineInterfaceError>()
+    {
+        Some(FailureKind::Engine)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::autohost::daemon::DaemonError;
+    use crate::autohost::lobby::LobbyError;
+
+    fn launch_error() -> LobbyError {
+        LobbyError::Spring(LaunchError::LaunchFail {
+            path: PathBuf::from("/opt/bar/spring-headless"),
+            source: io::Error::new(io::ErrorKind::NotFound, "No such file or directory"),
+        })
+    }
+
+    #[test]
+    fn test_report_shows_causes_and_suggestion() {
+        let error = launch_error();
+
+        let report = ErrorReport::new(&error);
+
+        assert_eq!(report.get_kind(), FailureKind::Engine);
+        assert_eq!(
+            report.to_string(),
+            "Error: Failed to start the engine\n  \
+             Caused by: Failed to launch /opt/bar/spring-headless\n  \
+             Caused by: No such file or directory\n\
+             Help: Check that spring_relative_path points at an executable spring-headless, \
+             and that the write dir is writable"
+        );
+    }
+
+    #[test]
+    fn test_error_chain_is_a_single_line() {
+        let error = launch_error();
+
+        assert_eq!(
+            ErrorChain(&error).to_string(),
+            "Failed to start the engine: Failed to launch /opt/bar/spring-headless: \
+             No such file or directory"
+        );
+    }
+
+    #[test]
+    fn test_failure_kinds() {
+        let login = DaemonError::Server(ServerError::Connect {
+            url: "wss://server.net/tachyon/websocket".to_string(),
+            source: WebsocketError::Rejected(401),
+        });
+        let server = DaemonError::Server(ServerError::Connect {
+            url: "wss://server.net/tachyon/websocket".to_string(),
+            source: WebsocketError::Unresponsive,
+        });
+        let config = ConfigError::NotFound(PathBuf::from("config.toml"));
+        let usage = ArgsError::Unknown("--verbose".to_string());
+        let other = io::Error::other("Oh noes!");
+
+        assert_eq!(FailureKind::of(&login), FailureKind::Login);
+        assert_eq!(FailureKind::of(&server), FailureKind::Server);
+        assert_eq!(FailureKind::of(&config), FailureKind::Config);
+        assert_eq!(FailureKind::of(&usage), FailureKind::Usage);
+        assert_eq!(FailureKind::of(&other), FailureKind::Other);
+        assert_eq!(FailureKind::of(&launch_error()), FailureKind::Engine);
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+use super::config;
+
+/// The state the readiness of the autohost is judged on, updated as it runs and read by
+/// the HTTP API.
+#[derive(Debug)]
+pub struct Health {
+    spring_path: PathBuf,
+    config_valid: AtomicBool,
+    server_connected: AtomicBool,
+    capacity_available: AtomicBool,
+    draining: AtomicBool,
+}
+
+/// The outcome of a readiness check, `message` telling what's wrong if it failed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+impl Health {
+    /// Judges readiness with the engine at `spring_path`. The config is assumed valid,
+    /// as the autohost doesn't start otherwise, and it isn't connected or hosting yet.
+    pub fn new(spring_path: &Path) -> Self {
+        Health {
+            spring_path: spring_path.to_path_buf(),
+            config_valid: AtomicBool::new(true),
+            server_connected: AtomicBool::new(false),
+            capacity_available: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the last config read, at startup or on reload, was valid.
+    pub fn set_config_valid(&self, valid: bool) {
+        self.config_valid.store(valid, Ordering::Relaxed);
+    }
+
+    pub fn set_server_connected(&self, connected: bool) {
+        self.server_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Whether another game could be started without exceeding the limits.
+    pub fn set_capacity_available(&self, available: bool) {
+        self.capacity_available.store(available, Ordering::Relaxed);
+    }
+
+    /// Whether new battles are turned away, so traffic should go elsewhere.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    /// Checks whether the autohost is ready to host games. The engine binary is looked
+    /// up at every check, as it may be replaced while running.
+    pub fn check_readiness(&self) -> Readiness {
+        let mut engine_problems = Vec::new();
+        config::check_executable(
+            &self.spring_path,
+            "spring_relative_path",
+            &mut engine_problems,
+        );
+
+        let checks = vec![
+            check(
+                "config",
+                self.config_valid.load(Ordering::Relaxed),
+                "The config failed to reload",
+            ),
+            check(
+                "server",
+                self.server_connected.load(Ordering::Relaxed),
+                "Not connected to the server",
+            ),
+            ReadinessCheck {
+                name: "engine",
+                ready: engine_problems.is_empty(),
+                message: engine_problems
+                    .first()
+                    .map(|problem| problem.message.clone()),
+            },
+            check(
+                "capacity",
+                self.capacity_available.load(Ordering::Relaxed),
+                "The maximum number of concurrent games is running",
+            ),
+            check(
+                "draining",
+                !self.draining.load(Ordering::Relaxed),
+                "New battles aren't accepted while draining",
+            ),
+        ];
+
+        Readiness {
+            ready: checks.iter().all(|check| check.ready),
+            checks,
+        }
+    }
+}
+
+fn check(name: &'static str, ready: bool, message: &str) -> ReadinessCheck {
+    ReadinessCheck {
+        name,
+        ready,
+        message: (!ready).then(|| message.to_string()),
+    }
+}
@@ -0,0 +1,589 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::result::Result;
+use std::sync::Arc;
+
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use super::error_report::ErrorChain;
+use super::health::Health;
+use super::metrics::Metrics;
+use crate::autohost::admin::{AdminClient, AdminCommand, AdminError, AdminResponse};
+use crate::autohost::battle_manager::{BattleId, BattleManagerError};
+
+const METRICS_PATH: &str = "/metrics";
+const HEALTH_PATH: &str = "/healthz";
+const READINESS_PATH: &str = "/readyz";
+const ADMIN_PATH: &str = "/admin/";
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+#[derive(Error, Debug)]
+pub enum HttpApiError {
+    #[error("Failed to listen on {0}")]
+    Bind(SocketAddr, #[source] hyper::Error),
+}
+
+/// The local HTTP API of the autohost. It serves:
+///
+/// - `/metrics`, the metrics for Prometheus.
+/// - `/healthz`, answered as long as the process is alive.
+/// - `/readyz`, whether the autohost is ready to host games, with the outcome of every
+///   check as JSON. Answered with a 503 if any check fails.
+/// - `/admin/...`, the admin API, if enabled with `set_admin`. Requests must carry the
+///   admin token as a bearer token.
+pub struct HttpApi {
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    admin: Option<Admin>,
+}
+
+struct Admin {
+    token: String,
+    client: AdminClient,
+}
+
+/// A parsed admin request, which may still need its body read.
+enum AdminRoute {
+    Command(AdminCommand),
+    Say(BattleId),
+}
+
+#[derive(Deserialize)]
+struct SayRequest {
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl HttpApi {
+    pub fn new(metrics: Arc<Metrics>, health: Arc<Health>) -> Self {
+        HttpApi {
+            metrics,
+            health,
+            admin: None,
+        }
+    }
+
+    /// Enables the admin API, serving the requests presenting `token` by sending their
+    /// commands with `client`.
+    pub fn set_admin(&mut self, token: &str, client: AdminClient) {
+        self.admin = Some(Admin {
+            token: token.to_string(),
+            client,
+        });
+    }
+
+    /// Starts listening on `address` and serves requests in a background task, until
+    /// the returned handle is dropped. Must be called from within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// A `HttpApiError::Bind` is returned if the address can't be listened on, such as
+    /// when it's already in use.
+    ///
+    pub fn spawn(self, address: SocketAddr) -> Result<HttpApiHandle, HttpApiError> {
+        let builder = Server::try_bind(&address).map_err(|e| HttpApiError::Bind(address, e))?;
+
+        let api = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let api = api.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let api = api.clone();
+                    async move { Ok::<_, Infallible>(api.handle(request).await) }
+                }))
+            }
+        });
+
+        let server = builder.serve(make_service);
+        let local_addr = server.local_addr();
+        info!(address = %local_addr, "Serving the HTTP API");
+
+        let task = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!(error = %ErrorChain(&e), "The HTTP API stopped");
+            }
+        });
+
+        Ok(HttpApiHandle { local_addr, task })
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let path = request.uri().path();
+        if let Some(admin_path) = path.strip_prefix(ADMIN_PATH) {
+            let admin_path = admin_path.to_string();
+            return self.handle_admin(request, &admin_path).await;
+        }
+        if ![METRICS_PATH, HEALTH_PATH, READINESS_PATH].contains(&path) {
+            return status_response(StatusCode::NOT_FOUND);
+        }
+        if request.method() != Method::GET {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        match path {
+            METRICS_PATH => response(StatusCode::OK, METRICS_CONTENT_TYPE, self.metrics.render()),
+            HEALTH_PATH => response(StatusCode::OK, TEXT_CONTENT_TYPE, "ok"),
+            _ => self.readiness_response(),
+        }
+    }
+
+    async fn handle_admin(&self, request: Request<Body>, path: &str) -> Response<Body> {
+        let Some(admin) = &self.admin else {
+            return status_response(StatusCode::NOT_FOUND);
+        };
+        if !admin.is_authorized(&request) {
+            let mut response = status_response(StatusCode::UNAUTHORIZED);
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return response;
+        }
+
+        let command = match route_admin(request.method(), path) {
+            Ok(AdminRoute::Command(command)) => command,
+            Ok(AdminRoute::Say(battle_id)) => {
+                let body = hyper::body::to_bytes(request.into_body()).await;
+                match body.map(|body| serde_json::from_slice::<SayRequest>(&body)) {
+                    Ok(Ok(say)) => AdminCommand::Say(battle_id, say.message),
+                    _ => return status_response(StatusCode::BAD_REQUEST),
+                }
+            }
+            Err(status) => return status_response(status),
+        };
+
+        match admin.client.send(command).await {
+            Ok(AdminResponse::Battles(battles)) => json_response(StatusCode::OK, &battles),
+            Ok(AdminResponse::Battle(battle)) => json_response(StatusCode::OK, &battle),
+            Ok(AdminResponse::Done) => empty_response(StatusCode::NO_CONTENT),
+            Ok(AdminResponse::Accepted) => empty_response(StatusCode::ACCEPTED),
+            Err(e) => json_response(
+                admin_error_status(&e),
+                &ErrorResponse {
+                    error: ErrorChain(&e).to_string(),
+                },
+            ),
+        }
+    }
+
+    fn readiness_response(&self) -> Response<Body> {
+        let readiness = self.health.check_readiness();
+        let status = if readiness.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        json_response(status, &readiness)
+    }
+}
+
+impl Admin {
+    fn is_authorized(&self, request: &Request<Body>) -> bool {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        token.is_some_and(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes()))
+    }
+}
+
+/// Compares the tokens in a time independent of where they differ, so a token can't be
+/// guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Finds the admin command of a request to `path`, relative to the admin API. An
+/// unknown path is answered with a 404, and a known one requested with the wrong
+/// method with a 405.
+fn route_admin(method: &Method, path: &str) -> Result<AdminRoute, StatusCode> {
+    let battle_id = |id: &str| id.parse().map_err(|_| StatusCode::NOT_FOUND);
+    let segments: Vec<_> = path.trim_end_matches('/').split('/').collect();
+
+    let (route, expected_method) = match segments.as_slice() {
+        ["battles"] => (AdminRoute::Command(AdminCommand::ListBattles), Method::GET),
+        ["battles", id] => (
+            AdminRoute::Command(AdminCommand::GetBattle(battle_id(id)?)),
+            Method::GET,
+        ),
+        ["battles", id, "start"] => (
+            AdminRoute::Command(AdminCommand::ForceStart(battle_id(id)?)),
+            Method::POST,
+        ),
+        ["battles", id, "stop"] => (
+            AdminRoute::Command(AdminCommand::StopGame(battle_id(id)?)),
+            Method::POST,
+        ),
+        ["battles", id, "kill"] => (
+            AdminRoute::Command(AdminCommand::KillGame(battle_id(id)?)),
+            Method::POST,
+        ),
+        ["battles", id, "say"] => (AdminRoute::Say(battle_id(id)?), Method::POST),
+        ["drain"] if method == Method::DELETE => (
+            AdminRoute::Command(AdminCommand::SetDraining(false)),
+            Method::DELETE,
+        ),
+        ["drain"] => (
+            AdminRoute::Command(AdminCommand::SetDraining(true)),
+            Method::POST,
+        ),
+        ["shutdown"] => (AdminRoute::Command(AdminCommand::Shutdown), Method::POST),
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+
+    if *method != expected_method {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    Ok(route)
+}
+
+fn admin_error_status(error: &AdminError) -> StatusCode {
+    match error {
+        AdminError::BattleManager(BattleManagerError::BattleNotFound(_)) => StatusCode::NOT_FOUND,
+        AdminError::NoGameRunning(_) => StatusCode::CONFLICT,
+        AdminError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The running HTTP API, which stops when dropped.
+pub struct HttpApiHandle {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl HttpApiHandle {
+    /// The address listened on, with the actual port if port 0 was asked for.
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for HttpApiHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn response(
+    status: StatusCode,
+    content_type: &'static str,
+    body: impl Into<Body>,
+) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    match serde_json::to_string(body) {
+        Ok(body) => response(status, JSON_CONTENT_TYPE, body),
+        Err(_) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// A response with nothing more to say than its status.
+fn status_response(status: StatusCode) -> Response<Body> {
+    response(
+        status,
+        TEXT_CONTENT_TYPE,
+        status.canonical_reason().unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+
+    use reqwest::header::HeaderMap;
+
+    use super::*;
+    use crate::autohost::admin::{self, BattleStatus, GameState};
+    use crate::utils::http_client::{HttpClient, HttpResponse, TeiHttpClient};
+
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    fn start_api(metrics: Arc<Metrics>, health: Arc<Health>) -> HttpApiHandle {
+        HttpApi::new(metrics, health)
+            .spawn((Ipv4Addr::LOCALHOST, 0).into())
+            .unwrap()
+    }
+
+    fn build_health() -> Arc<Health> {
+        Arc::new(Health::new(Path::new("missing/spring-headless")))
+    }
+
+    async fn get(api: &HttpApiHandle, path: &str) -> HttpResponse {
+        TeiHttpClient::new()
+            .get(
+                &format!("http://{}{}", api.get_local_addr(), path),
+                Default::default(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_served() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.record_launch(true);
+        let api = start_api(metrics, build_health());
+
+        let response = get(&api, "/metrics").await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[CONTENT_TYPE], METRICS_CONTENT_TYPE);
+        assert!(response
+            .body
+            .contains("\nautohost_games_launched_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let api = start_api(Arc::default(), build_health());
+
+        let response = get(&api, "/healthz").await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_readiness_reports_failed_checks() {
+        let health = build_health();
+        health.set_capacity_available(true);
+        let api = start_api(Arc::default(), health);
+
+        let response = get(&api, "/readyz").await;
+        let readiness: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readiness["ready"], false);
+        let failed: Vec<_> = readiness["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|check| check["ready"] == false)
+            .map(|check| check["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(failed, ["server", "engine"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_ready() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_dir = tempfile::tempdir().unwrap();
+        let spring_path = root_dir.path().join("spring-headless");
+        fs::write(&spring_path, "").unwrap();
+        fs::set_permissions(&spring_path, fs::Permissions::from_mode(0o755)).unwrap();
+        let health = Arc::new(Health::new(&spring_path));
+        health.set_server_connected(true);
+        health.set_capacity_available(true);
+        let api = start_api(Arc::default(), health);
+
+        let response = get(&api, "/readyz").await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[CONTENT_TYPE], JSON_CONTENT_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_path_is_not_found() {
+        let api = start_api(Arc::default(), build_health());
+
+        let response = get(&api, "/nope").await;
+
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_address_in_use_is_reported() {
+        let api = start_api(Arc::default(), build_health());
+
+        let result = HttpApi::new(Arc::default(), build_health()).spawn(api.get_local_addr());
+
+        assert!(matches!(result, Err(HttpApiError::Bind(..))));
+    }
+
+    /// Starts an API whose admin commands are answered by `respond`, in place of a daemon.
+    fn start_admin_api(
+        respond: fn(AdminCommand) -> Result<AdminResponse, AdminError>,
+    ) -> HttpApiHandle {
+        let (client, mut requests) = admin::channel();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                let response = respond(request.command.clone());
+                request.respond(response);
+            }
+        });
+
+        let mut api = HttpApi::new(Arc::default(), build_health());
+        api.set_admin(ADMIN_TOKEN, client);
+        api.spawn((Ipv4Addr::LOCALHOST, 0).into()).unwrap()
+    }
+
+    async fn post_admin(api: &HttpApiHandle, path: &str, body: &str) -> HttpResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {}", ADMIN_TOKEN).parse().unwrap(),
+        );
+
+        TeiHttpClient::new()
+            .post(
+                &format!("http://{}{}", api.get_local_addr(), path),
+                body.to_string(),
+                headers,
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let api = start_admin_api(|_| Ok(AdminResponse::Battles(Vec::new())));
+        let disabled_api = start_api(Arc::default(), build_health());
+
+        let missing = get(&api, "/admin/battles").await;
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer wrong-token".parse().unwrap());
+        let wrong = TeiHttpClient::new()
+            .get(
+                &format!("http://{}/admin/battles", api.get_local_addr()),
+                headers,
+            )
+            .await
+            .unwrap();
+        let disabled = get(&disabled_api, "/admin/battles").await;
+
+        assert_eq!(missing.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(missing.headers[WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(disabled.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_commands_are_sent() {
+        let api = start_admin_api(|command| match command {
+            AdminCommand::Say(3, message) if message == "gg" => Ok(AdminResponse::Done),
+            AdminCommand::Shutdown => Ok(AdminResponse::Accepted),
+            AdminCommand::StopGame(battle_id) => Err(AdminError::NoGameRunning(battle_id)),
+            command => panic!("Unexpected command {:?}", command),
+        });
+
+        let said = post_admin(&api, "/admin/battles/3/say", r#"{"message": "gg"}"#).await;
+        let malformed = post_admin(&api, "/admin/battles/3/say", "gg").await;
+        let stopped = post_admin(&api, "/admin/battles/3/stop", "").await;
+        let shutdown = post_admin(&api, "/admin/shutdown", "").await;
+
+        assert_eq!(said.status, StatusCode::NO_CONTENT);
+        assert_eq!(malformed.status, StatusCode::BAD_REQUEST);
+        assert_eq!(stopped.status, StatusCode::CONFLICT);
+        assert_eq!(
+            stopped.body,
+            r#"{"error":"No game is running in battle 3"}"#
+        );
+        assert_eq!(shutdown.status, StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_battles_are_listed() {
+        let api = start_admin_api(|_| {
+            Ok(AdminResponse::Battles(vec![BattleStatus {
+                id: 1,
+                game_version: "Beyond All Reason test-1".to_string(),
+                map_name: "Red Comet".to_string(),
+                host_port: 8452,
+                players: Vec::new(),
+                game_state: GameState::Waiting,
+                players_in_game: 0,
+                running_seconds: None,
+                exit_code: None,
+            }]))
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {}", ADMIN_TOKEN).parse().unwrap(),
+        );
+
+        let response = TeiHttpClient::new()
+            .get(
+                &format!("http://{}/admin/battles", api.get_local_addr()),
+                headers,
+            )
+            .await
+            .unwrap();
+        let battles: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(battles[0]["map_name"], "Red Comet");
+        assert_eq!(battles[0]["game_state"], "waiting");
+    }
+
+    #[test]
+    fn test_admin_routes() {
+        let command = |method, path| match route_admin(&method, path) {
+            Ok(AdminRoute::Command(command)) => Ok(command),
+            Ok(AdminRoute::Say(_)) => panic!("Unexpected say"),
+            Err(status) => Err(status),
+        };
+
+        assert_eq!(
+            command(Method::GET, "battles/7"),
+            Ok(AdminCommand::GetBattle(7))
+        );
+        assert_eq!(
+            command(Method::POST, "battles/7/kill"),
+            Ok(AdminCommand::KillGame(7))
+        );
+        assert_eq!(
+            command(Method::POST, "drain"),
+            Ok(AdminCommand::SetDraining(true))
+        );
+        assert_eq!(
+            command(Method::DELETE, "drain/"),
+            Ok(AdminCommand::SetDraining(false))
+        );
+        assert_eq!(
+            command(Method::GET, "shutdown"),
+            Err(StatusCode::METHOD_NOT_ALLOWED)
+        );
+        assert_eq!(
+            command(Method::GET, "battles/nope"),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert!(matches!(
+            route_admin(&Method::POST, "battles/7/say"),
+            Ok(AdminRoute::Say(7))
+        ));
+    }
+}
@@ -1,6 +1,10 @@
+use std::error::Error as StdError;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::result::Result;
 
-use reqwest::{header::HeaderMap, Client};
+use reqwest::{header::HeaderMap, Certificate, Client, StatusCode};
 
 use async_trait::async_trait;
 use thiserror::Error;
@@ -8,17 +12,31 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum HttpClientError {
     #[error("Request failed")]
-    RequestFailed(String),
+    RequestFailed(#[source] Box<dyn StdError + Send + Sync>),
+    #[error("Failed to read the CA bundle {}", .0.display())]
+    CaBundle(PathBuf, #[source] io::Error),
+    #[error("TLS setup failed")]
+    Tls(#[source] reqwest::Error),
+}
+
+/// A response of any status, as the status is left for the caller to interpret.
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
 }
 
 #[async_trait]
 pub trait HttpClient {
+    async fn get(&self, url: &str, headers: HeaderMap) -> Result<HttpResponse, HttpClientError>;
+
     async fn post(
         &self,
         url: &str,
         body: String,
         headers: HeaderMap,
-    ) -> Result<String, HttpClientError>;
+    ) -> Result<HttpResponse, HttpClientError>;
 }
 
 #[derive(Default)]
@@ -32,16 +50,63 @@ impl TeiHttpClient {
             client: Client::new(),
         }
     }
+
+    /// Builds a client that also trusts the PEM certificates in `ca_bundle_path`, or
+    /// trusts any certificate at all if `accept_invalid_certs` is set.
+    pub fn with_tls(
+        ca_bundle_path: Option<&Path>,
+        accept_invalid_certs: bool,
+    ) -> Result<TeiHttpClient, HttpClientError> {
+        let mut builder = Client::builder().danger_accept_invalid_certs(accept_invalid_certs);
+
+        if let Some(ca_bundle_path) = ca_bundle_path {
+            let pem = fs::read(ca_bundle_path)
+                .map_err(|e| HttpClientError::CaBundle(ca_bundle_path.to_path_buf(), e))?;
+            let certificate = Certificate::from_pem(&pem).map_err(HttpClientError::Tls)?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        Ok(TeiHttpClient {
+            client: builder.build().map_err(HttpClientError::Tls)?,
+        })
+    }
+}
+
+async fn read_response(response: reqwest::Response) -> Result<HttpResponse, HttpClientError> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| HttpClientError::RequestFailed(e.into()))?;
+
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
 }
 
 #[async_trait]
 impl HttpClient for TeiHttpClient {
+    async fn get(&self, url: &str, headers: HeaderMap) -> Result<HttpResponse, HttpClientError> {
+        let response = self
+            .client
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(|e| HttpClientError::RequestFailed(e.into()))?;
+
+        read_response(response).await
+    }
+
     async fn post(
         &self,
         url: &str,
         body: String,
         headers: HeaderMap,
-    ) -> Result<String, HttpClientError> {
+    ) -> Result<HttpResponse, HttpClientError> {
         let response = self
             .client
             .post(url)
@@ -49,12 +114,8 @@ impl HttpClient for TeiHttpClient {
             .body(body)
             .send()
             .await
-            .map_err(|e| HttpClientError::RequestFailed(format!("{:?}", e)))?;
+            .map_err(|e| HttpClientError::RequestFailed(e.into()))?;
 
-        let response = response
-            .text()
-            .await
-            .map_err(|e| HttpClientError::RequestFailed(format!("{:?}", e)))?;
-        Ok(response)
+        read_response(response).await
     }
 }
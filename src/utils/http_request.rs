@@ -1,34 +1,152 @@
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 use thiserror::Error;
 
-use super::http_client::HttpClient;
+use super::http_client::{HttpClient, HttpClientError, HttpResponse};
 
 #[derive(Error, Debug)]
 pub enum HttpRequestError {
-    #[error("Serialization error")]
-    Serialization(String),
-    #[error("Request error")]
-    Request(String),
+    #[error("Failed to serialize the request")]
+    Serialization(#[source] serde_json::Error),
+    #[error(transparent)]
+    Request(#[from] HttpClientError),
+    #[error("Failed to deserialize the response")]
+    Deserialization(#[source] serde_json::Error),
+    #[error("Unauthorized")]
+    Unauthorized(String),
+    #[error("Rate limited")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("Request rejected with status {status}")]
+    Rejected { status: StatusCode, body: String },
+    #[error("Server error with status {status}")]
+    ServerError { status: StatusCode, body: String },
+}
+
+/// Sends a GET request and deserializes the JSON body of a successful response.
+pub async fn get<R: DeserializeOwned>(
+    http_client: &(dyn HttpClient + Sync + Send),
+    endpoint_url: &str,
+) -> Result<R, HttpRequestError> {
+    let response = http_client.get(endpoint_url, json_headers()).await?;
+
+    parse_response(response)
 }
 
-pub async fn post<T: Serialize>(
+/// Sends `body` as JSON in a POST request and deserializes the JSON body of a successful
+/// response.
+pub async fn post<T: Serialize, R: DeserializeOwned>(
     http_client: &(dyn HttpClient + Sync + Send),
     endpoint_url: &str,
     body: &T,
-) -> Result<String, HttpRequestError> {
-    let body = serde_json::to_string(body).map_err(|e| {
-        HttpRequestError::Serialization(format!(
-            "Error occured during request serialization: {:?}",
-            e
-        ))
-    })?;
+) -> Result<R, HttpRequestError> {
+    let body = serde_json::to_string(body).map_err(HttpRequestError::Serialization)?;
+    let response = http_client.post(endpoint_url, body, json_headers()).await?;
+
+    parse_response(response)
+}
 
+fn json_headers() -> HeaderMap {
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    headers
+}
+
+fn parse_response<R: DeserializeOwned>(response: HttpResponse) -> Result<R, HttpRequestError> {
+    let HttpResponse {
+        status,
+        headers,
+        body,
+    } = response;
+
+    match status {
+        status if status.is_success() => {
+            serde_json::from_str(&body).map_err(HttpRequestError::Deserialization)
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err(HttpRequestError::Unauthorized(body))
+        }
+        StatusCode::TOO_MANY_REQUESTS => Err(HttpRequestError::RateLimited {
+            retry_after: headers
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs),
+        }),
+        status if status.is_server_error() => Err(HttpRequestError::ServerError { status, body }),
+        status => Err(HttpRequestError::Rejected { status, body }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Pong {
+        pong: bool,
+    }
+
+    fn build_response(status: StatusCode, headers: HeaderMap, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_successful_response_is_deserialized() {
+        let response = build_response(StatusCode::OK, HeaderMap::new(), r#"{"pong": true}"#);
+
+        assert_eq!(
+            parse_response::<Pong>(response).unwrap(),
+            Pong { pong: true }
+        );
+    }
+
+    #[test]
+    fn test_error_statuses_are_mapped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+
+        let cases = [
+            (StatusCode::UNAUTHORIZED, HeaderMap::new()),
+            (StatusCode::TOO_MANY_REQUESTS, headers),
+            (StatusCode::NOT_FOUND, HeaderMap::new()),
+            (StatusCode::BAD_GATEWAY, HeaderMap::new()),
+        ];
+        let results: Vec<_> = cases
+            .into_iter()
+            .map(|(status, headers)| parse_response::<Pong>(build_response(status, headers, "")))
+            .collect();
 
-    http_client
-        .post(endpoint_url, body, headers)
-        .await
-        .map_err(|e| HttpRequestError::Request(format!("Request failed: {:?}", e)))
+        assert!(matches!(results[0], Err(HttpRequestError::Unauthorized(_))));
+        assert!(matches!(
+            results[1],
+            Err(HttpRequestError::RateLimited {
+                retry_after: Some(retry_after)
+            }) if retry_after == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            results[2],
+            Err(HttpRequestError::Rejected {
+                status: StatusCode::NOT_FOUND,
+                ..
+            })
+        ));
+        assert!(matches!(
+            results[3],
+            Err(HttpRequestError::ServerError {
+                status: StatusCode::BAD_GATEWAY,
+                ..
+            })
+        ));
+    }
 }
@@ -0,0 +1,79 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::Mutex;
+
+use thiserror::Error;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use super::config::{LogFormat, LoggingConfig};
+
+#[derive(Error, Debug)]
+pub enum LoggingError {
+    #[error("Invalid log filter {0:?}")]
+    Filter(String),
+    #[error("Failed to open the log file {}", .0.display())]
+    File(PathBuf, #[source] io::Error),
+    #[error("Logging was already set up")]
+    AlreadySet,
+}
+
+/// Sets up the global logger as configured, with paths relative to `root_dir`. The
+/// `RUST_LOG` env var takes precedence over the configured level.
+///
+/// Spans are logged along with each event, so the lines of a battle carry its id, port
+/// and map, and those of the server its session.
+///
+/// # Errors
+///
+/// A `LoggingError::File` is returned if the log file can't be opened for appending,
+/// and a `LoggingError::AlreadySet` if a global logger was set up before.
+///
+pub fn init(config: &LoggingConfig, root_dir: &Path) -> Result<(), LoggingError> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.level)
+            .map_err(|_| LoggingError::Filter(config.level.clone()))?,
+    };
+
+    match &config.relative_path {
+        Some(relative_path) => {
+            let path = root_dir.join(relative_path);
+            let file = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+                .map_err(|e| LoggingError::File(path.clone(), e))?;
+
+            install(config.format, filter, Mutex::new(file), false)
+        }
+        None => install(config.format, filter, io::stdout, true),
+    }
+}
+
+fn install<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+    ansi: bool,
+) -> Result<(), LoggingError>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+
+    let result = match format {
+        LogFormat::Text => builder.with_ansi(ansi).try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+
+    result.map_err(|_| LoggingError::AlreadySet)
+}
@@ -0,0 +1,255 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the game duration buckets, in seconds.
+const GAME_DURATION_BUCKETS: [u64; 10] = [60, 300, 600, 900, 1200, 1800, 2700, 3600, 5400, 7200];
+
+/// The counters and gauges of a running autohost, shared by everything feeding them
+/// and rendered in the Prometheus text format for the HTTP API.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    games_running: AtomicI64,
+    games_launched: AtomicU64,
+    game_launch_failures: AtomicU64,
+    game_durations: Histogram,
+    engine_crashes: AtomicU64,
+    engine_messages: AtomicU64,
+    players_connected: AtomicI64,
+    server_reconnects: AtomicU64,
+    token_refreshes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Counts an attempt to launch a game, whether the engine was started or not.
+    pub fn record_launch(&self, success: bool) {
+        if success {
+            self.games_launched.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.game_launch_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn game_started(&self) {
+        self.games_running.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the end of a game that ran for `duration`. A `crashed` engine is one
+    /// that failed without being stopped by the autohost.
+    pub fn game_ended(&self, duration: Duration, crashed: bool) {
+        self.games_running.fetch_sub(1, Ordering::Relaxed);
+        self.game_durations.observe(duration);
+        if crashed {
+            self.engine_crashes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops counting a game that is no longer followed, without knowing how it ended.
+    pub fn game_abandoned(&self) {
+        self.games_running.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts a message received from an engine over its autohost interface.
+    pub fn engine_message_received(&self) {
+        self.engine_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn player_joined(&self) {
+        self.players_connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn players_left(&self, count: usize) {
+        self.players_connected
+            .fetch_sub(count as i64, Ordering::Relaxed);
+    }
+
+    pub fn server_reconnected(&self) {
+        self.server_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn token_refreshed(&self) {
+        self.token_refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_games_running(&self) -> i64 {
+        self.games_running.load(Ordering::Relaxed)
+    }
+
+    pub fn get_players_connected(&self) -> i64 {
+        self.players_connected.load(Ordering::Relaxed)
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        write_metric(
+            &mut text,
+            "autohost_games_running",
+            "gauge",
+            "Games whose engine is running.",
+            self.games_running.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut text,
+            "autohost_games_launched_total",
+            "counter",
+            "Games whose engine was launched.",
+            self.games_launched.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut text,
+            "autohost_game_launch_failures_total",
+            "counter",
+            "Games that failed to launch.",
+            self.game_launch_failures.load(Ordering::Relaxed),
+        );
+        self.game_durations.render(
+            &mut text,
+            "autohost_game_duration_seconds",
+            "How long games ran for, from launch to the exit of the engine.",
+        );
+        write_metric(
+            &mut text,
+            "autohost_engine_crashes_total",
+            "counter",
+            "Engines that exited with a failure without being stopped.",
+            self.engine_crashes.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut text,
+            "autohost_engine_messages_total",
+            "counter",
+            "Messages received from engines over the autohost interface.",
+            self.engine_messages.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut text,
+            "autohost_players_connected",
+            "gauge",
+            "Players connected to running games.",
+            self.players_connected.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut text,
+            "autohost_server_reconnects_total",
+            "counter",
+            "Reconnections to the server after losing the connection.",
+            self.server_reconnects.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut text,
+            "autohost_token_refreshes_total",
+            "counter",
+            "Logins to the server for a new token.",
+            self.token_refreshes.load(Ordering::Relaxed),
+        );
+
+        text
+    }
+}
+
+/// Counts observations into fixed buckets, along with their sum.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; GAME_DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_millis: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = GAME_DURATION_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound as f64)
+        {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the buckets as Prometheus expects them, each counting the observations
+    /// up to its bound.
+    fn render(&self, text: &mut String, name: &str, help: &str) {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (bound, bucket) in GAME_DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            text,
+            "{}_sum {}",
+            name,
+            self.sum_millis.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        let _ = writeln!(text, "{}_count {}", name, count);
+    }
+}
+
+fn write_metric(text: &mut String, name: &str, kind: &str, help: &str, value: impl Into<i128>) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+    let _ = writeln!(text, "{} {}", name, value.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_launch(true);
+        metrics.record_launch(false);
+        metrics.game_started();
+        metrics.player_joined();
+        metrics.player_joined();
+        metrics.players_left(1);
+        metrics.token_refreshed();
+
+        let text = metrics.render();
+
+        assert!(text.contains("# TYPE autohost_games_running gauge\nautohost_games_running 1\n"));
+        assert!(text.contains("\nautohost_games_launched_total 1\n"));
+        assert!(text.contains("\nautohost_game_launch_failures_total 1\n"));
+        assert!(text.contains("\nautohost_players_connected 1\n"));
+        assert!(text.contains("\nautohost_token_refreshes_total 1\n"));
+        assert!(text.contains("\nautohost_server_reconnects_total 0\n"));
+    }
+
+    #[test]
+    fn test_game_durations_are_cumulative() {
+        let metrics = Metrics::new();
+        metrics.game_started();
+        metrics.game_started();
+        metrics.game_started();
+        metrics.game_ended(Duration::from_secs(30), false);
+        metrics.game_ended(Duration::from_millis(1_200_500), true);
+        metrics.game_ended(Duration::from_secs(10_000), false);
+
+        let text = metrics.render();
+
+        assert!(text.contains("autohost_game_duration_seconds_bucket{le=\"60\"} 1\n"));
+        assert!(text.contains("autohost_game_duration_seconds_bucket{le=\"1200\"} 1\n"));
+        assert!(text.contains("autohost_game_duration_seconds_bucket{le=\"1800\"} 2\n"));
+        assert!(text.contains("autohost_game_duration_seconds_bucket{le=\"7200\"} 2\n"));
+        assert!(text.contains("autohost_game_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("autohost_game_duration_seconds_sum 11230.5\n"));
+        assert!(text.contains("autohost_game_duration_seconds_count 3\n"));
+        assert!(text.contains("\nautohost_engine_crashes_total 1\n"));
+        assert_eq!(metrics.get_games_running(), 0);
+    }
+}
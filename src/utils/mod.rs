@@ -1,5 +1,12 @@
+pub mod args;
 pub mod config;
+pub mod config_watcher;
 pub mod environment;
+pub mod error_report;
+pub mod health;
+pub mod http_api;
 pub mod http_client;
 pub mod http_request;
+pub mod logging;
+pub mod metrics;
 pub mod websocket_client;
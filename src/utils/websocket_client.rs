@@ -1,36 +1,383 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use native_tls::{Certificate, TlsConnector};
 use thiserror::Error;
-use tungstenite::{connect, stream::MaybeTlsStream, WebSocket};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as TungsteniteError, Message};
+use tokio_tungstenite::{
+    connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
+};
 
-use std::net::TcpStream;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const CHANNEL_SIZE: usize = 256;
 
 #[derive(Error, Debug)]
 pub enum WebsocketError {
-    #[error("Connection error")]
-    Connection(String),
+    #[error("Failed to open the websocket")]
+    Connection(#[source] Box<TungsteniteError>),
+    #[error("Connection rejected with HTTP status {0}")]
+    Rejected(u16),
+    #[error("Failed to read the CA bundle {}", .0.display())]
+    CaBundle(PathBuf, #[source] io::Error),
+    #[error("TLS setup failed")]
+    Tls(#[from] native_tls::Error),
+    #[error("Not connected")]
+    NotConnected,
+    #[error("Connection closed")]
+    Closed,
+    #[error("Connection closed by the server: {0}")]
+    ClosedByServer(String),
+    #[error("The server stopped responding")]
+    Unresponsive,
+    #[error("Failed to send a message")]
+    Send(#[source] Box<TungsteniteError>),
+    #[error("Failed to receive a message")]
+    Receive(#[source] Box<TungsteniteError>),
 }
 
+#[async_trait]
 pub trait WebsocketClient {
-    fn connect(&mut self, server_url: &str) -> Result<(), WebsocketError>;
+    async fn connect(&mut self, server_url: &str) -> Result<(), WebsocketError>;
+
+    /// Sends a text message.
+    async fn send(&mut self, message: &str) -> Result<(), WebsocketError>;
+
+    /// Waits for the next text message. Returns the reason the connection was lost once,
+    /// and `WebsocketError::Closed` after that. Cancel safe.
+    async fn receive(&mut self) -> Result<String, WebsocketError>;
+
+    /// Closes the connection with a close frame, waiting for it to shut down.
+    async fn close(&mut self) -> Result<(), WebsocketError>;
 }
 
+enum Outgoing {
+    Text(String),
+    Close(oneshot::Sender<()>),
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A websocket client that runs its connection on a background task.
+///
+/// The task answers the server's pings, pings the server in turn to detect a dead
+/// connection, and feeds every incoming text message into a channel read by `receive`.
+/// While that channel is full the task stops reading from the socket, leaving the
+/// server to wait, but keeps sending messages and pings.
 #[derive(Default)]
 pub struct TachyonClient {
-    websocket: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+    tls_connector: Option<TlsConnector>,
+    outgoing: Option<mpsc::Sender<Outgoing>>,
+    incoming: Option<mpsc::Receiver<Result<String, WebsocketError>>>,
+    task: Option<JoinHandle<()>>,
 }
 
 impl TachyonClient {
     pub fn new() -> TachyonClient {
-        TachyonClient { websocket: None }
+        TachyonClient {
+            tls_connector: None,
+            outgoing: None,
+            incoming: None,
+            task: None,
+        }
+    }
+
+    /// Builds a client that also trusts the PEM certificates in `ca_bundle_path`, or
+    /// trusts any certificate at all if `accept_invalid_certs` is set.
+    pub fn with_tls(
+        ca_bundle_path: Option<&Path>,
+        accept_invalid_certs: bool,
+    ) -> Result<TachyonClient, WebsocketError> {
+        let mut builder = TlsConnector::builder();
+        builder.danger_accept_invalid_certs(accept_invalid_certs);
+
+        if let Some(ca_bundle_path) = ca_bundle_path {
+            let pem = fs::read(ca_bundle_path)
+                .map_err(|e| WebsocketError::CaBundle(ca_bundle_path.to_path_buf(), e))?;
+            builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+
+        let mut client = TachyonClient::new();
+        client.tls_connector = Some(builder.build()?);
+
+        Ok(client)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(&self.outgoing, Some(outgoing) if !outgoing.is_closed())
+    }
+}
+
+impl Drop for TachyonClient {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
     }
 }
 
+#[async_trait]
 impl WebsocketClient for TachyonClient {
-    fn connect(&mut self, server_url: &str) -> Result<(), WebsocketError> {
-        let (socket, _response) = connect(server_url)
-            .map_err(|e| WebsocketError::Connection(format!("Connection error: {:?}", e)))?;
+    async fn connect(&mut self, server_url: &str) -> Result<(), WebsocketError> {
+        // Without a connector of its own, the default one is picked for wss URLs.
+        let connector = self.tls_connector.clone().map(Connector::NativeTls);
+        let (socket, _response) = connect_async_tls_with_config(server_url, None, connector)
+            .await
+            .map_err(|e| match e {
+                TungsteniteError::Http(response) => {
+                    WebsocketError::Rejected(response.status().as_u16())
+                }
+                e => WebsocketError::Connection(Box::new(e)),
+            })?;
+
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+
+        let (outgoing_sender, outgoing) = mpsc::channel(CHANNEL_SIZE);
+        let (incoming_sender, incoming) = mpsc::channel(CHANNEL_SIZE);
+
+        self.task = Some(tokio::spawn(run_connection(
+            socket,
+            outgoing,
+            incoming_sender,
+        )));
+        self.outgoing = Some(outgoing_sender);
+        self.incoming = Some(incoming);
+
+        Ok(())
+    }
+
+    async fn send(&mut self, message: &str) -> Result<(), WebsocketError> {
+        self.outgoing
+            .as_ref()
+            .ok_or(WebsocketError::NotConnected)?
+            .send(Outgoing::Text(message.to_string()))
+            .await
+            .map_err(|_| WebsocketError::Closed)
+    }
+
+    async fn receive(&mut self) -> Result<String, WebsocketError> {
+        self.incoming
+            .as_mut()
+            .ok_or(WebsocketError::NotConnected)?
+            .recv()
+            .await
+            .unwrap_or(Err(WebsocketError::Closed))
+    }
+
+    async fn close(&mut self) -> Result<(), WebsocketError> {
+        let outgoing = self.outgoing.take().ok_or(WebsocketError::NotConnected)?;
 
-        self.websocket = Some(socket);
+        let (closed_sender, closed) = oneshot::channel();
+        if outgoing.send(Outgoing::Close(closed_sender)).await.is_ok() {
+            let _ = closed.await;
+        }
+
+        self.task = None;
 
         Ok(())
     }
 }
+
+async fn run_connection(
+    mut socket: Socket,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    incoming: mpsc::Sender<Result<String, WebsocketError>>,
+) {
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    ping_interval.tick().await;
+    let mut last_seen = Instant::now();
+    // A message waiting for room in the incoming channel, nothing more is read until
+    // it's delivered.
+    let mut pending = None;
+
+    let reason = loop {
+        tokio::select! {
+            permit = incoming.reserve(), if pending.is_some() => match permit {
+                Ok(permit) => {
+                    if let Some(text) = pending.take() {
+                        permit.send(Ok(text));
+                    }
+                    // The server's replies were left unread, not missing.
+                    last_seen = Instant::now();
+                }
+                Err(_) => return,
+            },
+            message = outgoing.recv() => match message {
+                Some(Outgoing::Text(text)) => {
+                    if let Err(e) = socket.send(Message::Text(text)).await {
+                        break WebsocketError::Send(Box::new(e));
+                    }
+                }
+                Some(Outgoing::Close(closed)) => {
+                    let _ = socket.close(Some(normal_close_frame())).await;
+                    // Wait for the server to acknowledge the close frame.
+                    let acknowledged = async { while let Some(Ok(_)) = socket.next().await {} };
+                    let _ = tokio::time::timeout(CLOSE_TIMEOUT, acknowledged).await;
+                    let _ = closed.send(());
+                    return;
+                }
+                None => {
+                    let _ = socket.close(Some(normal_close_frame())).await;
+                    return;
+                }
+            },
+            message = socket.next(), if pending.is_none() => {
+                last_seen = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => match incoming.try_reserve() {
+                        Ok(permit) => permit.send(Ok(text)),
+                        Err(TrySendError::Full(())) => pending = Some(text),
+                        Err(TrySendError::Closed(())) => return,
+                    },
+                    // Pings are answered by tungstenite itself.
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => {}
+                    Some(Ok(Message::Close(frame))) => {
+                        break WebsocketError::ClosedByServer(describe_close(frame));
+                    }
+                    Some(Err(e)) => {
+                        break WebsocketError::Receive(Box::new(e));
+                    }
+                    None => break WebsocketError::Closed,
+                }
+            }
+            _ = ping_interval.tick() => {
+                if pending.is_none() && last_seen.elapsed() > PING_INTERVAL * 2 {
+                    break WebsocketError::Unresponsive;
+                }
+                if let Err(e) = socket.send(Message::Ping(Vec::new())).await {
+                    break WebsocketError::Send(Box::new(e));
+                }
+            }
+        }
+    };
+
+    let _ = incoming.send(Err(reason)).await;
+}
+
+fn describe_close(frame: Option<CloseFrame>) -> String {
+    match frame {
+        Some(frame) if frame.reason.is_empty() => format!("code {}", frame.code),
+        Some(frame) => format!("code {}, {}", frame.code, frame.reason),
+        None => "no close code".to_string(),
+    }
+}
+
+fn normal_close_frame() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Normal,
+        reason: "".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+
+    /// Echoes text messages back, and reports the close frame sent by the client.
+    async fn start_echo_server() -> (String, oneshot::Receiver<Option<CloseFrame<'static>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (close_sender, close) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+
+            while let Some(Ok(message)) = socket.next().await {
+                match message {
+                    Message::Text(text) if text == "bye" => {
+                        socket.close(None).await.unwrap();
+                    }
+                    Message::Text(text) => socket.send(Message::Text(text)).await.unwrap(),
+                    Message::Close(frame) => {
+                        let _ = close_sender.send(frame);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        (format!("ws://{}", addr), close)
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_sent_and_received() {
+        let (url, close) = start_echo_server().await;
+        let mut client = TachyonClient::new();
+        client.connect(&url).await.unwrap();
+
+        client.send("{\"cmd\":\"c.system.ping\"}").await.unwrap();
+
+        assert_eq!(
+            client.receive().await.unwrap(),
+            "{\"cmd\":\"c.system.ping\"}"
+        );
+        client.close().await.unwrap();
+        assert!(!client.is_connected());
+        assert_eq!(close.await.unwrap().unwrap().code, CloseCode::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_receive_reports_server_close() {
+        let (url, _close) = start_echo_server().await;
+        let mut client = TachyonClient::new();
+        client.connect(&url).await.unwrap();
+
+        client.send("bye").await.unwrap();
+
+        assert!(matches!(
+            client.receive().await,
+            Err(WebsocketError::ClosedByServer(_))
+        ));
+        assert!(matches!(
+            client.receive().await,
+            Err(WebsocketError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_keeps_running_while_messages_are_not_read() {
+        let (url, _close) = start_echo_server().await;
+        let mut client = TachyonClient::new();
+        client.connect(&url).await.unwrap();
+        let count = CHANNEL_SIZE * 3;
+
+        let exchange = async {
+            for i in 0..count {
+                client.send(&i.to_string()).await.unwrap();
+            }
+            for i in 0..count {
+                assert_eq!(client.receive().await.unwrap(), i.to_string());
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(10), exchange)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_when_not_connected() {
+        let mut client = TachyonClient::new();
+
+        let result = client.send("hello").await;
+
+        assert!(matches!(result, Err(WebsocketError::NotConnected)));
+    }
+}
@@ -0,0 +1,160 @@
+mod support;
+
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::oneshot;
+
+use bar_autohost::autohost::battle_manager::BattleManager;
+use bar_autohost::autohost::daemon::Daemon;
+use bar_autohost::autohost::spring::SpringHeadless;
+use bar_autohost::autohost::start_script::script::StartScript;
+use bar_autohost::server_coms::server::TeiServer;
+use bar_autohost::server_coms::token_store::FileTokenStore;
+use bar_autohost::utils::config::Config;
+use bar_autohost::utils::http_client::TeiHttpClient;
+use bar_autohost::utils::websocket_client::TachyonClient;
+
+use support::{write_scenario, MockTeiserver, TestEnvironment};
+
+#[tokio::test]
+async fn test_game_is_started_in_the_servers_lobby_with_the_users_who_joined() {
+    let mock = MockTeiserver::start().await;
+    let config = mock.get_config();
+    let root_dir = tempfile::tempdir().unwrap();
+    let environment = TestEnvironment {
+        root_dir: root_dir.path().to_path_buf(),
+    };
+    let token_store = FileTokenStore::new(&root_dir.path().join("token.json"));
+    let http_client = TeiHttpClient::new();
+    let mut socket_client = TachyonClient::new();
+    let mut server = TeiServer::new(&config, &http_client, &mut socket_client, &token_store);
+    let spring = SpringHeadless::new(Arc::default());
+    let battle_manager = BattleManager::new(&config, &spring, &environment, Arc::default());
+    let mut daemon = Daemon::new(&mut server, battle_manager, Duration::from_secs(5));
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let (result, _) = tokio::join!(
+        daemon.run(async {
+            let _ = stopped.await;
+        }),
+        async {
+            mock.wait_for_connections(1).await;
+            let lobby_id = 42;
+            mock.push(json!({
+                "cmd": "s.lobby_host.open_request",
+                "lobby": {
+                    "id": lobby_id,
+                    "name": "",
+                    "game_name": "BAR test-1",
+                    "map_name": "DSDR 4.1",
+                    "port": 0
+                }
+            }));
+            mock.wait_for_command("c.lobby.update", 1).await;
+            for (id, name, ally_team, spectator) in [
+                (1, "first", 0, false),
+                (2, "second", 1, false),
+                (3, "spectator", 0, true),
+            ] {
+                mock.push(json!({
+                    "cmd": "s.lobby.add_user",
+                    "lobby_id": lobby_id,
+                    "user": {"id": id, "name": name},
+                    "ally_team": ally_team,
+                    "spectator": spectator
+                }));
+            }
+            mock.push(json!({"cmd": "s.lobby_host.start_request", "lobby_id": lobby_id}));
+            mock.wait_for_command("c.lobby_host.update_host_status", 1)
+                .await;
+            let _ = stop.send(());
+        }
+    );
+    result.unwrap();
+
+    let commands = mock.get_received_commands();
+    assert!(!commands.iter().any(|cmd| cmd == "c.lobby.create"));
+    let updated = mock.get_received("c.lobby.update");
+    assert_eq!(updated[0]["lobby"]["id"], 42);
+    assert_eq!(updated[0]["lobby"]["port"], config.get_min_host_port());
+    let status = mock.get_received("c.lobby_host.update_host_status");
+    assert_eq!(status[0]["lobby_id"], 42);
+
+    let script_path = root_dir
+        .path()
+        .join(&config.write_dir_path)
+        .join("battles/1/_script.txt");
+    let script: StartScript = fs::read_to_string(script_path).unwrap().parse().unwrap();
+    let players: Vec<_> = script
+        .players
+        .iter()
+        .map(|player| (player.name.as_str(), player.spectator))
+        .collect();
+    assert_eq!(
+        players,
+        [("first", false), ("second", false), ("spectator", true)]
+    );
+    assert_eq!(script.teams.len(), 2);
+    assert_eq!(script.ally_teams.len(), 2);
+}
+
+#[tokio::test]
+async fn test_closing_a_battle_does_not_wait_for_its_game_to_stop() {
+    let mock = MockTeiserver::start().await;
+    let config = mock.get_config();
+    let root_dir = tempfile::tempdir().unwrap();
+    write_scenario(
+        root_dir.path(),
+        &config,
+        r#"{"wait_for_kill": true, "ignore_kill": true}"#,
+    );
+    let environment = TestEnvironment {
+        root_dir: root_dir.path().to_path_buf(),
+    };
+    let token_store = FileTokenStore::new(&root_dir.path().join("token.json"));
+    let http_client = TeiHttpClient::new();
+    let mut socket_client = TachyonClient::new();
+    let mut server = TeiServer::new(&config, &http_client, &mut socket_client, &token_store);
+    let spring = SpringHeadless::new(Arc::default());
+    let battle_manager = BattleManager::new(&config, &spring, &environment, Arc::default());
+    let stop_timeout = Duration::from_secs(2);
+    let mut daemon = Daemon::new(&mut server, battle_manager, stop_timeout);
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let (result, _) = tokio::join!(
+        daemon.run(async {
+            let _ = stopped.await;
+        }),
+        async {
+            mock.wait_for_connections(1).await;
+            let lobby_id = 42;
+            mock.push(json!({
+                "cmd": "s.lobby_host.open_request",
+                "lobby": {
+                    "id": lobby_id,
+                    "name": "",
+                    "game_name": "BAR test-1",
+                    "map_name": "DSDR 4.1",
+                    "port": 0
+                }
+            }));
+            mock.wait_for_command("c.lobby.update", 1).await;
+            mock.push(json!({"cmd": "s.lobby_host.start_request", "lobby_id": lobby_id}));
+            mock.wait_for_command("c.lobby_host.update_host_status", 1)
+                .await;
+
+            mock.push(json!({"cmd": "s.lobby_host.close_request", "lobby_id": lobby_id}));
+            tokio::time::timeout(stop_timeout / 2, mock.wait_for_command("c.lobby.close", 1))
+                .await
+                .expect("the battle wasn't closed before its game stopped");
+            tokio::time::sleep(stop_timeout + Duration::from_secs(1)).await;
+            let _ = stop.send(());
+        }
+    );
+    result.unwrap();
+
+    assert!(daemon.get_battle_manager().get_battle_ids().is_empty());
+}
@@ -0,0 +1,175 @@
+mod support;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bar_autohost::autohost::battle::{Battle, BattlePlayer};
+use bar_autohost::autohost::engine_interface::EngineEvent;
+use bar_autohost::autohost::lobby::Lobby;
+use bar_autohost::autohost::spring::{GameExit, GameUpdate, SpringHeadless};
+use bar_autohost::utils::metrics::Metrics;
+
+use support::{write_scenario, MockConfig, TestEnvironment};
+
+fn build_battle() -> Battle {
+    let mut battle = Battle::new("BAR test-1", "DSDR 4.1");
+    battle.add_player(BattlePlayer::new("first", Some(0)));
+    battle.add_player(BattlePlayer::new("second", Some(1)));
+    battle.add_player(BattlePlayer::new("spectator", None));
+    battle
+}
+
+async fn play_to_end(lobby: &mut Lobby<'_>) -> (Vec<EngineEvent>, Vec<String>, GameExit) {
+    let mut events = Vec::new();
+    let mut lines = Vec::new();
+
+    loop {
+        let update = tokio::time::timeout(Duration::from_secs(10), lobby.next_game_update())
+            .await
+            .expect("the game didn't end")
+            .unwrap();
+
+        match update {
+            GameUpdate::Event(event) => events.push(event),
+            GameUpdate::Log(line) => lines.push(line.text),
+            GameUpdate::Exited(exit) => return (events, lines, exit),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_whole_game_with_fake_engine() {
+    let root_dir = tempfile::tempdir().unwrap();
+    let config = MockConfig::default();
+    let spring = SpringHeadless::new(Arc::default());
+    let environment = TestEnvironment {
+        root_dir: root_dir.path().to_path_buf(),
+    };
+    let mut lobby = Lobby::new(
+        &config,
+        &spring,
+        &environment,
+        build_battle(),
+        Arc::default(),
+    );
+
+    lobby.start_game().unwrap();
+    let (events, lines, exit) = play_to_end(&mut lobby).await;
+
+    assert!(exit.success());
+    assert_eq!(events.first(), Some(&EngineEvent::ServerStarted));
+    assert_eq!(events.last(), Some(&EngineEvent::ServerQuit));
+    let joined: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            EngineEvent::PlayerJoined { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(joined, ["first", "second", "spectator"]);
+    assert!(events.contains(&EngineEvent::ServerGameOver {
+        player: 0,
+        winning_ally_teams: vec![0],
+    }));
+    assert_eq!(
+        events
+            .iter()
+            .filter(|event| matches!(event, EngineEvent::GameTeamStat { .. }))
+            .count(),
+        2
+    );
+    assert!(lines.iter().any(|line| line == "Game over"));
+}
+
+#[tokio::test]
+async fn test_fake_engine_exit_code() {
+    let root_dir = tempfile::tempdir().unwrap();
+    let config = MockConfig::default();
+    write_scenario(
+        root_dir.path(),
+        &config,
+        r#"{"exit_code": 3, "winning_ally_teams": [1]}"#,
+    );
+    let spring = SpringHeadless::new(Arc::default());
+    let environment = TestEnvironment {
+        root_dir: root_dir.path().to_path_buf(),
+    };
+    let mut lobby = Lobby::new(
+        &config,
+        &spring,
+        &environment,
+        build_battle(),
+        Arc::default(),
+    );
+
+    lobby.start_game().unwrap();
+    let (events, _, exit) = play_to_end(&mut lobby).await;
+
+    assert_eq!(exit.code(), Some(3));
+    assert!(events.contains(&EngineEvent::ServerGameOver {
+        player: 0,
+        winning_ally_teams: vec![1],
+    }));
+}
+
+#[tokio::test]
+async fn test_stop_game_ends_fake_engine() {
+    let root_dir = tempfile::tempdir().unwrap();
+    let config = MockConfig::default();
+    write_scenario(root_dir.path(), &config, r#"{"wait_for_kill": true}"#);
+    let spring = SpringHeadless::new(Arc::default());
+    let environment = TestEnvironment {
+        root_dir: root_dir.path().to_path_buf(),
+    };
+    let mut lobby = Lobby::new(
+        &config,
+        &spring,
+        &environment,
+        build_battle(),
+        Arc::default(),
+    );
+
+    lobby.start_game().unwrap();
+    while !matches!(
+        lobby.next_engine_event().await,
+        Some(Ok(EngineEvent::ServerStartPlaying { .. }))
+    ) {}
+    let exit = lobby.stop_game(Duration::from_secs(10)).await.unwrap();
+
+    assert!(exit.success());
+    assert!(exit.duration < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn test_game_metrics() {
+    let root_dir = tempfile::tempdir().unwrap();
+    let config = MockConfig::default();
+    write_scenario(root_dir.path(), &config, r#"{"exit_code": 1}"#);
+    let metrics = Arc::new(Metrics::new());
+    let spring = SpringHeadless::new(metrics.clone());
+    let environment = TestEnvironment {
+        root_dir: root_dir.path().to_path_buf(),
+    };
+    let mut lobby = Lobby::new(
+        &config,
+        &spring,
+        &environment,
+        build_battle(),
+        metrics.clone(),
+    );
+
+    lobby.start_game().unwrap();
+    assert_eq!(metrics.get_games_running(), 1);
+    let (events, _, _) = play_to_end(&mut lobby).await;
+
+    let text = metrics.render();
+    assert_eq!(metrics.get_games_running(), 0);
+    assert_eq!(metrics.get_players_connected(), 0);
+    assert!(text.contains("\nautohost_games_launched_total 1\n"));
+    assert!(text.contains("\nautohost_engine_crashes_total 1\n"));
+    assert!(text.contains("\nautohost_game_duration_seconds_count 1\n"));
+    assert!(text.contains(&format!(
+        "\nautohost_engine_messages_total {}\n",
+        events.len()
+    )));
+}
@@ -0,0 +1,440 @@
+//! A local stand-in for Teiserver, serving the token endpoint and Tachyon websocket on a
+//! single port like the real server does.
+
+// Every test crate uses a different part of the support module.
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use bar_autohost::utils::config::{Config, Endpoint, HttpApiConfig, LoggingConfig};
+use bar_autohost::utils::environment::{Environment, EnvironmentError};
+
+pub const EMAIL: &str = "autohost@example.com";
+pub const PASSWORD: &str = "password";
+
+const TOKEN_PATH: &str = "/teiserver/api/request_token";
+const WEBSOCKET_PATH: &str = "/tachyon/websocket";
+
+#[derive(Clone, Debug)]
+enum Control {
+    Push(String),
+    Drop,
+}
+
+#[derive(Default)]
+struct State {
+    tokens: HashSet<String>,
+    logins: usize,
+    connections: usize,
+    next_lobby_id: u64,
+    received: Vec<Value>,
+}
+
+/// A mock Teiserver on a random local port.
+///
+/// Logins succeed with `EMAIL` and `PASSWORD`, and websocket connections with any token
+/// it has handed out. Every Tachyon command it receives is recorded and answered with a
+/// successful response.
+pub struct MockTeiserver {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    control: broadcast::Sender<Control>,
+    task: JoinHandle<()>,
+}
+
+impl MockTeiserver {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            next_lobby_id: 1,
+            ..State::default()
+        }));
+        let (control, _) = broadcast::channel(64);
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            let control = control.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, state.clone(), control.subscribe()));
+                }
+            }
+        });
+
+        MockTeiserver {
+            addr,
+            state,
+            control,
+            task,
+        }
+    }
+
+    pub fn get_config(&self) -> MockConfig {
+        MockConfig {
+            http_endpoint: Endpoint::new("http", Some(self.addr.port()), "teiserver/api"),
+            websocket_endpoint: Endpoint::new("ws", Some(self.addr.port()), "tachyon/websocket"),
+            ..MockConfig::default()
+        }
+    }
+
+    pub fn get_login_count(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    pub fn get_connection_count(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// The commands received so far, in order.
+    pub fn get_received_commands(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .received
+            .iter()
+            .filter_map(|message| message["cmd"].as_str().map(str::to_string))
+            .collect()
+    }
+
+    /// The messages received so far with the command `cmd`, in order.
+    pub fn get_received(&self, cmd: &str) -> Vec<Value> {
+        self.state
+            .lock()
+            .unwrap()
+            .received
+            .iter()
+            .filter(|message| message["cmd"] == cmd)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_last_lobby_id(&self) -> u64 {
+        self.state.lock().unwrap().next_lobby_id - 1
+    }
+
+    /// Sends a message to every connected client.
+    pub fn push(&self, message: Value) {
+        let _ = self.control.send(Control::Push(message.to_string()));
+    }
+
+    /// Drops every websocket connection without a close frame.
+    pub fn drop_connections(&self) {
+        let _ = self.control.send(Control::Drop);
+    }
+
+    /// Waits until `count` websocket connections have been accepted in total.
+    pub async fn wait_for_connections(&self, count: usize) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while self.get_connection_count() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} connections weren't accepted", count));
+    }
+
+    /// Waits until `cmd` has been received `count` times in total.
+    pub async fn wait_for_command(&self, cmd: &str, count: usize) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while self
+                .get_received_commands()
+                .iter()
+                .filter(|received| *received == cmd)
+                .count()
+                < count
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} wasn't received {} times", cmd, count));
+    }
+}
+
+impl Drop for MockTeiserver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>, control: broadcast::Receiver<Control>) {
+    let mut request_line = vec![0; 256];
+    let read = stream.peek(&mut request_line).await.unwrap_or(0);
+    let request_line = String::from_utf8_lossy(&request_line[..read]).to_string();
+    let path = request_line
+        .split(' ')
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+
+    if path.starts_with(WEBSOCKET_PATH) {
+        serve_websocket(stream, &path, state, control).await;
+    } else {
+        serve_http(stream, &path, state).await;
+    }
+}
+
+async fn serve_http(mut stream: TcpStream, path: &str, state: Arc<Mutex<State>>) {
+    let Some(body) = read_http_body(&mut stream).await else {
+        return;
+    };
+
+    let (status, response) = if path != TOKEN_PATH {
+        ("404 Not Found", json!({"detail": "Not Found"}))
+    } else {
+        let request: Value = serde_json::from_str(&body).unwrap_or_default();
+        let mut state = state.lock().unwrap();
+
+        if request["cmd"] == "c.auth.get_token"
+            && request["email"] == EMAIL
+            && request["password"] == PASSWORD
+        {
+            state.logins += 1;
+            let token = format!("mock-token-{}", state.logins);
+            state.tokens.insert(token.clone());
+            ("200 OK", json!({"result": "success", "token_value": token}))
+        } else {
+            ("401 Unauthorized", json!({"detail": "Invalid credentials"}))
+        }
+    };
+
+    write_http_response(&mut stream, status, &response.to_string()).await;
+}
+
+async fn read_http_body(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    let header_end = loop {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+
+    let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+    let content_length: usize = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|length| length.trim().parse().ok())
+        .unwrap_or(0);
+
+    while request.len() < header_end + content_length {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    Some(String::from_utf8_lossy(&request[header_end..header_end + content_length]).to_string())
+}
+
+async fn write_http_response(stream: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn serve_websocket(
+    mut stream: TcpStream,
+    path: &str,
+    state: Arc<Mutex<State>>,
+    mut control: broadcast::Receiver<Control>,
+) {
+    let token = path
+        .split(['?', '&'])
+        .find_map(|parameter| parameter.strip_prefix("token="))
+        .unwrap_or_default();
+
+    if !state.lock().unwrap().tokens.contains(token) {
+        let _ = read_http_body(&mut stream).await;
+        write_http_response(&mut stream, "401 Unauthorized", "").await;
+        return;
+    }
+
+    let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    state.lock().unwrap().connections += 1;
+
+    loop {
+        tokio::select! {
+            message = socket.next() => {
+                let Some(Ok(Message::Text(text))) = message else {
+                    return;
+                };
+                let Ok(request) = serde_json::from_str::<Value>(&text) else {
+                    continue;
+                };
+                let disconnect = request["cmd"] == "c.auth.disconnect";
+
+                let response = respond(&request, &mut state.lock().unwrap());
+                if let Some(response) = response {
+                    let _ = socket.send(Message::Text(response.to_string())).await;
+                }
+                if disconnect {
+                    let _ = socket.close(None).await;
+                }
+            }
+            control = control.recv() => match control {
+                Ok(Control::Push(text)) => {
+                    let _ = socket.send(Message::Text(text)).await;
+                }
+                Ok(Control::Drop) | Err(_) => return,
+            }
+        }
+    }
+}
+
+fn respond(request: &Value, state: &mut State) -> Option<Value> {
+    state.received.push(request.clone());
+
+    let cmd = request["cmd"].as_str()?;
+    let mut response = match cmd {
+        "c.system.ping" => json!({"cmd": "s.system.pong"}),
+        "c.lobby.create" => {
+            let mut lobby = request["lobby"].clone();
+            lobby["id"] = json!(state.next_lobby_id);
+            state.next_lobby_id += 1;
+            json!({"cmd": "s.lobby.create", "result": "success", "lobby": lobby})
+        }
+        cmd => json!({"cmd": cmd.replacen("c.", "s.", 1), "result": "success"}),
+    };
+
+    if let Some(msg_id) = request.get("msg_id") {
+        response["msg_id"] = msg_id.clone();
+    }
+
+    Some(response)
+}
+
+/// The config of an autohost under test, connecting to a `MockTeiserver` and
+/// launching `fake-spring-headless` by default.
+pub struct MockConfig {
+    pub spring_path: String,
+    pub write_dir_path: String,
+    pub login_password: String,
+    pub http_endpoint: Endpoint,
+    pub websocket_endpoint: Endpoint,
+    pub logging: LoggingConfig,
+    pub http_api: Option<HttpApiConfig>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            spring_path: env!("CARGO_BIN_EXE_fake-spring-headless").to_string(),
+            write_dir_path: "write_dir".to_string(),
+            login_password: PASSWORD.to_string(),
+            http_endpoint: Endpoint::new("http", None, "teiserver/api"),
+            websocket_endpoint: Endpoint::new("ws", None, "tachyon/websocket"),
+            logging: LoggingConfig::default(),
+            http_api: None,
+        }
+    }
+}
+
+impl Config for MockConfig {
+    fn get_spring_relative_path(&self) -> &str {
+        &self.spring_path
+    }
+
+    fn get_write_dir_relative_path(&self) -> &str {
+        &self.write_dir_path
+    }
+
+    fn get_server_domain(&self) -> &str {
+        "127.0.0.1"
+    }
+
+    fn get_server_login_email(&self) -> &str {
+        EMAIL
+    }
+
+    fn get_server_login_password(&self) -> &str {
+        &self.login_password
+    }
+
+    fn get_min_host_port(&self) -> u16 {
+        8452
+    }
+
+    fn get_max_host_port(&self) -> u16 {
+        8551
+    }
+
+    fn get_max_concurrent_games(&self) -> usize {
+        10
+    }
+
+    fn get_token_store_relative_path(&self) -> &str {
+        "autohost_token.json"
+    }
+
+    fn get_server_http_endpoint(&self) -> &Endpoint {
+        &self.http_endpoint
+    }
+
+    fn get_server_websocket_endpoint(&self) -> &Endpoint {
+        &self.websocket_endpoint
+    }
+
+    fn get_server_ca_bundle_path(&self) -> Option<&str> {
+        None
+    }
+
+    fn get_server_accept_invalid_certs(&self) -> bool {
+        false
+    }
+
+    fn get_logging(&self) -> &LoggingConfig {
+        &self.logging
+    }
+
+    fn get_http_api(&self) -> Option<&HttpApiConfig> {
+        self.http_api.as_ref()
+    }
+}
+
+/// Runs the autohost in a directory of its own, such as a temporary one.
+pub struct TestEnvironment {
+    pub root_dir: PathBuf,
+}
+
+impl Environment for TestEnvironment {
+    fn get_current_dir(&self) -> Result<PathBuf, EnvironmentError> {
+        Ok(self.root_dir.clone())
+    }
+}
+
+/// Sets the scenario `fake-spring-headless` plays, as JSON, for games launched under
+/// `root_dir` with `config`.
+pub fn write_scenario(root_dir: &Path, config: &MockConfig, scenario: &str) {
+    let data_dir = root_dir.join(&config.write_dir_path);
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::write(data_dir.join("fake_spring_scenario.json"), scenario).unwrap();
+}
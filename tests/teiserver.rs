@@ -0,0 +1,100 @@
+mod support;
+
+use serde_json::json;
+
+use bar_autohost::autohost::battle::Battle;
+use bar_autohost::server_coms::server::{Server, TeiServer};
+use bar_autohost::server_coms::server_error::ServerError;
+use bar_autohost::server_coms::server_request::ServerRequest;
+use bar_autohost::server_coms::token_store::{FileTokenStore, TokenStore};
+use bar_autohost::utils::config::Config;
+use bar_autohost::utils::http_client::TeiHttpClient;
+use bar_autohost::utils::websocket_client::TachyonClient;
+
+use support::MockTeiserver;
+
+#[tokio::test]
+async fn test_session_against_mock_server() {
+    let mock = MockTeiserver::start().await;
+    let config = mock.get_config();
+    let dir = tempfile::tempdir().unwrap();
+    let token_store = FileTokenStore::new(&dir.path().join("token.json"));
+    let http_client = TeiHttpClient::new();
+    let mut socket_client = TachyonClient::new();
+    let mut server = TeiServer::new(&config, &http_client, &mut socket_client, &token_store);
+
+    server.start_session().await.unwrap();
+    server
+        .battle_opened(1, &Battle::new("BAR test-1", "DSDR 4.1"), None)
+        .await
+        .unwrap();
+
+    let lobby_id = mock.get_last_lobby_id();
+    mock.push(json!({"cmd": "s.lobby_host.start_request", "lobby_id": lobby_id}));
+    assert!(matches!(
+        server.next_request().await.unwrap(),
+        ServerRequest::StartGame(1)
+    ));
+
+    server.battle_status_changed(1, true).await.unwrap();
+    server.end_session().await.unwrap();
+
+    assert_eq!(
+        mock.get_received_commands(),
+        [
+            "c.lobby.create",
+            "c.lobby_host.update_host_status",
+            "c.auth.disconnect"
+        ]
+    );
+    assert_eq!(mock.get_login_count(), 1);
+    assert!(token_store
+        .load(config.get_server_domain(), config.get_server_login_email())
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_reconnect_to_mock_server() {
+    let mock = MockTeiserver::start().await;
+    let config = mock.get_config();
+    let dir = tempfile::tempdir().unwrap();
+    let token_store = FileTokenStore::new(&dir.path().join("token.json"));
+    let http_client = TeiHttpClient::new();
+    let mut socket_client = TachyonClient::new();
+    let mut server = TeiServer::new(&config, &http_client, &mut socket_client, &token_store);
+
+    server.start_session().await.unwrap();
+    server
+        .battle_opened(1, &Battle::new("BAR test-1", "DSDR 4.1"), None)
+        .await
+        .unwrap();
+    mock.drop_connections();
+
+    let (request, _) = tokio::join!(server.next_request(), async {
+        mock.wait_for_command("c.lobby.create", 2).await;
+        let lobby_id = mock.get_last_lobby_id();
+        mock.push(json!({"cmd": "s.lobby_host.close_request", "lobby_id": lobby_id}));
+    });
+
+    assert!(matches!(request.unwrap(), ServerRequest::CloseBattle(1)));
+    assert_eq!(mock.get_connection_count(), 2);
+    assert_eq!(mock.get_login_count(), 1);
+}
+
+#[tokio::test]
+async fn test_login_rejected_by_mock_server() {
+    let mock = MockTeiserver::start().await;
+    let mut config = mock.get_config();
+    config.login_password = "wrong".to_string();
+    let dir = tempfile::tempdir().unwrap();
+    let token_store = FileTokenStore::new(&dir.path().join("token.json"));
+    let http_client = TeiHttpClient::new();
+    let mut socket_client = TachyonClient::new();
+    let mut server = TeiServer::new(&config, &http_client, &mut socket_client, &token_store);
+
+    let result = server.start_session().await;
+
+    assert!(matches!(result, Err(ServerError::LoginRefused { .. })));
+    assert_eq!(mock.get_connection_count(), 0);
+}